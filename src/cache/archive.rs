@@ -0,0 +1,190 @@
+use std::{
+  fs::{self, File},
+  io::{self, BufReader},
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+
+use crate::{
+  cache::{entry::Entry, lib::lock_entry, metadata::Metadata},
+  errors::to_error,
+  utils::{fs as fs_utils, hash::Hash},
+};
+
+pub const ARCHIVE_EXTENSION: &str = "tar.zst";
+
+/// A balanced level between the size and the time, since node_modules is compressed well anyway.
+const COMPRESSION_LEVEL: i32 = 3;
+
+pub fn to_archive_path(cache_dir: impl AsRef<Path>, key: &Hash) -> PathBuf {
+  cache_dir
+    .as_ref()
+    .join(format!("{}.{}", key, ARCHIVE_EXTENSION))
+}
+
+/// Pack `dir` into a tar stream compressed by zstd. Symlinks such as `.bin` shims are kept as links.
+pub fn compress(dir: impl AsRef<Path>, archive_path: impl AsRef<Path>) -> Result<()> {
+  create(archive_path, |builder| builder.append_dir_all(".", &dir))
+}
+
+/// Unpack an archive made by `compress` at `dir`, replacing `dir`.
+pub fn decompress(archive_path: impl AsRef<Path>, dir: impl AsRef<Path>) -> Result<()> {
+  let dir = dir.as_ref();
+  fs::remove_dir_all(dir).unwrap_or_default();
+  let result = open(archive_path).and_then(|mut archive| archive.unpack(dir));
+  if result.is_err() {
+    fs::remove_dir_all(dir).unwrap_or_default();
+  }
+  result.map_err(to_error)
+}
+
+/// Write an archive by `append` under a temporary name, so that the archive is never left half-written.
+pub fn create(
+  archive_path: impl AsRef<Path>,
+  append: impl FnOnce(&mut tar::Builder<zstd::Encoder<'static, File>>) -> io::Result<()>,
+) -> Result<()> {
+  let archive_path = archive_path.as_ref();
+  let dir = match archive_path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent,
+    _ => Path::new("."),
+  };
+  // the temporary file is removed when dropped on errors
+  let (file, tmp_path) = fs_utils::create_temp_file_in(dir)?.into_parts();
+  zstd::Encoder::new(file, COMPRESSION_LEVEL)
+    .and_then(|encoder| {
+      let mut builder = tar::Builder::new(encoder);
+      builder.follow_symlinks(false);
+      append(&mut builder)?;
+      builder.into_inner()?.finish()?.sync_all()
+    })
+    .map_err(to_error)?;
+  tmp_path.persist(archive_path).map_err(to_error)?;
+  Ok(())
+}
+
+/// Open an archive to read, which restores permissions and mtime of files.
+pub fn open(
+  archive_path: impl AsRef<Path>,
+) -> io::Result<tar::Archive<zstd::Decoder<'static, BufReader<File>>>> {
+  let decoder = zstd::Decoder::new(File::open(archive_path)?)?;
+  let mut archive = tar::Archive::new(decoder);
+  archive.set_preserve_permissions(true);
+  archive.set_preserve_mtime(true);
+  Ok(archive)
+}
+
+/// Compress cache entries not used for `unused_for`, and return their keys.
+/// Entries being restored by other processes are skipped.
+pub fn compress_unused(cache_dir: impl AsRef<Path>, unused_for: Duration) -> Result<Vec<Hash>> {
+  let cache_dir = cache_dir.as_ref();
+  let metadata = Metadata::new(cache_dir)?;
+  let now = SystemTime::now();
+  let mut compressed = Vec::<Hash>::new();
+  for (key, entry) in Entry::list(cache_dir) {
+    let Entry::Dir(dir) = &entry else {
+      continue;
+    };
+    // fall back to the time when the directory was moved into the cache
+    let last_used = metadata
+      .last_used(&key)
+      .or_else(|| fs::metadata(dir).and_then(|m| m.modified()).ok());
+    let is_unused = last_used
+      .and_then(|t| now.duration_since(t).ok())
+      .is_some_and(|elapsed| elapsed >= unused_for);
+    if !is_unused {
+      continue;
+    }
+    let Ok(_lock) = lock_entry(cache_dir, &key, Duration::ZERO) else {
+      continue;
+    };
+    // the entry may be restored while waiting for the lock
+    if Entry::find(cache_dir, &key) != Some(entry.clone()) {
+      continue;
+    }
+    compress(dir, to_archive_path(cache_dir, &key))?;
+    fs::remove_dir_all(dir).map_err(to_error)?;
+    compressed.push(key);
+  }
+  Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_compress_and_decompress() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dir = tmp_dir.path().join("node_modules");
+    fs::create_dir_all(dir.join("foo/bin")).unwrap();
+    fs::create_dir_all(dir.join(".bin")).unwrap();
+    fs::write(dir.join("foo/bin/cli.js"), "console.log(1)").unwrap();
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let cli = dir.join("foo/bin/cli.js");
+      fs::set_permissions(&cli, fs::Permissions::from_mode(0o755)).unwrap();
+      std::os::unix::fs::symlink("../foo/bin/cli.js", dir.join(".bin/foo")).unwrap();
+    }
+    let archive_path = tmp_dir.path().join("a-b-c.tar.zst");
+    compress(&dir, &archive_path).unwrap();
+    assert!(archive_path.is_file());
+
+    let restored = tmp_dir.path().join("restored");
+    fs::create_dir_all(restored.join("stale")).unwrap();
+    decompress(&archive_path, &restored).unwrap();
+    assert!(!restored.join("stale").exists());
+    assert_eq!(
+      fs::read_to_string(restored.join("foo/bin/cli.js")).unwrap(),
+      "console.log(1)"
+    );
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(restored.join("foo/bin/cli.js"))
+        .unwrap()
+        .permissions()
+        .mode();
+      assert_eq!(mode & 0o777, 0o755);
+      assert_eq!(
+        fs::read_link(restored.join(".bin/foo")).unwrap(),
+        Path::new("../foo/bin/cli.js")
+      );
+    }
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_create_failed() {
+    let tmp_dir = TempDir::new().unwrap();
+    let archive_path = tmp_dir.path().join("a-b-c.tar.zst");
+    let result = create(&archive_path, |_| Err(io::Error::other("failed")));
+    assert!(result.is_err());
+    // neither the archive nor the temporary file is left
+    assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_compress_unused() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path();
+    fs::create_dir_all(cache_dir.join("a-b-c/foo")).unwrap();
+    let key = Hash(String::from("a-b-c"));
+
+    let compressed = compress_unused(cache_dir, Duration::from_secs(3600)).unwrap();
+    assert!(compressed.is_empty());
+    let compressed = compress_unused(cache_dir, Duration::ZERO).unwrap();
+    assert_eq!(compressed, vec![key.clone()]);
+    assert!(!cache_dir.join("a-b-c").exists());
+    assert_eq!(
+      Entry::find(cache_dir, &key),
+      Some(Entry::Archive(to_archive_path(cache_dir, &key)))
+    );
+    tmp_dir.close().unwrap();
+  }
+}
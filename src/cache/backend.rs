@@ -0,0 +1,270 @@
+use std::{
+  fmt::Debug,
+  fs::{self, File},
+  io,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use tempfile::NamedTempFile;
+
+use crate::{
+  cache::{archive::ARCHIVE_EXTENSION, s3::S3Backend},
+  errors::{to_error, Error},
+  utils::{fs as fs_utils, hash::Hash},
+};
+
+/// An environment variable of a token sent as `Authorization: Bearer <token>` to a remote cache.
+pub const REMOTE_TOKEN_ENV: &str = "SYNCNM_REMOTE_TOKEN";
+
+/// A store of cache bundles made by `export` shared among machines, keyed by the same keys as the local caches.
+/// Only remote caches are behind this trait: the cache directory stays the primary store of `Cache`,
+/// since restoring relies on its directories and blobs in place, which a store of whole bundles cannot provide.
+/// `LocalBackend` is a directory used as a remote cache, such as a shared mount, not the cache directory.
+pub trait Backend: Debug {
+  fn exists(&self, key: &Hash) -> Result<bool>;
+
+  /// Download the bundle of `key` to `path`, and return false if not found.
+  fn download(&self, key: &Hash, path: &Path) -> Result<bool>;
+
+  fn upload(&self, key: &Hash, path: &Path) -> Result<()>;
+}
+
+/// Open a backend by a URL (`http://`, `https://` or `s3://<bucket>/<prefix>`) or a path to a directory such as a shared mount.
+pub fn open_backend(remote: &str) -> Result<Box<dyn Backend>> {
+  if remote.starts_with("s3://") {
+    Ok(Box::new(S3Backend::from_url(remote)?))
+  } else if remote.starts_with("http://") || remote.starts_with("https://") {
+    Ok(Box::new(HttpBackend::new(
+      remote,
+      std::env::var(REMOTE_TOKEN_ENV).ok(),
+    )))
+  } else {
+    let dir = remote.strip_prefix("file://").unwrap_or(remote);
+    Ok(Box::new(LocalBackend::new(dir)))
+  }
+}
+
+/// Bundles in a local directory as `<dir>/<key>.tar.zst`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LocalBackend {
+  dir: PathBuf,
+}
+
+impl LocalBackend {
+  pub fn new(dir: impl AsRef<Path>) -> Self {
+    Self {
+      dir: dir.as_ref().to_path_buf(),
+    }
+  }
+
+  fn to_bundle_path(&self, key: &Hash) -> PathBuf {
+    self.dir.join(format!("{}.{}", key, ARCHIVE_EXTENSION))
+  }
+}
+
+impl Backend for LocalBackend {
+  fn exists(&self, key: &Hash) -> Result<bool> {
+    Ok(self.to_bundle_path(key).is_file())
+  }
+
+  fn download(&self, key: &Hash, path: &Path) -> Result<bool> {
+    let bundle = self.to_bundle_path(key);
+    if !bundle.is_file() {
+      return Ok(false);
+    }
+    fs::copy(bundle, path).map_err(to_error)?;
+    Ok(true)
+  }
+
+  fn upload(&self, key: &Hash, path: &Path) -> Result<()> {
+    fs_utils::make_dir_if_not_exists(&self.dir)?;
+    // copy under a temporary name unique among threads not to expose a half-written bundle to others
+    let mut tmp_file = NamedTempFile::new_in(&self.dir).map_err(to_error)?;
+    let mut file = File::open(path).map_err(to_error)?;
+    io::copy(&mut file, &mut tmp_file).map_err(to_error)?;
+    // a temporary file is only readable by the owner, while the directory may be shared with other users
+    let permissions = file.metadata().map_err(to_error)?.permissions();
+    tmp_file
+      .as_file()
+      .set_permissions(permissions)
+      .map_err(to_error)?;
+    tmp_file
+      .persist(self.to_bundle_path(key))
+      .map_err(to_error)?;
+    Ok(())
+  }
+}
+
+/// Bundles served at `<base URL>/<key>` by GET, PUT and HEAD.
+#[derive(PartialEq, Clone)]
+pub struct HttpBackend {
+  base_url: String,
+  token: Option<String>,
+}
+
+impl Debug for HttpBackend {
+  // never print the token
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("HttpBackend")
+      .field("base_url", &self.base_url)
+      .field("token", &self.token.as_ref().map(|_| "***"))
+      .finish()
+  }
+}
+
+impl HttpBackend {
+  pub fn new(base_url: &str, token: Option<String>) -> Self {
+    Self {
+      base_url: base_url.trim_end_matches('/').to_string(),
+      token,
+    }
+  }
+
+  fn request(&self, method: &str, key: &Hash) -> ureq::Request {
+    let request = ureq::request(method, &format!("{}/{}", self.base_url, key));
+    match &self.token {
+      Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+      None => request,
+    }
+  }
+}
+
+impl Backend for HttpBackend {
+  fn exists(&self, key: &Hash) -> Result<bool> {
+    match self.request("HEAD", key).call() {
+      Ok(_) => Ok(true),
+      Err(ureq::Error::Status(404, _)) => Ok(false),
+      Err(error) => Err(to_http_error(error)),
+    }
+  }
+
+  fn download(&self, key: &Hash, path: &Path) -> Result<bool> {
+    let response = match self.request("GET", key).call() {
+      Ok(response) => response,
+      Err(ureq::Error::Status(404, _)) => return Ok(false),
+      Err(error) => return Err(to_http_error(error)),
+    };
+    let mut file = File::create(path).map_err(to_error)?;
+    io::copy(&mut response.into_reader(), &mut file).map_err(to_error)?;
+    Ok(true)
+  }
+
+  fn upload(&self, key: &Hash, path: &Path) -> Result<()> {
+    let file = File::open(path).map_err(to_error)?;
+    let length = file.metadata().map_err(to_error)?.len();
+    self
+      .request("PUT", key)
+      .set("Content-Type", "application/zstd")
+      .set("Content-Length", &length.to_string())
+      .send(file)
+      .map(|_| ())
+      .map_err(to_http_error)
+  }
+}
+
+fn to_http_error(error: ureq::Error) -> anyhow::Error {
+  Error::Any(format!("Remote cache request failed: {}", error)).into()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, thread};
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  /// A stand-in of a remote cache keeping bundles in memory, which requires the token if given.
+  fn serve(token: Option<&'static str>) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr());
+    thread::spawn(move || {
+      let mut store = HashMap::<String, Vec<u8>>::new();
+      for mut request in server.incoming_requests() {
+        let authorization = request
+          .headers()
+          .iter()
+          .find(|h| h.field.equiv("Authorization"))
+          .map(|h| h.value.to_string());
+        if token.is_some_and(|t| authorization != Some(format!("Bearer {}", t))) {
+          request
+            .respond(tiny_http::Response::empty(401))
+            .unwrap_or_default();
+          continue;
+        }
+        let path = request.url().to_string();
+        let response = match request.method() {
+          tiny_http::Method::Put => {
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            store.insert(path, body);
+            tiny_http::Response::from_data(Vec::new()).with_status_code(201)
+          }
+          tiny_http::Method::Get | tiny_http::Method::Head => match store.get(&path) {
+            Some(body) => tiny_http::Response::from_data(body.clone()),
+            None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+          },
+          _ => tiny_http::Response::from_data(Vec::new()).with_status_code(405),
+        };
+        request.respond(response).unwrap_or_default();
+      }
+    });
+    url
+  }
+
+  fn test_backend(backend: &dyn Backend) {
+    let tmp_dir = TempDir::new().unwrap();
+    let key = Hash(String::from("a-b-c"));
+    let bundle = tmp_dir.path().join("bundle");
+    let downloaded = tmp_dir.path().join("downloaded");
+    fs::write(&bundle, "bundle").unwrap();
+
+    assert!(!backend.exists(&key).unwrap());
+    assert!(!backend.download(&key, &downloaded).unwrap());
+    backend.upload(&key, &bundle).unwrap();
+    assert!(backend.exists(&key).unwrap());
+    assert!(backend.download(&key, &downloaded).unwrap());
+    assert_eq!(fs::read_to_string(&downloaded).unwrap(), "bundle");
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_local_backend() {
+    let tmp_dir = TempDir::new().unwrap();
+    test_backend(&LocalBackend::new(tmp_dir.path().join("remote")));
+    // no temporary file is left besides the bundle
+    assert_eq!(
+      fs::read_dir(tmp_dir.path().join("remote")).unwrap().count(),
+      1
+    );
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_http_backend() {
+    let url = serve(Some("secret"));
+    test_backend(&HttpBackend::new(&url, Some(String::from("secret"))));
+
+    let unauthorized = HttpBackend::new(&url, None);
+    assert!(unauthorized.exists(&Hash(String::from("a-b-c"))).is_err());
+  }
+
+  #[test]
+  fn test_open_backend() {
+    assert_eq!(
+      format!("{:?}", open_backend("https://cache.example.com/").unwrap()),
+      format!(
+        "{:?}",
+        HttpBackend::new(
+          "https://cache.example.com",
+          std::env::var(REMOTE_TOKEN_ENV).ok()
+        )
+      )
+    );
+    assert_eq!(
+      format!("{:?}", open_backend("file:///mnt/cache").unwrap()),
+      format!("{:?}", LocalBackend::new("/mnt/cache"))
+    );
+  }
+}
@@ -0,0 +1,272 @@
+use std::{
+  fs,
+  io::Read,
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tempfile::TempDir;
+
+use crate::{
+  cache::{
+    archive,
+    entry::Entry,
+    lib::resolve_cache_dir,
+    metadata::{CacheMeta, KeyComponents, Metadata},
+    store::Store,
+    strategy::Strategy,
+  },
+  errors::{to_error, Error},
+  project::{find_project_root, Fingerprint, Version},
+  utils::{
+    hash::{Hash, Hashable},
+    path::to_dir_key,
+  },
+};
+
+/// The version of the layout of bundles, which is incremented on breaking changes.
+/// Keys in bundles before version 2 contain the directory of the exported project.
+const BUNDLE_VERSION: u64 = 2;
+
+const INFO_FILE_NAME: &str = "bundle.json";
+const NODE_MODULES_DIR: &str = "node_modules";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct BundleInfo {
+  version: u64,
+  key: KeyComponents,
+  meta: CacheMeta,
+  fingerprint: Fingerprint,
+  /// the exported project whose absolute paths are baked into node_modules
+  origin: Option<PathBuf>,
+}
+
+/// Write the cache entry of `key` into a bundle at `output`, which is a tar.zst archive of `bundle.json` and node_modules.
+pub fn export(
+  cache_dir: Option<impl AsRef<Path>>,
+  key: &Hash,
+  output: impl AsRef<Path>,
+) -> Result<()> {
+  let cache_dir = resolve_cache_dir(cache_dir)?;
+  let components =
+    KeyComponents::parse(&key.0).ok_or(Error::Any(format!("Invalid cache key: {}", key)))?;
+  let entry = Entry::find(&cache_dir, key).ok_or(Error::NoEntry(vec![cache_dir.join(&key.0)]))?;
+  let metadata = Metadata::new(&cache_dir)?;
+  let meta = metadata
+    .contents
+    .values()
+    .find_map(|c| c.caches.get(key))
+    .cloned()
+    .unwrap_or_default();
+  let info = BundleInfo {
+    version: BUNDLE_VERSION,
+    key: components,
+    meta,
+    fingerprint: Fingerprint::current(),
+    origin: metadata.origin(key).cloned(),
+  };
+  let info = serde_json::to_vec_pretty(&info).map_err(to_error)?;
+
+  // unpack entries in other formats into a temporary directory to be archived
+  let tmp_dir = TempDir::new_in(&cache_dir).map_err(to_error)?;
+  let dir = match entry {
+    Entry::InUse(link) => fs::read_link(link).map_err(to_error)?,
+    Entry::Dir(dir) => dir,
+    Entry::Manifest(_) => {
+      let dir = tmp_dir.path().join(NODE_MODULES_DIR);
+      Store::new(&cache_dir).materialize(key, &dir, Strategy::Hardlink)?;
+      dir
+    }
+    Entry::Archive(archive_path) => {
+      let dir = tmp_dir.path().join(NODE_MODULES_DIR);
+      archive::decompress(archive_path, &dir)?;
+      dir
+    }
+  };
+  archive::create(output, |builder| {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(info.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+      SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default(),
+    );
+    builder.append_data(&mut header, INFO_FILE_NAME, info.as_slice())?;
+    builder.append_dir_all(NODE_MODULES_DIR, &dir)
+  })
+}
+
+/// Add the cache entry in a bundle made by `export`, and return its key.
+/// The entry is registered as a cache of the project at `base_dir` if given.
+/// A bundle built on another platform is refused unless `force`, and a different Node.js version is warned.
+/// The entry is keyed with the current fingerprint, so that a bundle accepted in spite of the difference is found by this environment.
+pub fn import(
+  cache_dir: Option<impl AsRef<Path>>,
+  bundle: impl AsRef<Path>,
+  base_dir: Option<impl AsRef<Path>>,
+  force: bool,
+) -> Result<Hash> {
+  let cache_dir = resolve_cache_dir(cache_dir)?;
+  let bundle = bundle.as_ref();
+  let to_bundle_error =
+    |reason: &str| Error::IncompatibleBundle(bundle.to_path_buf(), reason.into());
+
+  let mut archive = archive::open(bundle).map_err(to_error)?;
+  let mut entries = archive.entries().map_err(to_error)?;
+  // `bundle.json` comes first to check it before unpacking node_modules
+  let info = match entries.next() {
+    Some(Ok(mut entry)) if entry.path().is_ok_and(|p| p == Path::new(INFO_FILE_NAME)) => {
+      let mut text = String::new();
+      entry.read_to_string(&mut text).map_err(to_error)?;
+      let to_parse_error =
+        |error: serde_json::Error| Error::Parse(vec![bundle.to_path_buf()], error.to_string());
+      let value = serde_json::from_str::<Value>(&text).map_err(to_parse_error)?;
+      let version = value.get("version").and_then(Value::as_u64);
+      if version != Some(BUNDLE_VERSION) {
+        let version = version.map(|v| v.to_string()).unwrap_or_default();
+        return Err(to_bundle_error(&format!("unsupported version {}", version)).into());
+      }
+      serde_json::from_value::<BundleInfo>(value).map_err(to_parse_error)?
+    }
+    _ => return Err(to_bundle_error("bundle.json is missing").into()),
+  };
+  let fingerprint = Fingerprint::current();
+  check_fingerprint(&info.fingerprint, &fingerprint, force)
+    .map_err(|reason| to_bundle_error(&reason))?;
+
+  let dir_key = match base_dir {
+    Some(base_dir) => Some(to_dir_key(find_project_root(base_dir)?)),
+    None => None,
+  };
+  let key = KeyComponents {
+    fingerprint: fingerprint.generate_hash()?.0,
+    ..info.key.clone()
+  }
+  .to_key();
+  if key != info.key.to_key() {
+    log::info!("Import the cache {} as {}", info.key.to_key(), key);
+  }
+  if Entry::find(&cache_dir, &key).is_some() {
+    return Err(Error::Any(format!("The cache {} already exists", key)).into());
+  }
+
+  let tmp_dir = TempDir::new_in(&cache_dir).map_err(to_error)?;
+  for entry in entries {
+    let mut entry = entry.map_err(to_error)?;
+    let is_node_modules = entry.path().is_ok_and(|p| p.starts_with(NODE_MODULES_DIR));
+    if is_node_modules {
+      entry.unpack_in(tmp_dir.path()).map_err(to_error)?;
+    }
+  }
+  let dir: PathBuf = tmp_dir.path().join(NODE_MODULES_DIR);
+  if !dir.is_dir() {
+    return Err(to_bundle_error("node_modules is missing").into());
+  }
+  fs::rename(&dir, cache_dir.join(&key.0)).map_err(to_error)?;
+
+  Metadata::new(&cache_dir)?.modify_all(|contents, entries| {
+    entries.entry(key.clone()).or_default().origin = info.origin;
+    if let Some(dir_key) = dir_key {
+      contents
+        .entry(dir_key)
+        .or_default()
+        .caches
+        .insert(key.clone(), info.meta);
+    }
+  })?;
+  Ok(key)
+}
+
+fn check_fingerprint(
+  bundle: &Fingerprint,
+  current: &Fingerprint,
+  force: bool,
+) -> std::result::Result<(), String> {
+  if bundle.os != current.os || bundle.arch != current.arch {
+    let reason = format!(
+      "built on {}-{} but this is {}-{}",
+      bundle.os, bundle.arch, current.os, current.arch
+    );
+    if !force {
+      return Err(reason);
+    }
+    log::warn!("Import a bundle {}", reason);
+  }
+  let to_string = |v: &Option<Version>| v.map(|v| v.to_string()).unwrap_or(String::from("unknown"));
+  if bundle.node.map(|v| v.0) != current.node.map(|v| v.0) {
+    log::warn!(
+      "A bundle built with Node.js {} may not work with Node.js {}",
+      to_string(&bundle.node),
+      to_string(&current.node)
+    );
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_export_and_import() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let key = Hash(String::from("a-b-c"));
+    fs::create_dir_all(cache_dir.join("a-b-c/foo")).unwrap();
+    fs::write(cache_dir.join("a-b-c/foo/index.js"), "1").unwrap();
+    let origin = PathBuf::from("/home/alice/app");
+    Metadata::new(&cache_dir)
+      .unwrap()
+      .set_origin(&key, Some(&origin))
+      .unwrap();
+    let bundle = tmp_dir.path().join("bundle.tar.zst");
+    export(Some(&cache_dir), &key, &bundle).unwrap();
+
+    let other_cache_dir = tmp_dir.path().join("other");
+    let base_dir = tmp_dir.path().join("bob_app");
+    fs::create_dir_all(&base_dir).unwrap();
+    fs::write(base_dir.join("package.json"), "{}").unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    let imported = import(Some(&other_cache_dir), &bundle, Some(&base_dir), false).unwrap();
+    // the fingerprint `c` of the exporter is replaced with the current one
+    let fingerprint = Fingerprint::current().generate_hash().unwrap();
+    assert_eq!(imported, Hash(format!("a-b-{}", fingerprint)));
+    assert_eq!(
+      fs::read_to_string(other_cache_dir.join(&imported.0).join("foo/index.js")).unwrap(),
+      "1"
+    );
+    let metadata = Metadata::new(&other_cache_dir).unwrap();
+    let contents = &metadata.contents[&to_dir_key(&base_dir)];
+    assert!(contents.caches.contains_key(&imported));
+    assert_eq!(contents.current_hash_key, None);
+    assert_eq!(metadata.origin(&imported), Some(&origin));
+
+    // the same entry is not imported twice
+    assert!(import(Some(&other_cache_dir), &bundle, Some(&base_dir), false).is_err());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_check_fingerprint() {
+    let fingerprint = |os: &str, node: Option<Version>| Fingerprint {
+      os: os.to_string(),
+      arch: String::from("x86_64"),
+      node,
+    };
+    let linux = fingerprint("linux", Some(Version(20, 1, 0)));
+    assert!(check_fingerprint(&linux, &linux, false).is_ok());
+    assert!(check_fingerprint(
+      &linux,
+      &fingerprint("linux", Some(Version(18, 0, 0))),
+      false
+    )
+    .is_ok());
+    assert!(check_fingerprint(&linux, &fingerprint("macos", None), false).is_err());
+    assert!(check_fingerprint(&linux, &fingerprint("macos", None), true).is_ok());
+  }
+}
@@ -0,0 +1,81 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+
+use crate::{
+  cache::{
+    archive::{to_archive_path, ARCHIVE_EXTENSION},
+    metadata::KeyComponents,
+    store::Store,
+  },
+  utils::hash::Hash,
+};
+
+/// A cache entry in the cache directory, kept in one of the formats.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Entry {
+  /// a symlink to node_modules of the project using the entry
+  InUse(PathBuf),
+  /// a directory of node_modules
+  Dir(PathBuf),
+  /// a manifest of files in the content-addressed store
+  Manifest(PathBuf),
+  /// a tar archive compressed by zstd
+  Archive(PathBuf),
+}
+
+impl Entry {
+  /// Find the entry of `key`, preferring the one in use.
+  pub fn find(cache_dir: impl AsRef<Path>, key: &Hash) -> Option<Self> {
+    let path = cache_dir.as_ref().join(key.to_string());
+    let manifest = Store::new(&cache_dir).to_manifest_path(key);
+    let archive = to_archive_path(&cache_dir, key);
+    if path.is_symlink() {
+      Some(Entry::InUse(path))
+    } else if path.is_dir() {
+      Some(Entry::Dir(path))
+    } else if manifest.is_file() {
+      Some(Entry::Manifest(manifest))
+    } else if archive.is_file() {
+      Some(Entry::Archive(archive))
+    } else {
+      None
+    }
+  }
+
+  /// List entries in the cache directory sorted by their keys.
+  pub fn list(cache_dir: impl AsRef<Path>) -> Vec<(Hash, Self)> {
+    let cache_dir = cache_dir.as_ref();
+    let manifests_dir = Store::new(cache_dir).manifests_dir();
+    let archive_suffix = format!(".{}", ARCHIVE_EXTENSION);
+    let keys = read_dir_names(cache_dir)
+      .map(|name| match name.strip_suffix(&archive_suffix) {
+        Some(key) => key.to_string(),
+        None => name,
+      })
+      .chain(
+        read_dir_names(&manifests_dir)
+          .filter_map(|name| name.strip_suffix(".json").map(|key| key.to_string())),
+      )
+      .filter(|name| KeyComponents::parse(name).is_some())
+      .unique()
+      .sorted();
+    keys
+      .filter_map(|key| {
+        let key = Hash(key);
+        Entry::find(cache_dir, &key).map(|entry| (key, entry))
+      })
+      .collect()
+  }
+}
+
+fn read_dir_names(dir: &Path) -> impl Iterator<Item = String> {
+  fs::read_dir(dir)
+    .into_iter()
+    .flatten()
+    .filter_map(|e| e.ok())
+    .map(|e| e.file_name().to_string_lossy().to_string())
+}
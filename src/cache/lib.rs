@@ -1,19 +1,148 @@
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::Result;
 
-use crate::cache::metadata::Metadata;
+use tempfile::TempDir;
+
+use crate::cache::archive::decompress;
+use crate::cache::backend::open_backend;
+use crate::cache::bundle::{export, import};
+use crate::cache::entry::Entry;
+use crate::cache::metadata::{Metadata, StatCache};
+use crate::cache::relocate::relocate;
+use crate::cache::store::Store;
+use crate::cache::strategy::{move_tree, Strategy};
 use crate::core::APP_NAME;
-use crate::errors::Error;
-use crate::utils::path::to_dir_key;
+use crate::errors::{to_error, Error};
+use crate::project::{KeyInputs, Repository};
+use crate::utils::lock::FileLock;
+use crate::utils::path::{to_absolute_path, to_dir_key};
 use crate::utils::{fs, hash::Hash};
 
+const BUNDLE_FILE_NAME: &str = "bundle.tar.zst";
+
+/// Resolve the cache directory, and create it if not exists.
+pub fn resolve_cache_dir(cache_dir: Option<impl AsRef<Path>>) -> Result<PathBuf> {
+  let cache_dir = to_cache_dir(cache_dir)?;
+  fs::exists_dir(&cache_dir).or(fs::make_dir_if_not_exists(&cache_dir))
+}
+
+/// Return the absolute path of the cache directory without creating it.
+pub fn to_cache_dir(cache_dir: Option<impl AsRef<Path>>) -> Result<PathBuf> {
+  let cache_dir = cache_dir
+    .map(|c| c.as_ref().to_path_buf())
+    .or(dirs::cache_dir().map(|c| c.join(APP_NAME)))
+    .ok_or(Error::NotAccessible(PathBuf::from(
+      "Cache directory in your environment",
+    )))?;
+  to_absolute_path(cache_dir)
+}
+
+/// Restoring an entry may wait for it to be compressed, which takes a while for a large node_modules.
+const ENTRY_LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Share the lock of the cache directory among syncs, which only change entries under their own entry locks.
+pub fn share_cache_dir(cache_dir: impl AsRef<Path>, timeout: Duration) -> Result<FileLock> {
+  FileLock::acquire_shared(to_cache_dir_lock(cache_dir), timeout)
+}
+
+/// Lock the whole cache directory exclusively while verifying, repairing or compressing entries of any project.
+pub fn lock_cache_dir(cache_dir: impl AsRef<Path>, timeout: Duration) -> Result<FileLock> {
+  FileLock::acquire(to_cache_dir_lock(cache_dir), timeout)
+}
+
+fn to_cache_dir_lock(cache_dir: impl AsRef<Path>) -> PathBuf {
+  // project locks are named by directory keys, which always contain `-`
+  cache_dir.as_ref().join("locks").join("cache_dir.lock")
+}
+
+/// Lock a project so that only one process syncs its node_modules at the same time.
+/// Lock files are placed in the cache directory not to leave files in the project.
+pub fn lock_project(
+  cache_dir: impl AsRef<Path>,
+  base_dir: impl AsRef<Path>,
+  timeout: Duration,
+) -> Result<FileLock> {
+  let lock_file = cache_dir
+    .as_ref()
+    .join("locks")
+    .join(format!("{}.lock", to_dir_key(base_dir)));
+  FileLock::acquire(lock_file, timeout)
+}
+
+/// Lock a cache entry shared among projects while it is moved, copied or compressed.
+pub(crate) fn lock_entry(
+  cache_dir: impl AsRef<Path>,
+  key: &Hash,
+  timeout: Duration,
+) -> Result<FileLock> {
+  let lock_file = cache_dir
+    .as_ref()
+    .join("locks")
+    .join("entries")
+    .join(format!("{}.lock", key));
+  FileLock::acquire(lock_file, timeout)
+}
+
+/// Return the key of node_modules synced last at `base_dir`, reading metadata without writing it.
+pub fn find_current_key(cache_dir: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> Option<Hash> {
+  Metadata::load(cache_dir)
+    .find_contents(base_dir)?
+    .current_hash_key
+    .clone()
+}
+
+/// Return what the key of the entry was generated from, reading metadata without writing it.
+pub fn find_inputs(cache_dir: impl AsRef<Path>, key: &Hash) -> Option<KeyInputs> {
+  Metadata::load(cache_dir).entries.remove(key)?.inputs
+}
+
+/// Return the key generated last at `base_dir` with the stats of its files, reading metadata without writing it.
+pub fn find_stat_cache(
+  cache_dir: impl AsRef<Path>,
+  base_dir: impl AsRef<Path>,
+) -> Option<StatCache> {
+  Metadata::load(cache_dir)
+    .find_contents(base_dir)?
+    .stat_cache
+    .clone()
+}
+
+/// Record the key generated at `base_dir` to reuse it while none of its files is modified.
+pub fn record_stat_cache(
+  cache_dir: impl AsRef<Path>,
+  base_dir: &Path,
+  stat_cache: &StatCache,
+) -> Result<()> {
+  Metadata::new(cache_dir)?.set_stat_cache(base_dir, stat_cache)?;
+  Ok(())
+}
+
+/// Whether the entry of `key` is in the cache directory in any format.
+pub fn has_entry(cache_dir: impl AsRef<Path>, key: &Hash) -> bool {
+  Entry::find(cache_dir, key).is_some()
+}
+
+/// How caches are kept in the cache directory and restored.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct CacheOptions {
+  pub strategy: Strategy,
+  /// keep caches in the content-addressed store deduplicating files among them
+  pub dedupe: bool,
+  /// compress caches not used for the duration
+  pub compress_after: Option<Duration>,
+  /// a URL or a directory of the remote cache shared among machines
+  pub remote: Option<String>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Cache {
   base_dir: PathBuf,
   target_dir: PathBuf,
   cache_dir: PathBuf,
   metadata: Metadata,
+  options: CacheOptions,
 }
 
 impl Cache {
@@ -24,71 +153,252 @@ impl Cache {
   ) -> Result<Self> {
     let base_dir = fs::exists_dir(base_dir)?;
     let target_dir = fs::exists_dir(target_dir)?;
-    let cache_dir = cache_dir
-      .map(|c| c.as_ref().to_path_buf())
-      .or(dirs::cache_dir().map(|c| c.join(APP_NAME)))
-      .ok_or(Error::NotAccessible(PathBuf::from(
-        "Cache directory in your environment",
-      )))?;
-    let cache_dir = fs::exists_dir(&cache_dir).or(fs::make_dir_if_not_exists(&cache_dir))?;
+    let cache_dir = resolve_cache_dir(cache_dir)?;
     let metadata = Metadata::new(&cache_dir)?;
     Ok(Self {
       base_dir,
       target_dir,
       cache_dir,
       metadata,
+      options: CacheOptions::default(),
     })
   }
 
+  pub fn with_options(self, options: CacheOptions) -> Self {
+    Self { options, ..self }
+  }
+
   fn to_cache_path(&self, key: &Hash) -> PathBuf {
     self.cache_dir.join(key.to_string())
   }
 
   pub fn save(&self, key: Hash) -> Result<Self> {
     let cache = self.to_cache_path(&key);
-    fs::create_symlink(&self.target_dir, cache).or::<Error>(Ok(()))?;
-    let metadata = Metadata::new(&self.cache_dir)?;
-    // TODO: branch and commit
-    metadata.update(&self.base_dir, &key, "branch".into(), "commit".into())?;
+    let _lock = lock_entry(&self.cache_dir, &key, ENTRY_LOCK_TIMEOUT)?;
+    // node_modules installed just now takes over the entry of the same contents, unless node_modules of another project
+    // such as a sibling worktree is the entry, so that the entry never belongs to two projects at the same time
+    let owner = self.find_other_owner(&key);
+    if let Some(owner) = &owner {
+      log::info!(
+        "Keep the cache {} owned by {}",
+        key,
+        owner.to_string_lossy()
+      );
+    } else {
+      fs::create_symlink(&self.target_dir, cache).or::<Error>(Ok(()))?;
+    }
+    let repository = Repository::discover(&self.base_dir);
+    let metadata =
+      Metadata::new(&self.cache_dir)?.update(&self.base_dir, &key, repository.as_ref())?;
+    if owner.is_none() {
+      metadata.set_origin(&key, Some(&self.base_dir))?;
+    }
+    Ok(self.clone())
+  }
+
+  /// Record the key generated at the project to reuse it while none of its files is modified.
+  pub fn record_stat_cache(&self, stat_cache: &StatCache) -> Result<Self> {
+    record_stat_cache(&self.cache_dir, &self.base_dir, stat_cache)?;
     Ok(self.clone())
   }
 
-  pub fn revoke_current_cache(&self, base_dir: &PathBuf) -> Result<Self> {
-    if let Some(current_cache_key) = self.find_current_cache(base_dir) {
-      fs::rename(&self.target_dir, self.to_cache_path(&current_cache_key))?;
+  /// Record the inputs of the key of the entry saved, to explain later why the key changes.
+  pub fn record_inputs(&self, inputs: &KeyInputs) -> Result<Self> {
+    Metadata::new(&self.cache_dir)?.set_inputs(&inputs.to_key(), inputs)?;
+    Ok(self.clone())
+  }
+
+  /// Return node_modules of another project which the entry of `key` is, unless it has gone with a removed worktree.
+  fn find_other_owner(&self, key: &Hash) -> Option<PathBuf> {
+    match Entry::find(&self.cache_dir, key) {
+      Some(Entry::InUse(link)) if !self.is_own(&link) => std::fs::read_link(link)
+        .ok()
+        .filter(|target| target.is_dir()),
+      _ => None,
+    }
+  }
+
+  /// Whether the entry of `link` in use is node_modules of this project.
+  fn is_own(&self, link: &Path) -> bool {
+    std::fs::read_link(link).is_ok_and(|target| target == self.target_dir)
+  }
+
+  /// Move the current node_modules into the cache, and return its key if revoked.
+  pub fn revoke_current_cache(&self, base_dir: &PathBuf) -> Result<Option<Hash>> {
+    let current_cache_key = self.find_current_cache(base_dir);
+    if let Some(current_cache_key) = &current_cache_key {
+      self.evacuate(current_cache_key)?;
     };
+    Ok(current_cache_key)
+  }
+
+  /// Undo `revoke_current_cache`, discarding node_modules left by a failed install.
+  pub fn rollback(&self, key: &Hash) -> Result<Self> {
+    self.materialize(key)?;
     Ok(self.clone())
   }
 
+  /// Whether `node_modules_dir` belongs to another worktree of the git repository of this project.
+  fn is_sibling_worktree(&self, node_modules_dir: &Path) -> bool {
+    match (
+      Repository::discover(&self.base_dir),
+      Repository::discover(node_modules_dir),
+    ) {
+      (Some(this), Some(other)) => {
+        this.common_dir == other.common_dir && this.git_dir != other.git_dir
+      }
+      _ => false,
+    }
+  }
+
+  /// Take node_modules away into the cache entry of `key`.
+  fn evacuate(&self, key: &Hash) -> Result<()> {
+    let cache = self.to_cache_path(key);
+    let _lock = lock_entry(&self.cache_dir, key, ENTRY_LOCK_TIMEOUT)?;
+    match Entry::find(&self.cache_dir, key) {
+      // the entry has been kept by copying or linking, or is in use by another project,
+      // so node_modules is its duplicate
+      Some(Entry::InUse(link)) if !self.is_own(&link) => {
+        std::fs::remove_dir_all(&self.target_dir).map_err(to_error)
+      }
+      Some(Entry::Dir(_)) | Some(Entry::Manifest(_)) | Some(Entry::Archive(_)) => {
+        std::fs::remove_dir_all(&self.target_dir).map_err(to_error)
+      }
+      _ if self.options.dedupe => {
+        Store::new(&self.cache_dir).ingest(key, &self.target_dir)?;
+        std::fs::remove_dir_all(&self.target_dir).map_err(to_error)?;
+        if cache.is_symlink() {
+          fs::remove_symlink(cache)?;
+        }
+        Ok(())
+      }
+      _ => move_tree(&self.target_dir, cache),
+    }
+  }
+
+  /// Put the cache entry of `key` at node_modules, rewriting absolute paths of the project where the entry was built.
+  fn materialize(&self, key: &Hash) -> Result<()> {
+    let _lock = lock_entry(&self.cache_dir, key, ENTRY_LOCK_TIMEOUT)?;
+    let metadata = Metadata::new(&self.cache_dir)?;
+    let strategy = self.options.strategy;
+    let is_renamed = match Entry::find(&self.cache_dir, key) {
+      Some(Entry::Manifest(_)) => {
+        Store::new(&self.cache_dir).materialize(key, &self.target_dir, strategy)?;
+        false
+      }
+      Some(Entry::InUse(link)) => {
+        // node_modules of another project is duplicated, since it can't be taken away
+        let in_use = std::fs::read_link(link).map_err(to_error)?;
+        if self.is_sibling_worktree(&in_use) {
+          log::info!(
+            "Copy the cache {} from the worktree {}",
+            key,
+            in_use.parent().unwrap_or(&in_use).to_string_lossy()
+          );
+        }
+        let strategy = strategy.resolve_duplicate(&in_use, &self.target_dir);
+        strategy.materialize(in_use, &self.target_dir)?;
+        false
+      }
+      entry => {
+        let cache = self.to_cache_path(key);
+        if let Some(Entry::Archive(archive)) = entry {
+          // an archive is unpacked into an entry used as usual until compressed again
+          decompress(&archive, &cache)?;
+          std::fs::remove_file(archive).map_err(to_error)?;
+        }
+        let is_renamed = strategy.materialize(&cache, &self.target_dir)? == Strategy::Rename;
+        if is_renamed {
+          // the symlink marks the entry in use as well as after `save`
+          fs::create_symlink(&self.target_dir, cache)?;
+        }
+        is_renamed
+      }
+    };
+    if let Some(origin) = metadata.origin(key).filter(|o| **o != self.base_dir) {
+      let relocated = relocate(&self.target_dir, origin, &self.base_dir)?;
+      if relocated > 0 {
+        log::info!(
+          "Rewrote {} paths of {} in node_modules",
+          relocated,
+          origin.to_string_lossy()
+        );
+      }
+      if is_renamed {
+        metadata.set_origin(key, Some(&self.base_dir))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Download the bundle of `key` from the remote cache into the cache directory, and return if found.
+  pub fn fetch(&self, key: &Hash) -> Result<bool> {
+    let Some(remote) = &self.options.remote else {
+      return Ok(false);
+    };
+    let backend = open_backend(remote)?;
+    let tmp_dir = TempDir::new_in(&self.cache_dir).map_err(to_error)?;
+    let bundle = tmp_dir.path().join(BUNDLE_FILE_NAME);
+    if !backend.download(key, &bundle)? {
+      return Ok(false);
+    }
+    let imported = import(Some(&self.cache_dir), &bundle, Some(&self.base_dir), false)?;
+    log::info!("Downloaded the cache {} from {}", imported, remote);
+    // a bundle uploaded from another environment under the key is kept as its own entry
+    if imported != *key {
+      log::warn!("The cache {} downloaded is not {}", imported, key);
+      return Ok(false);
+    }
+    Ok(true)
+  }
+
+  /// Upload the cache entry of `key` to the remote cache unless it exists there.
+  pub fn publish(&self, key: &Hash) -> Result<()> {
+    let Some(remote) = &self.options.remote else {
+      return Ok(());
+    };
+    let backend = open_backend(remote)?;
+    if backend.exists(key)? {
+      return Ok(());
+    }
+    let tmp_dir = TempDir::new_in(&self.cache_dir).map_err(to_error)?;
+    let bundle = tmp_dir.path().join(BUNDLE_FILE_NAME);
+    export(Some(&self.cache_dir), key, &bundle)?;
+    backend.upload(key, &bundle)?;
+    log::info!("Uploaded the cache {} to {}", key, remote);
+    Ok(())
+  }
+
   pub fn find_current_cache(&self, base_dir: &PathBuf) -> Option<Hash> {
-    let dir_key = to_dir_key(base_dir);
-    let current_hash_key = Metadata::new(&self.cache_dir)
+    Metadata::new(&self.cache_dir)
       .ok()?
-      .contents
-      .get(&dir_key)?
+      .find_contents(base_dir)?
+      .current_hash_key
       .clone()
-      .current_hash_key?;
-    Some(current_hash_key)
   }
 
   pub fn restore(&self, base_dir: &PathBuf, key: &Hash) -> Result<Self> {
-    let key: String = key.to_string();
-    let cache = self.cache_dir.join(&key);
+    let cache = self.to_cache_path(key);
 
-    if cache.is_symlink() {
+    let entry = Entry::find(&self.cache_dir, key);
+    if matches!(&entry, Some(Entry::InUse(link)) if self.is_own(link)) {
       Ok(self.clone())
-    } else if cache.is_dir() {
+    } else if entry.is_some() {
       if let Some(current_hash_key) = self.find_current_cache(base_dir) {
-        if current_hash_key.to_string() == key {
+        if current_hash_key == *key {
           return Ok(self.clone());
         }
         // escape the current cache if exists
-        fs::rename(&self.target_dir, self.to_cache_path(&current_hash_key))
+        self
+          .evacuate(&current_hash_key)
           .map_err(|error| error.context("Failed to save the old cache"))
           .unwrap_or(());
       }
       // restore the cache
-      fs::rename(cache, &self.target_dir).and(Ok(self.clone()))
+      self.materialize(key)?;
+      let metadata = Metadata::new(&self.cache_dir)?;
+      metadata.set_current(base_dir, key)?;
+      Ok(self.clone())
     } else {
       Err(Error::NotDir(cache).into())
     }
@@ -101,9 +411,11 @@ mod tests {
   use std::{collections::HashMap, fs, path::PathBuf};
 
   use crate::{
+    project::Fingerprint,
     test_each,
     utils::{
       fs::exists_dir,
+      hash::Hashable,
       path::{clean_path_separator, to_absolute_path},
       result::convert_panic_to_result,
     },
@@ -118,10 +430,9 @@ mod tests {
     let cache_dir = case.input.2.clone().and_then(|c| exists_dir(c).ok());
     let cache = Cache::new(case.input.0, case.input.1, case.input.2);
     let result = convert_panic_to_result(|| {
-      if case.expected.is_ok() {
+      if let Ok(expected) = &case.expected {
         assert!(cache.is_ok());
         let cache = cache.as_ref().unwrap();
-        let expected = &case.expected.unwrap();
         assert_eq!(clean_path_separator(&cache.base_dir), expected.base_dir);
         assert_eq!(clean_path_separator(&cache.target_dir), expected.target_dir);
         assert_eq!(clean_path_separator(&cache.cache_dir), expected.cache_dir);
@@ -135,7 +446,10 @@ mod tests {
       }
     });
     if cache_dir.is_some() {
-      fs::remove_file(cache.unwrap().metadata.file_path).unwrap();
+      let file_path = cache.unwrap().metadata.file_path;
+      fs::remove_file(&file_path).unwrap();
+      // the lock taken to create metadata.json
+      fs::remove_file(file_path.with_extension("json.lock")).unwrap_or_default();
     } else {
       fs::remove_dir_all(cache.unwrap().cache_dir).unwrap();
     }
@@ -153,8 +467,10 @@ mod tests {
         cache_dir: dirs::cache_dir().unwrap().join(APP_NAME),
         metadata: Metadata {
           contents: HashMap::new(),
+          entries: HashMap::new(),
           file_path: dirs::cache_dir().unwrap().join(APP_NAME).join("metadata.json"),
         },
+        options: CacheOptions::default(),
       }),
     },
     "2" => CacheNewTestCase {
@@ -165,8 +481,10 @@ mod tests {
         cache_dir: to_absolute_path("tests/fixtures/cache/.cache").unwrap(),
         metadata: Metadata {
           contents: HashMap::new(),
+          entries: HashMap::new(),
           file_path: to_absolute_path("tests/fixtures/cache/.cache/metadata.json").unwrap(),
         },
+        options: CacheOptions::default(),
       }),
     },
     "3" => CacheNewTestCase {
@@ -177,9 +495,317 @@ mod tests {
         cache_dir: to_absolute_path("tests/fixtures/cache").unwrap(),
         metadata: Metadata {
           contents: HashMap::new(),
+          entries: HashMap::new(),
           file_path: to_absolute_path("tests/fixtures/cache/metadata.json").unwrap(),
         },
+        options: CacheOptions::default(),
       }),
     },
   );
+
+  #[test]
+  fn test_rollback() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let target_dir = base_dir.join("node_modules");
+    fs::create_dir_all(target_dir.join("foo")).unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let key = Hash(String::from("a-b-c"));
+
+    let cache = Cache::new(&base_dir, &target_dir, Some(&cache_dir)).unwrap();
+    cache.save(key.clone()).unwrap();
+    assert_eq!(
+      cache.revoke_current_cache(&base_dir).unwrap(),
+      Some(key.clone())
+    );
+    assert!(!target_dir.exists());
+    assert!(cache_dir.join("a-b-c/foo").is_dir());
+
+    // a failed install leaves node_modules partially
+    fs::create_dir_all(target_dir.join("bar")).unwrap();
+    cache.rollback(&key).unwrap();
+    assert!(target_dir.join("foo").is_dir());
+    assert!(!target_dir.join("bar").exists());
+    assert!(cache_dir.join("a-b-c").is_symlink());
+    assert_eq!(cache.find_current_cache(&base_dir), Some(key));
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_restore_with_copy() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let target_dir = base_dir.join("node_modules");
+    fs::create_dir_all(target_dir.join("foo")).unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let (key1, key2) = (Hash(String::from("a-b-c")), Hash(String::from("d-e-c")));
+
+    let cache = Cache::new(&base_dir, &target_dir, Some(&cache_dir))
+      .unwrap()
+      .with_options(CacheOptions {
+        strategy: Strategy::Copy,
+        ..Default::default()
+      });
+    cache.save(key1.clone()).unwrap();
+    cache.revoke_current_cache(&base_dir).unwrap();
+    fs::create_dir_all(target_dir.join("bar")).unwrap();
+    cache.save(key2.clone()).unwrap();
+
+    cache.restore(&base_dir, &key1).unwrap();
+    assert!(target_dir.join("foo").is_dir());
+    assert!(!target_dir.is_symlink());
+    // the entry is kept to be restored by other projects
+    assert!(cache_dir.join("a-b-c/foo").is_dir());
+    assert!(cache_dir.join("d-e-c/bar").is_dir());
+    assert_eq!(cache.find_current_cache(&base_dir), Some(key1));
+
+    cache.restore(&base_dir, &key2).unwrap();
+    assert!(target_dir.join("bar").is_dir());
+    assert!(!target_dir.join("foo").exists());
+    assert!(cache_dir.join("a-b-c/foo").is_dir());
+    assert_eq!(cache.find_current_cache(&base_dir), Some(key2));
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_restore_with_dedupe() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let target_dir = base_dir.join("node_modules");
+    fs::create_dir_all(target_dir.join("foo")).unwrap();
+    fs::write(target_dir.join("foo/index.js"), "module.exports = 1").unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let (key1, key2) = (Hash(String::from("a-b-c")), Hash(String::from("d-e-c")));
+
+    let cache = Cache::new(&base_dir, &target_dir, Some(&cache_dir))
+      .unwrap()
+      .with_options(CacheOptions {
+        dedupe: true,
+        ..Default::default()
+      });
+    cache.save(key1.clone()).unwrap();
+    cache.revoke_current_cache(&base_dir).unwrap();
+    assert!(!target_dir.exists());
+    assert_eq!(
+      Entry::find(&cache_dir, &key1),
+      Some(Entry::Manifest(
+        cache_dir.join("store/manifests/a-b-c.json")
+      ))
+    );
+
+    fs::create_dir_all(target_dir.join("bar")).unwrap();
+    cache.save(key2.clone()).unwrap();
+    cache.restore(&base_dir, &key1).unwrap();
+    assert_eq!(
+      fs::read_to_string(target_dir.join("foo/index.js")).unwrap(),
+      "module.exports = 1"
+    );
+    assert!(matches!(
+      Entry::find(&cache_dir, &key2),
+      Some(Entry::Manifest(_))
+    ));
+    assert_eq!(cache.find_current_cache(&base_dir), Some(key1.clone()));
+
+    // node_modules restored from the store is dropped on leaving
+    cache.restore(&base_dir, &key2).unwrap();
+    assert!(target_dir.join("bar").is_dir());
+    assert!(matches!(
+      Entry::find(&cache_dir, &key1),
+      Some(Entry::Manifest(_))
+    ));
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_restore_from_archive() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let target_dir = base_dir.join("node_modules");
+    fs::create_dir_all(target_dir.join("foo")).unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let (key1, key2) = (Hash(String::from("a-b-c")), Hash(String::from("d-e-c")));
+
+    let cache = Cache::new(&base_dir, &target_dir, Some(&cache_dir)).unwrap();
+    cache.save(key1.clone()).unwrap();
+    cache.revoke_current_cache(&base_dir).unwrap();
+    fs::create_dir_all(target_dir.join("bar")).unwrap();
+    cache.save(key2.clone()).unwrap();
+    let metadata = Metadata::new(&cache_dir).unwrap();
+    assert!(metadata.last_used(&key1).is_some());
+
+    // the entry in use is not compressed
+    let compressed = crate::cache::compress_unused(&cache_dir, Duration::ZERO).unwrap();
+    assert_eq!(compressed, vec![key1.clone()]);
+
+    cache.restore(&base_dir, &key1).unwrap();
+    assert!(target_dir.join("foo").is_dir());
+    assert!(!cache_dir.join("a-b-c.tar.zst").exists());
+    assert!(cache_dir.join("a-b-c").is_symlink());
+    assert_eq!(cache.find_current_cache(&base_dir), Some(key1));
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_restore_in_another_project() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let new_cache = |name: &str| {
+      let base_dir = tmp_dir.path().join(name);
+      fs::create_dir_all(base_dir.join("node_modules/.bin")).unwrap();
+      let base_dir = exists_dir(base_dir).unwrap();
+      Cache::new(
+        &base_dir,
+        base_dir.join("node_modules"),
+        Some(tmp_dir.path().join("cache")),
+      )
+      .unwrap()
+    };
+    let shim = |cache: &Cache| fs::read_to_string(cache.target_dir.join(".bin/foo")).unwrap();
+    let (app, clone) = (new_cache("app"), new_cache("clone"));
+    let key = Hash(String::from("a-b-c"));
+    fs::write(
+      app.target_dir.join(".bin/foo"),
+      format!("{}/node_modules/foo", app.base_dir.display()),
+    )
+    .unwrap();
+    app.save(key.clone()).unwrap();
+    app.revoke_current_cache(&app.base_dir).unwrap();
+    fs::create_dir_all(&app.target_dir).unwrap();
+    app.save(Hash(String::from("d-e-c"))).unwrap();
+
+    // the entry left by the project is taken by its clone
+    clone.restore(&clone.base_dir, &key).unwrap();
+    assert_eq!(
+      shim(&clone),
+      format!("{}/node_modules/foo", clone.base_dir.display())
+    );
+    assert_eq!(
+      fs::read_link(app.cache_dir.join("a-b-c")).unwrap(),
+      clone.target_dir
+    );
+    let metadata = Metadata::new(&app.cache_dir).unwrap();
+    assert_eq!(metadata.origin(&key), Some(&clone.base_dir));
+
+    // the entry in use by the clone is duplicated
+    app.restore(&app.base_dir, &key).unwrap();
+    assert_eq!(
+      shim(&app),
+      format!("{}/node_modules/foo", app.base_dir.display())
+    );
+    assert_eq!(
+      shim(&clone),
+      format!("{}/node_modules/foo", clone.base_dir.display())
+    );
+    assert_eq!(app.find_current_cache(&app.base_dir), Some(key.clone()));
+    app.revoke_current_cache(&app.base_dir).unwrap();
+    assert!(!app.target_dir.exists());
+    assert!(clone.target_dir.join(".bin/foo").is_file());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_save_in_worktrees() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let root = exists_dir(tmp_dir.path()).unwrap();
+    // a repository with a worktree added by `git worktree add`
+    let git_dir = root.join("main/.git");
+    fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+    fs::create_dir_all(git_dir.join("worktrees/feature")).unwrap();
+    fs::create_dir_all(root.join("feature")).unwrap();
+    fs::write(git_dir.join("HEAD"), "ref: refs/heads/main").unwrap();
+    fs::write(git_dir.join("refs/heads/main"), "1234abcd").unwrap();
+    fs::write(
+      root.join("feature/.git"),
+      format!("gitdir: {}", git_dir.join("worktrees/feature").display()),
+    )
+    .unwrap();
+    fs::write(git_dir.join("worktrees/feature/commondir"), "../..").unwrap();
+    fs::write(git_dir.join("worktrees/feature/HEAD"), "1234abcd").unwrap();
+    let new_cache = |name: &str| {
+      let base_dir = root.join(name);
+      fs::create_dir_all(base_dir.join("node_modules/foo")).unwrap();
+      Cache::new(
+        &base_dir,
+        base_dir.join("node_modules"),
+        Some(root.join("cache")),
+      )
+      .unwrap()
+    };
+    let (main, feature) = (new_cache("main"), new_cache("feature"));
+    let key = Hash(String::from("a-b-c"));
+
+    main.save(key.clone()).unwrap();
+    // the worktree installing the same dependencies doesn't take the entry from the main worktree
+    feature.save(key.clone()).unwrap();
+    assert_eq!(
+      fs::read_link(main.cache_dir.join("a-b-c")).unwrap(),
+      main.target_dir
+    );
+    let metadata = Metadata::new(&main.cache_dir).unwrap();
+    assert_eq!(metadata.origin(&key), Some(&main.base_dir));
+    let (main_meta, feature_meta) = (
+      &metadata.contents[&to_dir_key(&main.base_dir)],
+      &metadata.contents[&to_dir_key(&feature.base_dir)],
+    );
+    assert_eq!(main_meta.repository, Some(git_dir.clone()));
+    assert_eq!(feature_meta.repository, Some(git_dir.clone()));
+    assert_eq!(main_meta.caches[&key].branch, "main");
+    assert_eq!(feature_meta.caches[&key].branch, "HEAD");
+    assert_eq!(feature_meta.caches[&key].commit, "1234abcd");
+
+    // the entry of a removed worktree is taken over
+    fs::remove_dir_all(&main.target_dir).unwrap();
+    feature.save(key.clone()).unwrap();
+    assert_eq!(
+      fs::read_link(main.cache_dir.join("a-b-c")).unwrap(),
+      feature.target_dir
+    );
+    let metadata = Metadata::new(&main.cache_dir).unwrap();
+    assert_eq!(metadata.origin(&key), Some(&feature.base_dir));
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_publish_and_fetch() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let target_dir = base_dir.join("node_modules");
+    fs::create_dir_all(target_dir.join("foo")).unwrap();
+    fs::write(base_dir.join("package.json"), "{}").unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    let fingerprint = Fingerprint::current().generate_hash().unwrap();
+    let key = Hash(format!("a-b-{}", fingerprint));
+    let options = CacheOptions {
+      remote: Some(tmp_dir.path().join("remote").to_string_lossy().to_string()),
+      ..Default::default()
+    };
+
+    let cache = Cache::new(&base_dir, &target_dir, Some(tmp_dir.path().join("cache")))
+      .unwrap()
+      .with_options(options.clone());
+    cache.save(key.clone()).unwrap();
+    cache.publish(&key).unwrap();
+    let remote = open_backend(options.remote.as_ref().unwrap()).unwrap();
+    assert!(remote.exists(&key).unwrap());
+
+    // another machine misses the cache locally
+    let other_cache_dir = tmp_dir.path().join("other");
+    let other = Cache::new(&base_dir, &target_dir, Some(&other_cache_dir))
+      .unwrap()
+      .with_options(options);
+    assert!(other.fetch(&key).unwrap());
+    assert!(matches!(
+      Entry::find(&other_cache_dir, &key),
+      Some(Entry::Dir(_))
+    ));
+    assert!(!other.fetch(&Hash(String::from("x-y-z"))).unwrap());
+
+    // a bundle uploaded under a key of another fingerprint is imported with the current one
+    let other_key = Hash(String::from("c-d-e"));
+    cache.save(other_key.clone()).unwrap();
+    cache.publish(&other_key).unwrap();
+    assert!(!other.fetch(&other_key).unwrap());
+    assert!(Entry::find(&other_cache_dir, &other_key).is_none());
+    assert!(Entry::find(&other_cache_dir, &Hash(format!("c-d-{}", fingerprint))).is_some());
+    tmp_dir.close().unwrap();
+  }
 }
@@ -1,83 +1,940 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::errors::{to_error, Error};
-use crate::utils::path::{to_dir_key, DirKey};
+use crate::cache::entry::Entry;
+use crate::errors::Error;
+use crate::project::{Fingerprint, KeyInputs, Repository};
+use crate::utils::hash::Hashable;
+use crate::utils::lock::FileLock;
+use crate::utils::path::{to_dir_key, to_legacy_dir_key, DirKey};
 use crate::utils::{fs, hash::Hash};
 
+/// Updating metadata takes a moment, so a long wait means the holder is stuck.
+const UPDATE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+const FILE_NAME: &str = "metadata.json";
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The version of the layout of metadata.json, which is incremented on breaking changes.
+/// Files without `version` field are the layout before versioning.
+pub const METADATA_VERSION: u64 = 3;
+
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
 pub struct CacheMeta {
-  branch: String,
-  commit: String,
+  pub branch: String,
+  pub commit: String,
+  /// seconds since the Unix epoch when the cache was last in use
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  last_used: Option<u64>,
 }
 
+/// Caches used by a project, whose keys are shared with the other projects.
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
 pub struct MetadataContents {
   pub current_hash_key: Option<Hash>,
   pub caches: HashMap<Hash, CacheMeta>,
+  /// the common git directory of the project, shared among worktrees of the repository
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub repository: Option<PathBuf>,
+  /// the key generated last with the stats of the files it was generated from
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub stat_cache: Option<StatCache>,
+}
+
+/// What a file looked like, which tells whether it is modified without reading it.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct FileStat {
+  pub path: PathBuf,
+  pub size: u64,
+  /// nanoseconds since the Unix epoch
+  pub mtime: u64,
+  /// 0 on platforms without inodes
+  pub inode: u64,
+}
+
+impl FileStat {
+  pub fn new(path: impl AsRef<Path>) -> Option<Self> {
+    let metadata = std::fs::metadata(&path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    Some(Self {
+      path: path.as_ref().to_path_buf(),
+      size: metadata.len(),
+      mtime: mtime.as_nanos() as u64,
+      inode,
+    })
+  }
+
+  /// Whether the file is the same as when the stat was taken at `at`.
+  /// A file modified around `at` may be modified again without changing the stat, so that it is taken as modified
+  /// as racy git does, in the same second on filesystems without sub-second mtime.
+  fn is_unmodified(&self, at: u64) -> bool {
+    let racy = if self.mtime.is_multiple_of(NANOS_PER_SEC) {
+      self.mtime >= at - at % NANOS_PER_SEC
+    } else {
+      self.mtime >= at
+    };
+    !racy && Self::new(&self.path).as_ref() == Some(self)
+  }
+}
+
+/// The cache key generated last at a project with the stats of the files it was generated from,
+/// so that the key is reused without reading and hashing the files while none of them is modified.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct StatCache {
+  pub key: Hash,
+  /// nanoseconds since the Unix epoch when the stats were taken
+  pub at: u64,
+  pub files: Vec<FileStat>,
+  /// files which change the key when added, such as another lockfile
+  pub absent: Vec<PathBuf>,
+  /// the executable of Node.js in PATH, which changes the fingerprint
+  pub node: Option<FileStat>,
+}
+
+impl StatCache {
+  /// Take the stats of `files` and the absence of `candidates` before generating the key from them, so that the files
+  /// modified meanwhile fail the stats. The key is set by `with_key` after generating it.
+  pub fn take(files: &[PathBuf], candidates: &[PathBuf]) -> Self {
+    let at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |d| d.as_nanos() as u64);
+    Self {
+      key: Hash(String::new()),
+      at,
+      files: files.iter().filter_map(FileStat::new).collect(),
+      absent: candidates
+        .iter()
+        .filter(|path| !files.contains(path) && !path.exists())
+        .cloned()
+        .collect(),
+      node: Fingerprint::find_node().and_then(FileStat::new),
+    }
+  }
+
+  pub fn with_key(self, key: Hash) -> Self {
+    Self { key, ..self }
+  }
+
+  /// Whether the stats are taken of exactly `files`, which may change as workspaces are added or removed.
+  pub fn has_files(&self, files: &[PathBuf]) -> bool {
+    self.files.len() == files.len() && self.files.iter().zip(files).all(|(s, f)| s.path == *f)
+  }
+
+  /// Whether none of the files is modified, removed or added, and Node.js in PATH is the same, so that the key is valid.
+  /// A shim of Node.js may switch the version for the project without changing, so that the key is generated again.
+  pub fn is_fresh(&self) -> bool {
+    self.is_fresh_with(Fingerprint::find_node())
+  }
+
+  fn is_fresh_with(&self, node: Option<PathBuf>) -> bool {
+    self.files.iter().all(|stat| stat.is_unmodified(self.at))
+      && self.absent.iter().all(|path| !path.exists())
+      && !node.as_ref().is_some_and(Fingerprint::is_shim)
+      && node.and_then(FileStat::new) == self.node
+  }
+}
+
+impl MetadataContents {
+  /// Switch the cache in use to `hash`, recording when the previous one was left.
+  fn switch_current(&mut self, hash: &Hash) {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs())
+      .ok();
+    for key in self.current_hash_key.iter().chain([hash]) {
+      if let Some(meta) = self.caches.get_mut(key) {
+        meta.last_used = now;
+      }
+    }
+    self.current_hash_key = Some(hash.clone());
+  }
+}
+
+/// A cache entry in the cache directory, which may be used by any project.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
+pub struct EntryMeta {
+  /// the project directory whose absolute paths are baked into the entry, or `None` if unknown
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub origin: Option<PathBuf>,
+  /// what the key of the entry was generated from, or `None` if unknown
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub inputs: Option<KeyInputs>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct MetadataFile {
+  version: u64,
+  contents: HashMap<DirKey, MetadataContents>,
+  #[serde(default)]
+  entries: HashMap<Hash, EntryMeta>,
 }
 
 #[derive(PartialEq, Clone, Debug, Default)]
 pub struct Metadata {
   pub contents: HashMap<DirKey, MetadataContents>,
+  pub entries: HashMap<Hash, EntryMeta>,
   pub file_path: PathBuf,
 }
 
 impl Metadata {
   pub fn new(cache_dir: impl AsRef<Path>) -> Result<Self> {
-    const FILE_NAME: &str = "metadata.json";
+    let cache_dir = cache_dir.as_ref();
+    let file_path = cache_dir.join(FILE_NAME);
+    let file = match fs::read_to_string(&file_path)
+      .ok()
+      .map(|text| Self::parse(&file_path, &text))
+    {
+      Some(Ok(file)) if file.version == METADATA_VERSION => file,
+      // writing the file races with updates by others
+      _ => {
+        let _lock = Self::lock(&file_path)?;
+        Self::prepare(cache_dir, &file_path)?
+      }
+    };
+    Ok(Self {
+      contents: file.contents,
+      entries: file.entries,
+      file_path,
+    })
+  }
+
+  /// Read metadata.json without creating, migrating or rebuilding it, so that the cache directory is inspected as it is.
+  /// Metadata in an older layout or broken reads as empty.
+  pub fn load(cache_dir: impl AsRef<Path>) -> Self {
     let file_path = cache_dir.as_ref().join(FILE_NAME);
-    let contents = fs::read_to_string(&file_path);
-    match contents {
-      Ok(contents) => serde_json::from_str::<HashMap<DirKey, MetadataContents>>(&contents)
-        .map(|contents| Self {
+    let file = fs::read_to_string(&file_path)
+      .ok()
+      .and_then(|text| Self::parse(&file_path, &text).ok())
+      .filter(|file| file.version == METADATA_VERSION);
+    match file {
+      Some(file) => Self {
+        contents: file.contents,
+        entries: file.entries,
+        file_path,
+      },
+      None => Self {
+        file_path,
+        ..Default::default()
+      },
+    }
+  }
+
+  /// Parse the contents of metadata.json in any layout up to the current one.
+  fn parse(file_path: &Path, text: &str) -> Result<MetadataFile> {
+    let to_parse_error = |message: String| Error::Parse(vec![file_path.to_path_buf()], message);
+    let value = serde_json::from_str::<Value>(text).map_err(|e| to_parse_error(e.to_string()))?;
+    match value.get("version").map(Value::as_u64) {
+      Some(Some(version)) if version > METADATA_VERSION => Err(
+        Error::Any(format!(
+          "{} is written by a newer version of metadata ({version})",
+          file_path.to_string_lossy()
+        ))
+        .into(),
+      ),
+      Some(Some(_)) => serde_json::from_value::<MetadataFile>(value)
+        .map_err(|e| to_parse_error(e.to_string()).into()),
+      Some(None) => Err(to_parse_error(String::from("invalid version")).into()),
+      None => serde_json::from_value::<HashMap<DirKey, MetadataContents>>(value)
+        .map(|contents| MetadataFile {
+          version: 0,
           contents,
-          file_path: file_path.clone(),
+          entries: HashMap::new(),
         })
-        .map_err(|error| Error::Parse(vec![file_path], error.to_string()).into()),
-      Err(_) => {
-        let v = Self {
-          file_path: file_path.clone(),
-          ..Self::default()
-        };
-        let contents = serde_json::to_string(&v.contents).map_err(to_error)?;
-        fs::write(&file_path, contents)?;
-        Ok(v)
+        .map_err(|e| to_parse_error(e.to_string()).into()),
+    }
+  }
+
+  fn write(file_path: &Path, file: &MetadataFile) -> Result<()> {
+    let json = serde_json::to_string(file)
+      .map_err(|error| Error::Parse(vec![file_path.to_path_buf()], error.to_string()))?;
+    fs::write_atomic(file_path, json)
+  }
+
+  fn lock(file_path: &Path) -> Result<FileLock> {
+    // serialize read-modify-write among processes and threads
+    let mut lock_file = file_path.to_path_buf().into_os_string();
+    lock_file.push(".lock");
+    FileLock::acquire(PathBuf::from(lock_file), UPDATE_LOCK_TIMEOUT)
+  }
+
+  /// Create, migrate or rebuild metadata.json while holding the lock, unless another process has done it meanwhile.
+  fn prepare(cache_dir: &Path, file_path: &Path) -> Result<MetadataFile> {
+    let Ok(text) = fs::read_to_string(file_path) else {
+      let file = MetadataFile {
+        version: METADATA_VERSION,
+        contents: HashMap::new(),
+        entries: HashMap::new(),
+      };
+      Self::write(file_path, &file)?;
+      return Ok(file);
+    };
+    match Self::parse(file_path, &text) {
+      Ok(file) if file.version == METADATA_VERSION => Ok(file),
+      Ok(file) => Self::migrate(cache_dir, file_path, file),
+      Err(error) => {
+        let error = error.downcast::<Error>()?;
+        if !matches!(error, Error::Parse(..)) {
+          return Err(error.into());
+        }
+        error.log_warn(Some("Rebuild metadata from the cache directory"));
+        let file = Self::rebuild(cache_dir);
+        Self::write(file_path, &file)?;
+        Ok(file)
       }
     }
   }
 
+  /// Upgrade metadata.json in an older layout.
+  fn migrate(cache_dir: &Path, file_path: &Path, mut file: MetadataFile) -> Result<MetadataFile> {
+    log::info!(
+      "Migrate {} to version {}",
+      file_path.to_string_lossy(),
+      METADATA_VERSION
+    );
+    if file.version < 2 {
+      Self::migrate_cache_keys(cache_dir, &mut file)?;
+    }
+    if file.version < 3 {
+      Self::migrate_dir_keys(cache_dir, &mut file);
+    }
+    file.version = METADATA_VERSION;
+    Self::write(file_path, &file)?;
+    Ok(file)
+  }
+
+  /// Keys before version 2 end with the project directory instead of the fingerprint, so the entry in use by each project is renamed to its content key.
+  /// The other entries are left to be unused.
+  fn migrate_cache_keys(cache_dir: &Path, file: &mut MetadataFile) -> Result<()> {
+    let fingerprint = Fingerprint::current().generate_hash()?;
+    for value in file.contents.values_mut() {
+      let Some(current) = value.current_hash_key.clone() else {
+        continue;
+      };
+      let Some(Entry::InUse(link)) = Entry::find(cache_dir, &current) else {
+        continue;
+      };
+      let Some(mut components) = KeyComponents::parse(&current.0) else {
+        continue;
+      };
+      components.fingerprint = fingerprint.to_string();
+      let key = components.to_key();
+      if Entry::find(cache_dir, &key).is_some() {
+        continue;
+      }
+      let origin = std::fs::read_link(&link)
+        .ok()
+        .and_then(|target| target.parent().map(Path::to_path_buf));
+      if let Err(error) = std::fs::rename(&link, cache_dir.join(&key.0)) {
+        log::warn!("Failed to rename the cache {}: {:?}", current, error);
+        continue;
+      }
+      let meta = value.caches.remove(&current).unwrap_or_default();
+      value.caches.insert(key.clone(), meta);
+      value.current_hash_key = Some(key.clone());
+      file.entries.insert(
+        key,
+        EntryMeta {
+          origin,
+          inputs: None,
+        },
+      );
+    }
+    Ok(())
+  }
+
+  /// Directory keys before version 3 can't be turned back into paths, so projects are rekeyed by paths known from
+  /// the entries in use and the origins of entries. The projects whose paths are unknown keep their legacy keys
+  /// until they are synced next time.
+  /// Projects which shared a key are given its caches, but only the one using the current entry keeps it as current.
+  fn migrate_dir_keys(cache_dir: &Path, file: &mut MetadataFile) {
+    let in_use = Entry::list(cache_dir)
+      .into_iter()
+      .filter_map(|(key, entry)| match entry {
+        Entry::InUse(link) => {
+          let target = std::fs::read_link(link).ok()?;
+          Some((key, target.parent()?.to_path_buf()))
+        }
+        _ => None,
+      })
+      .collect::<HashMap<_, _>>();
+    let paths = in_use
+      .values()
+      .chain(file.entries.values().filter_map(|e| e.origin.as_ref()))
+      .unique()
+      .map(|path| (to_legacy_dir_key(path), path.clone()))
+      .into_group_map();
+    let contents = std::mem::take(&mut file.contents);
+    for (legacy_dir_key, value) in contents {
+      let Some(paths) = paths.get(&legacy_dir_key) else {
+        log::info!("Keep metadata of the unknown project {}", legacy_dir_key);
+        file.contents.insert(legacy_dir_key, value);
+        continue;
+      };
+      for path in paths {
+        let mut value = value.clone();
+        let is_current = value
+          .current_hash_key
+          .as_ref()
+          .and_then(|key| in_use.get(key))
+          .is_some_and(|p| p == path);
+        if paths.len() > 1 && !is_current {
+          value.current_hash_key = None;
+        }
+        file.contents.insert(to_dir_key(path), value);
+      }
+    }
+  }
+
+  /// Recover metadata from entries in the cache directory named as `<lockfile hash>-<project hash>-<fingerprint>`.
+  /// A symlink is the cache in use by the project of node_modules it points to, and the others are revoked ones.
+  /// Branches and commits are lost.
+  fn rebuild(cache_dir: &Path) -> MetadataFile {
+    let mut contents = HashMap::<DirKey, MetadataContents>::new();
+    let mut entries = HashMap::<Hash, EntryMeta>::new();
+    for (key, entry) in Entry::list(cache_dir) {
+      let origin = match entry {
+        Entry::InUse(link) => std::fs::read_link(link)
+          .ok()
+          .and_then(|target| target.parent().map(Path::to_path_buf)),
+        _ => None,
+      };
+      if let Some(origin) = &origin {
+        let value = contents.entry(to_dir_key(origin)).or_default();
+        value.current_hash_key = Some(key.clone());
+        value.caches.insert(key.clone(), CacheMeta::default());
+      }
+      entries.insert(
+        key,
+        EntryMeta {
+          origin,
+          inputs: None,
+        },
+      );
+    }
+    MetadataFile {
+      version: METADATA_VERSION,
+      contents,
+      entries,
+    }
+  }
+
+  /// Return the contents of the project at `base_dir`, which may be still under its key before version 3.
+  pub fn find_contents(&self, base_dir: impl AsRef<Path>) -> Option<&MetadataContents> {
+    let base_dir = base_dir.as_ref();
+    self
+      .contents
+      .get(&to_dir_key(base_dir))
+      .or_else(|| self.contents.get(&to_legacy_dir_key(base_dir)))
+  }
+
+  /// Record `hash` as the cache in use at `base_dir` with the branch and the commit checked out in `repository`.
   pub fn update(
     &self,
-    base_dir: &PathBuf,
+    base_dir: &Path,
     hash: &Hash,
-    branch: String,
-    commit: String,
+    repository: Option<&Repository>,
   ) -> Result<Self> {
-    let dir_key = to_dir_key(base_dir);
-    let contents_value = {
-      let mut caches = self
-        .contents
-        .get(&dir_key)
-        .map(|c| c.caches.clone())
-        .unwrap_or_default();
-      caches.insert(hash.clone(), CacheMeta { branch, commit });
-      MetadataContents {
-        current_hash_key: Some(hash.clone()),
-        caches,
-      }
-    };
-    let mut contents = self.contents.clone();
-    contents.insert(dir_key, contents_value);
-    let json = serde_json::to_string(&contents)
-      .map_err(|error| Error::Parse(vec![self.file_path.clone()], error.to_string()))?;
-    fs::write(&self.file_path, json)?;
+    let head = repository.and_then(Repository::head);
+    self.modify(|contents| {
+      let value = contents_entry(contents, base_dir);
+      value.caches.insert(
+        hash.clone(),
+        CacheMeta {
+          branch: head.as_ref().map(|h| h.branch.clone()).unwrap_or_default(),
+          commit: head.map(|h| h.commit).unwrap_or_default(),
+          last_used: None,
+        },
+      );
+      value.switch_current(hash);
+      value.repository = repository.map(|r| r.common_dir.clone());
+    })
+  }
+
+  /// Mark `hash` as the cache in use at `base_dir`.
+  pub fn set_current(&self, base_dir: &Path, hash: &Hash) -> Result<Self> {
+    self.modify(|contents| {
+      contents_entry(contents, base_dir).switch_current(hash);
+    })
+  }
+
+  /// Record the key generated at `base_dir` with the stats of the files it was generated from.
+  pub fn set_stat_cache(&self, base_dir: &Path, stat_cache: &StatCache) -> Result<Self> {
+    self.modify(|contents| {
+      contents_entry(contents, base_dir).stat_cache = Some(stat_cache.clone());
+    })
+  }
+
+  /// Return when `hash` was last in use by any project.
+  pub fn last_used(&self, hash: &Hash) -> Option<SystemTime> {
+    let last_used = self
+      .contents
+      .values()
+      .filter_map(|c| c.caches.get(hash)?.last_used)
+      .max()?;
+    Some(UNIX_EPOCH + Duration::from_secs(last_used))
+  }
+
+  pub fn origin(&self, hash: &Hash) -> Option<&PathBuf> {
+    self.entries.get(hash)?.origin.as_ref()
+  }
+
+  /// Record that the entry of `hash` now contains absolute paths of `origin`.
+  pub fn set_origin(&self, hash: &Hash, origin: Option<&Path>) -> Result<Self> {
+    self.modify_all(|_, entries| {
+      entries.entry(hash.clone()).or_default().origin = origin.map(Path::to_path_buf);
+    })
+  }
+
+  /// Record what the key of the entry was generated from, to explain later why the key changes.
+  pub fn set_inputs(&self, hash: &Hash, inputs: &KeyInputs) -> Result<Self> {
+    self.modify_all(|_, entries| {
+      entries.entry(hash.clone()).or_default().inputs = Some(inputs.clone());
+    })
+  }
+
+  /// Apply `f` to the latest contents of metadata.json and save them.
+  pub fn modify(&self, f: impl FnOnce(&mut HashMap<DirKey, MetadataContents>)) -> Result<Self> {
+    self.modify_all(|contents, _| f(contents))
+  }
+
+  /// Apply `f` to the latest contents and entries of metadata.json and save them.
+  pub fn modify_all(
+    &self,
+    f: impl FnOnce(&mut HashMap<DirKey, MetadataContents>, &mut HashMap<Hash, EntryMeta>),
+  ) -> Result<Self> {
+    let _lock = Self::lock(&self.file_path)?;
+    // the file may be updated by another project since `self` was loaded
+    let mut file = fs::read_to_string(&self.file_path)
+      .ok()
+      .and_then(|text| Self::parse(&self.file_path, &text).ok())
+      .filter(|file| file.version == METADATA_VERSION)
+      .unwrap_or_else(|| MetadataFile {
+        version: METADATA_VERSION,
+        contents: self.contents.clone(),
+        entries: self.entries.clone(),
+      });
+    f(&mut file.contents, &mut file.entries);
+    Self::write(&self.file_path, &file)?;
     Ok(Self {
-      contents,
+      contents: file.contents,
+      entries: file.entries,
       ..self.clone()
     })
   }
 }
+
+/// Return the contents of the project at `base_dir` to modify, moving them from its key before version 3 if kept
+/// by migration, as the path of the project wasn't known then.
+fn contents_entry<'a>(
+  contents: &'a mut HashMap<DirKey, MetadataContents>,
+  base_dir: &Path,
+) -> &'a mut MetadataContents {
+  let dir_key = to_dir_key(base_dir);
+  if !contents.contains_key(&dir_key) {
+    let legacy_dir_key = to_legacy_dir_key(base_dir);
+    if let Some(value) = contents.remove(&legacy_dir_key) {
+      log::info!("Migrate metadata of {} to {}", legacy_dir_key, dir_key);
+      contents.insert(dir_key.clone(), value);
+    }
+  }
+  contents.entry(dir_key).or_default()
+}
+
+/// Components of a cache key formatted as `<lockfile hash>-<project hash>-<fingerprint>`.
+/// Keys don't depend on where projects are, so that entries are shared among checkouts and machines.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct KeyComponents {
+  pub lockfile_hash: String,
+  pub project_hash: String,
+  pub fingerprint: String,
+}
+
+impl KeyComponents {
+  pub fn parse(key: &str) -> Option<Self> {
+    match key.splitn(3, '-').collect::<Vec<_>>()[..] {
+      [lockfile_hash, project_hash, fingerprint]
+        if !lockfile_hash.is_empty() && !project_hash.is_empty() =>
+      {
+        Some(Self {
+          lockfile_hash: lockfile_hash.to_string(),
+          project_hash: project_hash.to_string(),
+          fingerprint: fingerprint.to_string(),
+        })
+      }
+      _ => None,
+    }
+  }
+
+  pub fn to_key(&self) -> Hash {
+    Hash(format!(
+      "{}-{}-{}",
+      self.lockfile_hash, self.project_hash, self.fingerprint
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{test_each, utils::fs::create_symlink};
+
+  struct NewTestCase {
+    /// contents of metadata.json, or `None` if not exists
+    input: Option<&'static str>,
+    /// directories of cache entries
+    cache_entries: Vec<&'static str>,
+    expected: HashMap<DirKey, MetadataContents>,
+    expected_entries: HashMap<Hash, EntryMeta>,
+  }
+
+  fn test_new_each(case: NewTestCase) {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path();
+    for key in case.cache_entries {
+      fs::create_dir(cache_dir.join(key)).unwrap();
+    }
+    if let Some(input) = case.input {
+      fs::write(cache_dir.join(FILE_NAME), input).unwrap();
+    }
+
+    let metadata = Metadata::new(cache_dir).unwrap();
+    assert_eq!(metadata.contents, case.expected);
+    assert_eq!(metadata.entries, case.expected_entries);
+    // the file is always written in the current layout
+    let written = fs::read_to_string(cache_dir.join(FILE_NAME)).unwrap();
+    let written = serde_json::from_str::<MetadataFile>(&written).unwrap();
+    assert_eq!(written.version, METADATA_VERSION);
+    assert_eq!(written.contents, case.expected);
+    assert_eq!(written.entries, case.expected_entries);
+    tmp_dir.close().unwrap();
+  }
+
+  test_each!(
+    test_new,
+    test_new_each,
+    "not_exists" => NewTestCase {
+      input: None,
+      cache_entries: vec![],
+      expected: HashMap::new(),
+      expected_entries: HashMap::new(),
+    },
+    "current" => NewTestCase {
+      input: Some(r#"{"version":3,"contents":{"a_b":{"current_hash_key":"x-y-z","caches":{}}},"entries":{"x-y-z":{"origin":"/a/b"}}}"#),
+      cache_entries: vec![],
+      expected: HashMap::from([(
+        DirKey(String::from("a_b")),
+        MetadataContents {
+          current_hash_key: Some(Hash(String::from("x-y-z"))),
+          caches: HashMap::new(),
+          repository: None,
+          stat_cache: None,
+        },
+      )]),
+      expected_entries: HashMap::from([(
+        Hash(String::from("x-y-z")),
+        EntryMeta {
+          origin: Some(PathBuf::from("/a/b")),
+          inputs: None,
+        },
+      )]),
+    },
+    "v2" => NewTestCase {
+      input: Some(r#"{"version":2,"contents":{"a_b":{"current_hash_key":"x-y-z","caches":{}},"c":{"current_hash_key":null,"caches":{}}},"entries":{"x-y-z":{"origin":"/a/b"}}}"#),
+      cache_entries: vec![],
+      expected: HashMap::from([
+        (
+          to_dir_key("/a/b"),
+          MetadataContents {
+            current_hash_key: Some(Hash(String::from("x-y-z"))),
+            caches: HashMap::new(),
+            repository: None,
+            stat_cache: None,
+          },
+        ),
+        // the path of the project is unknown without the entry in use or the origin
+        (DirKey(String::from("c")), MetadataContents::default()),
+      ]),
+      expected_entries: HashMap::from([(
+        Hash(String::from("x-y-z")),
+        EntryMeta {
+          origin: Some(PathBuf::from("/a/b")),
+          inputs: None,
+        },
+      )]),
+    },
+    "unversioned" => NewTestCase {
+      input: Some(r#"{"a_b":{"current_hash_key":"x-y-a_b","caches":{"x-y-a_b":{"branch":"main","commit":"abc"}}}}"#),
+      cache_entries: vec![],
+      expected: HashMap::from([(
+        DirKey(String::from("a_b")),
+        MetadataContents {
+          current_hash_key: Some(Hash(String::from("x-y-a_b"))),
+          caches: HashMap::from([(
+            Hash(String::from("x-y-a_b")),
+            CacheMeta {
+              branch: String::from("main"),
+              commit: String::from("abc"),
+              last_used: None,
+            },
+          )]),
+          repository: None,
+          stat_cache: None,
+        },
+      )]),
+      expected_entries: HashMap::new(),
+    },
+    "corrupt" => NewTestCase {
+      input: Some(r#"{"version":3,"conte"#),
+      cache_entries: vec!["x-y-z", "z-w-z", "locks"],
+      expected: HashMap::new(),
+      expected_entries: HashMap::from([
+        (Hash(String::from("x-y-z")), EntryMeta::default()),
+        (Hash(String::from("z-w-z")), EntryMeta::default()),
+      ]),
+    },
+    "empty" => NewTestCase {
+      input: Some(""),
+      cache_entries: vec![],
+      expected: HashMap::new(),
+      expected_entries: HashMap::new(),
+    },
+  );
+
+  #[test]
+  fn test_new_newer_version() {
+    let tmp_dir = TempDir::new().unwrap();
+    let file_path = tmp_dir.path().join(FILE_NAME);
+    let input = r#"{"version":100,"contents":{}}"#;
+    fs::write(&file_path, input).unwrap();
+    assert!(Metadata::new(tmp_dir.path()).is_err());
+    // never overwrite a file written by a newer version
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), input);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_new_under_lock() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().to_path_buf();
+    let file_path = cache_dir.join(FILE_NAME);
+    fs::write(&file_path, r#"{"version":3,"conte"#).unwrap();
+
+    // another process repairs metadata.json while holding the lock
+    let lock = Metadata::lock(&file_path).unwrap();
+    let handle = std::thread::spawn(move || Metadata::new(cache_dir).unwrap());
+    std::thread::sleep(Duration::from_millis(200));
+    let input = r#"{"version":3,"contents":{"a_b":{"current_hash_key":"x-y-z","caches":{}}}}"#;
+    fs::write(&file_path, input).unwrap();
+    drop(lock);
+
+    let metadata = handle.join().unwrap();
+    assert!(metadata.contents.contains_key(&DirKey(String::from("a_b"))));
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), input);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_migrate() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let base_dir = tmp_dir.path().join("project");
+    fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+    let legacy_dir_key = to_legacy_dir_key(&base_dir);
+    let legacy_key = format!("x-y-{}", legacy_dir_key);
+    create_symlink(base_dir.join("node_modules"), cache_dir.join(&legacy_key)).unwrap();
+    fs::create_dir_all(cache_dir.join(format!("z-w-{}", legacy_dir_key))).unwrap();
+    fs::write(
+      cache_dir.join(FILE_NAME),
+      format!(
+        r#"{{"version":1,"contents":{{"{0}":{{"current_hash_key":"{1}","caches":{{"{1}":{{"branch":"main","commit":"abc"}},"z-w-{0}":{{"branch":"main","commit":"def"}}}}}}}}}}"#,
+        legacy_dir_key, legacy_key
+      ),
+    )
+    .unwrap();
+
+    let metadata = Metadata::new(&cache_dir).unwrap();
+    let key = Hash(format!(
+      "x-y-{}",
+      Fingerprint::current().generate_hash().unwrap()
+    ));
+    let contents = &metadata.contents[&to_dir_key(&base_dir)];
+    assert_eq!(contents.current_hash_key, Some(key.clone()));
+    assert_eq!(contents.caches[&key].commit, "abc");
+    // revoked entries are left as they were
+    let revoked = Hash(format!("z-w-{}", legacy_dir_key));
+    assert_eq!(contents.caches[&revoked].commit, "def");
+    assert_eq!(metadata.origin(&key), Some(&base_dir));
+    assert!(cache_dir.join(&key.0).is_symlink());
+    assert!(!cache_dir.join(&legacy_key).exists());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_migrate_dir_keys() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    // projects sharing the legacy key
+    let (a, b) = (tmp_dir.path().join("a_b/c"), tmp_dir.path().join("a/b_c"));
+    assert_eq!(to_legacy_dir_key(&a), to_legacy_dir_key(&b));
+    fs::create_dir_all(b.join("node_modules")).unwrap();
+    create_symlink(b.join("node_modules"), cache_dir.join("x-y-z")).unwrap();
+    fs::write(
+      cache_dir.join(FILE_NAME),
+      format!(
+        r#"{{"version":2,"contents":{{"{}":{{"current_hash_key":"x-y-z","caches":{{"v-w-z":{{"branch":"main","commit":"abc"}}}}}}}},"entries":{{"v-w-z":{{"origin":"{}"}}}}}}"#,
+        to_legacy_dir_key(&a),
+        a.display()
+      ),
+    )
+    .unwrap();
+
+    let metadata = Metadata::new(&cache_dir).unwrap();
+    let (a, b) = (
+      &metadata.contents[&to_dir_key(&a)],
+      &metadata.contents[&to_dir_key(&b)],
+    );
+    assert_eq!(a.current_hash_key, None);
+    assert_eq!(b.current_hash_key, Some(Hash(String::from("x-y-z"))));
+    assert_eq!(a.caches, b.caches);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_update_legacy_dir_key() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let base_dir = tmp_dir.path().join("project");
+    let legacy_dir_key = to_legacy_dir_key(&base_dir);
+    fs::create_dir_all(&cache_dir).unwrap();
+    fs::write(
+      cache_dir.join(FILE_NAME),
+      format!(
+        r#"{{"version":2,"contents":{{"{}":{{"current_hash_key":"x-y-z","caches":{{"x-y-z":{{"branch":"main","commit":"abc"}}}}}}}}}}"#,
+        legacy_dir_key
+      ),
+    )
+    .unwrap();
+
+    // the project unknown at migration is found by its legacy key, and rekeyed when it is synced
+    let metadata = Metadata::new(&cache_dir).unwrap();
+    assert_eq!(
+      metadata.find_contents(&base_dir).unwrap().current_hash_key,
+      Some(Hash(String::from("x-y-z")))
+    );
+    let metadata = metadata
+      .update(&base_dir, &Hash(String::from("v-w-z")), None)
+      .unwrap();
+    assert!(!metadata.contents.contains_key(&legacy_dir_key));
+    let contents = &metadata.contents[&to_dir_key(&base_dir)];
+    assert_eq!(contents.current_hash_key, Some(Hash(String::from("v-w-z"))));
+    assert_eq!(contents.caches[&Hash(String::from("x-y-z"))].commit, "abc");
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_rebuild() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let base_dir = tmp_dir.path().join("project");
+    fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+    create_symlink(base_dir.join("node_modules"), cache_dir.join("x-y-z")).unwrap();
+    fs::create_dir_all(cache_dir.join("z-w-z")).unwrap();
+    fs::write(cache_dir.join(FILE_NAME), "{").unwrap();
+
+    let metadata = Metadata::new(&cache_dir).unwrap();
+    let (in_use, revoked) = (Hash(String::from("x-y-z")), Hash(String::from("z-w-z")));
+    assert_eq!(
+      metadata.contents,
+      HashMap::from([(
+        to_dir_key(&base_dir),
+        MetadataContents {
+          current_hash_key: Some(in_use.clone()),
+          caches: HashMap::from([(in_use.clone(), CacheMeta::default())]),
+          repository: None,
+          stat_cache: None,
+        },
+      )])
+    );
+    assert_eq!(metadata.origin(&in_use), Some(&base_dir));
+    assert_eq!(metadata.entries[&revoked], EntryMeta::default());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_stat_cache() {
+    let tmp_dir = TempDir::new().unwrap();
+    let package_json = tmp_dir.path().join("package.json");
+    let lockfile = tmp_dir.path().join("package-lock.json");
+    let yarn_lock = tmp_dir.path().join("yarn.lock");
+    fs::write(&package_json, r#"{"a":"^1.0.0"}"#).unwrap();
+    fs::write(&lockfile, "{}").unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    let files = vec![package_json.clone(), lockfile.clone()];
+    // Node.js in PATH of the environment is left out
+    let take = || StatCache {
+      node: None,
+      ..StatCache::take(&files, &[lockfile.clone(), yarn_lock.clone()])
+    };
+
+    let stat_cache = take().with_key(Hash(String::from("x-y-z")));
+    assert_eq!(stat_cache.absent, vec![yarn_lock.clone()]);
+    assert!(stat_cache.has_files(&files));
+    assert!(!stat_cache.has_files(&files[..1]));
+    assert!(stat_cache.is_fresh_with(None));
+
+    // the same size
+    fs::write(&package_json, r#"{"a":"^2.0.0"}"#).unwrap();
+    assert!(!stat_cache.is_fresh_with(None));
+
+    std::thread::sleep(Duration::from_millis(10));
+    let stat_cache = take();
+    assert!(stat_cache.is_fresh_with(None));
+    fs::write(&yarn_lock, "").unwrap();
+    assert!(!stat_cache.is_fresh_with(None));
+    fs::remove_file(&yarn_lock).unwrap();
+
+    // Node.js is switched, or may be switched by a shim
+    let node = tmp_dir.path().join("bin/node");
+    fs::create_dir_all(node.parent().unwrap()).unwrap();
+    fs::write(&node, b"\x7fELF").unwrap();
+    let stat_cache = StatCache {
+      node: FileStat::new(&node),
+      ..take()
+    };
+    assert!(stat_cache.is_fresh_with(Some(node.clone())));
+    assert!(!stat_cache.is_fresh_with(None));
+    fs::write(&node, "#!/bin/sh\n").unwrap();
+    let stat_cache = StatCache {
+      node: FileStat::new(&node),
+      ..take()
+    };
+    assert!(!stat_cache.is_fresh_with(Some(node.clone())));
+
+    // modified in the same second on a filesystem without sub-second mtime
+    let at = SystemTime::now();
+    let second = UNIX_EPOCH + Duration::from_secs(at.duration_since(UNIX_EPOCH).unwrap().as_secs());
+    fs::File::options()
+      .write(true)
+      .open(&lockfile)
+      .unwrap()
+      .set_modified(second)
+      .unwrap();
+    assert!(!take().is_fresh_with(None));
+    tmp_dir.close().unwrap();
+  }
+}
@@ -1,4 +1,19 @@
+mod archive;
+mod backend;
+mod bundle;
+mod entry;
 mod lib;
 mod metadata;
+mod relocate;
+mod s3;
+mod store;
+mod strategy;
+mod verify;
 
+pub use archive::compress_unused;
+pub use backend::REMOTE_TOKEN_ENV;
+pub use bundle::{export, import};
 pub use lib::*;
+pub use metadata::StatCache;
+pub use strategy::Strategy;
+pub use verify::{repair, verify, Problem};
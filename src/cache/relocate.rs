@@ -0,0 +1,171 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Result;
+use tempfile::NamedTempFile;
+
+use crate::{errors::to_error, utils::fs as fs_utils};
+
+/// Files larger than this are not scripts or state files of package managers.
+const MAX_REWRITTEN_SIZE: u64 = 1024 * 1024;
+
+/// Rewrite absolute paths of the project at `from` baked into node_modules at `dir` to `to`, and return the number of rewritten files.
+/// Symlinks pointing into `from` such as `file:` dependencies are relinked, and shims in `.bin` and state files at the top of node_modules
+/// (e.g. `.modules.yaml` of pnpm) are rewritten. The other files are left as they are not to read the whole tree.
+pub fn relocate(
+  dir: impl AsRef<Path>,
+  from: impl AsRef<Path>,
+  to: impl AsRef<Path>,
+) -> Result<usize> {
+  let (dir, from, to) = (dir.as_ref(), from.as_ref(), to.as_ref());
+  if from == to {
+    return Ok(0);
+  }
+  relocate_tree(dir, dir, from, to)
+}
+
+fn relocate_tree(root: &Path, path: &Path, from: &Path, to: &Path) -> Result<usize> {
+  let metadata = fs::symlink_metadata(path).map_err(to_error)?;
+  let file_type = metadata.file_type();
+  if file_type.is_symlink() {
+    let target = fs::read_link(path).map_err(to_error)?;
+    let Ok(rest) = target.strip_prefix(from) else {
+      return Ok(0);
+    };
+    fs_utils::remove_symlink(path)?;
+    fs_utils::symlink(to.join(rest), path)?;
+    Ok(1)
+  } else if file_type.is_dir() {
+    let mut count = 0;
+    for entry in fs::read_dir(path).map_err(to_error)? {
+      let entry = entry.map_err(to_error)?;
+      count += relocate_tree(root, &entry.path(), from, to)?;
+    }
+    Ok(count)
+  } else if is_rewritable(root, path) && metadata.len() <= MAX_REWRITTEN_SIZE {
+    let contents = fs::read(path).map_err(to_error)?;
+    let from = from.to_string_lossy();
+    let to = to.to_string_lossy();
+    let Some(contents) = replace_path(&contents, from.as_bytes(), to.as_bytes()) else {
+      return Ok(0);
+    };
+    // replace the file instead of writing in place, since it may be hardlinked to the cache entry
+    let dir = path.parent().unwrap_or(root);
+    let mut tmp_file = NamedTempFile::new_in(dir).map_err(to_error)?;
+    tmp_file.write_all(&contents).map_err(to_error)?;
+    tmp_file
+      .as_file()
+      .set_permissions(metadata.permissions())
+      .map_err(to_error)?;
+    tmp_file.persist(path).map_err(to_error)?;
+    Ok(1)
+  } else {
+    Ok(0)
+  }
+}
+
+/// Shims in any `.bin` and dotfiles at the top of node_modules may contain absolute paths.
+fn is_rewritable(root: &Path, path: &Path) -> bool {
+  let Some(parent) = path.parent() else {
+    return false;
+  };
+  let is_dotfile = path
+    .file_name()
+    .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+  parent.file_name().is_some_and(|name| name == ".bin") || (parent == root && is_dotfile)
+}
+
+/// Replace `from` in `contents` with `to` where it is a whole path, and return `None` if not found.
+fn replace_path(contents: &[u8], from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+  if from.is_empty() {
+    return None;
+  }
+  let is_name_byte = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.');
+  let mut replaced = Vec::with_capacity(contents.len());
+  let mut found = false;
+  let mut i = 0;
+  while i < contents.len() {
+    let is_match = contents[i..].starts_with(from)
+      && !contents
+        .get(i + from.len())
+        .is_some_and(|b| is_name_byte(*b));
+    if is_match {
+      replaced.extend_from_slice(to);
+      i += from.len();
+      found = true;
+    } else {
+      replaced.push(contents[i]);
+      i += 1;
+    }
+  }
+  found.then_some(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_replace_path() {
+    let replace = |contents: &str| {
+      replace_path(contents.as_bytes(), b"/work/app", b"/work/app-2")
+        .map(|c| String::from_utf8(c).unwrap())
+    };
+    assert_eq!(
+      replace("NODE_PATH=\"/work/app/node_modules:/work/app\""),
+      Some(String::from(
+        "NODE_PATH=\"/work/app-2/node_modules:/work/app-2\""
+      ))
+    );
+    // another directory sharing the prefix
+    assert_eq!(replace("/work/apple/node_modules"), None);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_relocate() {
+    let tmp_dir = TempDir::new().unwrap();
+    let from = tmp_dir.path().join("app");
+    let to = tmp_dir.path().join("clone");
+    let dir = to.join("node_modules");
+    fs::create_dir_all(dir.join(".bin")).unwrap();
+    fs::create_dir_all(dir.join("foo")).unwrap();
+    let shim = format!("exec node {}/node_modules/foo/cli.js", from.display());
+    fs::write(dir.join(".bin/foo"), &shim).unwrap();
+    fs::write(
+      dir.join(".modules.yaml"),
+      format!("root: {}", from.display()),
+    )
+    .unwrap();
+    fs::write(dir.join("foo/cli.js"), &shim).unwrap();
+    std::os::unix::fs::symlink(from.join("packages/lib"), dir.join("lib")).unwrap();
+    std::os::unix::fs::symlink("foo/cli.js", dir.join("cli")).unwrap();
+    // a hardlink shared with the cache entry
+    let linked = tmp_dir.path().join("linked");
+    fs::hard_link(dir.join(".bin/foo"), &linked).unwrap();
+
+    assert_eq!(relocate(&dir, &from, &to).unwrap(), 3);
+    assert_eq!(
+      fs::read_to_string(dir.join(".bin/foo")).unwrap(),
+      format!("exec node {}/node_modules/foo/cli.js", to.display())
+    );
+    assert_eq!(
+      fs::read_to_string(dir.join(".modules.yaml")).unwrap(),
+      format!("root: {}", to.display())
+    );
+    assert_eq!(fs::read_to_string(dir.join("foo/cli.js")).unwrap(), shim);
+    assert_eq!(
+      fs::read_link(dir.join("lib")).unwrap(),
+      to.join("packages/lib")
+    );
+    assert_eq!(
+      fs::read_link(dir.join("cli")).unwrap(),
+      Path::new("foo/cli.js")
+    );
+    assert_eq!(fs::read_to_string(linked).unwrap(), shim);
+    // no temporary file is left besides the shim
+    assert_eq!(fs::read_dir(dir.join(".bin")).unwrap().count(), 1);
+    tmp_dir.close().unwrap();
+  }
+}
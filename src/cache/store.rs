@@ -0,0 +1,389 @@
+use std::{
+  collections::{BTreeMap, HashSet},
+  fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{
+  cache::strategy::Strategy,
+  errors::{to_error, Error},
+  utils::{
+    fs as fs_utils,
+    hash::{hash_file, Hash},
+  },
+};
+
+const STORE_DIR: &str = "store";
+const BLOBS_DIR: &str = "blobs";
+const MANIFESTS_DIR: &str = "manifests";
+
+/// A file or a directory in a cache entry. Paths of nodes are relative to node_modules and separated by `/`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Node {
+  Dir { mode: u32 },
+  File { blob: String, mode: u32 },
+  Symlink { target: PathBuf },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct Manifest {
+  pub nodes: BTreeMap<String, Node>,
+}
+
+/// A content-addressed store of files shared among cache entries, so that the same file in packages is kept once.
+/// Entries are manifests mapping paths to blobs. Blobs are read-only copies, and files are cloned or copied from them on restore,
+/// since a file modified in place in node_modules would change every entry sharing its blob if linked.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Store {
+  dir: PathBuf,
+}
+
+impl Store {
+  pub fn new(cache_dir: impl AsRef<Path>) -> Self {
+    Self {
+      dir: cache_dir.as_ref().join(STORE_DIR),
+    }
+  }
+
+  pub fn manifests_dir(&self) -> PathBuf {
+    self.dir.join(MANIFESTS_DIR)
+  }
+
+  pub fn to_manifest_path(&self, key: &Hash) -> PathBuf {
+    self.manifests_dir().join(format!("{}.json", key))
+  }
+
+  /// Blobs of executables are separated because hardlinks share permissions.
+  pub fn to_blob_path(&self, blob: &str) -> PathBuf {
+    self.dir.join(BLOBS_DIR).join(&blob[..2]).join(blob)
+  }
+
+  pub fn read_manifest(&self, key: &Hash) -> Result<Manifest> {
+    let path = self.to_manifest_path(key);
+    let text = fs_utils::read_to_string(&path)?;
+    serde_json::from_str(&text).map_err(|error| Error::Parse(vec![path], error.to_string()).into())
+  }
+
+  /// Add files in `dir` to the store as the entry of `key`. `dir` is left as is.
+  pub fn ingest(&self, key: &Hash, dir: impl AsRef<Path>) -> Result<Manifest> {
+    let dir = dir.as_ref();
+    let mut manifest = Manifest::default();
+    self.ingest_tree(dir, dir, &mut manifest)?;
+    let path = self.to_manifest_path(key);
+    fs_utils::make_dir_if_not_exists(self.manifests_dir())?;
+    let contents = serde_json::to_string(&manifest).map_err(to_error)?;
+    fs_utils::write_atomic(path, contents)?;
+    Ok(manifest)
+  }
+
+  fn ingest_tree(&self, root: &Path, path: &Path, manifest: &mut Manifest) -> Result<()> {
+    let metadata = fs::symlink_metadata(path).map_err(to_error)?;
+    let name = path
+      .strip_prefix(root)
+      .map_err(to_error)?
+      .components()
+      .map(|c| c.as_os_str().to_string_lossy())
+      .collect::<Vec<_>>()
+      .join("/");
+    let node = if metadata.is_symlink() {
+      Node::Symlink {
+        target: fs::read_link(path).map_err(to_error)?,
+      }
+    } else if metadata.is_dir() {
+      for entry in fs::read_dir(path).map_err(to_error)? {
+        let entry = entry.map_err(to_error)?;
+        self.ingest_tree(root, &entry.path(), manifest)?;
+      }
+      Node::Dir {
+        mode: to_mode(&metadata),
+      }
+    } else {
+      let mode = to_mode(&metadata);
+      let blob = match mode & 0o111 {
+        0 => hash_file(path)?.to_string(),
+        _ => format!("{}-x", hash_file(path)?),
+      };
+      let blob_path = self.to_blob_path(&blob);
+      if !blob_path.exists() {
+        let blob_dir = blob_path.parent().unwrap_or(&self.dir);
+        fs_utils::make_dir_if_not_exists(blob_dir)?;
+        // place a blob under a temporary name unique among threads not to expose a half-written one
+        let tmp_path = NamedTempFile::new_in(blob_dir)
+          .map_err(to_error)?
+          .into_temp_path();
+        fs::remove_file(&tmp_path).map_err(to_error)?;
+        Strategy::Reflink.place_file(path, &tmp_path)?;
+        set_mode(&tmp_path, mode & 0o555)?;
+        tmp_path.persist(&blob_path).map_err(to_error)?;
+      }
+      Node::File { blob, mode }
+    };
+    if !name.is_empty() {
+      manifest.nodes.insert(name, node);
+    }
+    Ok(())
+  }
+
+  /// Put files of the entry of `key` at `to`, replacing `to`.
+  /// Files are linked to blobs only by `Strategy::Hardlink`, which leaves them read-only.
+  pub fn materialize(&self, key: &Hash, to: impl AsRef<Path>, strategy: Strategy) -> Result<()> {
+    let to = to.as_ref();
+    let manifest = self.read_manifest(key)?;
+    let strategy = match strategy.resolve(&self.dir, to) {
+      Strategy::Auto | Strategy::Rename => Strategy::Reflink,
+      strategy => strategy,
+    };
+    fs::remove_dir_all(to).unwrap_or_default();
+    fs::create_dir_all(to).map_err(to_error)?;
+    // parents are sorted before their children
+    for (name, node) in manifest.nodes.iter() {
+      let path = to.join(name);
+      match node {
+        Node::Dir { .. } => fs::create_dir_all(&path).map_err(to_error)?,
+        Node::Symlink { target } => fs_utils::symlink(target, &path)?,
+        Node::File { blob, mode } => {
+          strategy.place_file(self.to_blob_path(blob), &path)?;
+          if strategy != Strategy::Hardlink {
+            set_mode(&path, *mode)?;
+          }
+        }
+      }
+    }
+    // set permissions of directories after their children are created
+    for (name, node) in manifest.nodes.iter().rev() {
+      if let Node::Dir { mode } = node {
+        set_mode(to.join(name), *mode)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Names of every blob in the store.
+  pub fn list_blobs(&self) -> Vec<String> {
+    let read_dir = |dir: &Path| {
+      fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect::<Vec<_>>()
+    };
+    read_dir(&self.dir.join(BLOBS_DIR))
+      .iter()
+      .flat_map(|dir| read_dir(dir))
+      .filter_map(|path| path.file_name().map(|n| n.to_string_lossy().to_string()))
+      .sorted()
+      .collect()
+  }
+
+  /// Find a blob referred by `manifest` which is missing or modified since ingested.
+  /// `intact` keeps the blobs checked already, so that a blob shared among entries is hashed once.
+  pub fn find_corrupt_blob(
+    &self,
+    manifest: &Manifest,
+    intact: &mut HashSet<String>,
+  ) -> Option<String> {
+    for node in manifest.nodes.values() {
+      let Node::File { blob, .. } = node else {
+        continue;
+      };
+      if intact.contains(blob) {
+        continue;
+      }
+      let hash = blob.strip_suffix("-x").unwrap_or(blob);
+      match hash_file(self.to_blob_path(blob)) {
+        Ok(actual) if actual.0 == hash => {
+          intact.insert(blob.clone());
+        }
+        _ => return Some(blob.clone()),
+      }
+    }
+    None
+  }
+
+  /// Names of blobs which no manifest refers to, left by entries removed or ingesting interrupted.
+  pub fn find_unreferenced_blobs(&self) -> Result<Vec<String>> {
+    let mut referred = HashSet::<String>::new();
+    for entry in fs::read_dir(self.manifests_dir()).into_iter().flatten() {
+      let path = entry.map_err(to_error)?.path();
+      let Some(key) = path
+        .file_name()
+        .and_then(|n| n.to_string_lossy().strip_suffix(".json").map(String::from))
+      else {
+        continue;
+      };
+      let manifest = self.read_manifest(&Hash(key))?;
+      referred.extend(manifest.nodes.into_values().filter_map(|node| match node {
+        Node::File { blob, .. } => Some(blob),
+        _ => None,
+      }));
+    }
+    Ok(
+      self
+        .list_blobs()
+        .into_iter()
+        .filter(|blob| !referred.contains(blob))
+        .collect(),
+    )
+  }
+
+  pub fn remove_blob(&self, blob: &str) -> Result<()> {
+    fs::remove_file(self.to_blob_path(blob)).map_err(to_error)
+  }
+
+  pub fn remove_manifest(&self, key: &Hash) -> Result<()> {
+    fs::remove_file(self.to_manifest_path(key)).map_err(to_error)
+  }
+}
+
+#[cfg(unix)]
+fn to_mode(metadata: &fs::Metadata) -> u32 {
+  use std::os::unix::fs::PermissionsExt;
+  metadata.permissions().mode() & 0o777
+}
+
+#[cfg(windows)]
+fn to_mode(metadata: &fs::Metadata) -> u32 {
+  match metadata.permissions().readonly() {
+    true => 0o444,
+    false => 0o644,
+  }
+}
+
+#[cfg(unix)]
+fn set_mode(path: impl AsRef<Path>, mode: u32) -> Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(to_error)
+}
+
+#[cfg(windows)]
+fn set_mode(path: impl AsRef<Path>, mode: u32) -> Result<()> {
+  let mut permissions = fs::metadata(&path).map_err(to_error)?.permissions();
+  permissions.set_readonly(mode & 0o200 == 0);
+  fs::set_permissions(path, permissions).map_err(to_error)
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_ingest_and_materialize() {
+    let tmp_dir = TempDir::new().unwrap();
+    let store = Store::new(tmp_dir.path().join("cache"));
+    let node_modules = tmp_dir.path().join("node_modules");
+    fs::create_dir_all(node_modules.join("a")).unwrap();
+    fs::create_dir_all(node_modules.join("b")).unwrap();
+    fs::create_dir_all(node_modules.join(".bin")).unwrap();
+    fs::write(node_modules.join("a/index.js"), "module.exports = 1").unwrap();
+    fs::write(node_modules.join("b/index.js"), "module.exports = 1").unwrap();
+    #[cfg(unix)]
+    {
+      fs::write(node_modules.join("a/cli.js"), "module.exports = 1").unwrap();
+      set_mode(node_modules.join("a/cli.js"), 0o755).unwrap();
+      fs_utils::symlink("../a/cli.js", node_modules.join(".bin/a")).unwrap();
+    }
+
+    let key1 = Hash(String::from("a-b-c"));
+    let manifest = store.ingest(&key1, &node_modules).unwrap();
+    let blob = |name: &str| match &manifest.nodes[name] {
+      Node::File { blob, .. } => blob.clone(),
+      node => panic!("{:?}", node),
+    };
+    // the same contents are stored once
+    assert_eq!(blob("a/index.js"), blob("b/index.js"));
+    assert!(node_modules.join("a/index.js").is_file());
+
+    let key2 = Hash(String::from("d-e-c"));
+    // a file is replaced by a package manager rather than modified in place
+    fs::remove_file(node_modules.join("b/index.js")).unwrap();
+    fs::write(node_modules.join("b/index.js"), "module.exports = 2").unwrap();
+    let manifest2 = store.ingest(&key2, &node_modules).unwrap();
+    assert_ne!(manifest.nodes["b/index.js"], manifest2.nodes["b/index.js"]);
+    assert_eq!(manifest.nodes["a/index.js"], manifest2.nodes["a/index.js"]);
+
+    let restored = tmp_dir.path().join("project/node_modules");
+    fs::create_dir_all(restored.join("stale")).unwrap();
+    store.materialize(&key1, &restored, Strategy::Auto).unwrap();
+    assert!(!restored.join("stale").exists());
+    assert_eq!(
+      fs::read_to_string(restored.join("b/index.js")).unwrap(),
+      "module.exports = 1"
+    );
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::{MetadataExt, PermissionsExt};
+      let cli = fs::metadata(restored.join("a/cli.js")).unwrap();
+      assert_eq!(cli.permissions().mode() & 0o777, 0o755);
+      assert_eq!(
+        fs::read_link(restored.join(".bin/a")).unwrap(),
+        Path::new("../a/cli.js")
+      );
+      // blobs are read-only, and restored files are not linked to them
+      let blob = fs::metadata(store.to_blob_path(&blob("a/index.js"))).unwrap();
+      assert_eq!(blob.permissions().mode() & 0o777, 0o444);
+      let index = fs::metadata(restored.join("a/index.js")).unwrap();
+      assert_eq!(index.nlink(), 1);
+      let original = fs::metadata(node_modules.join("a/index.js")).unwrap();
+      assert_eq!(index.mode() & 0o777, original.mode() & 0o777);
+    }
+    // a file modified in place never changes the blob
+    fs::write(restored.join("a/index.js"), "module.exports = 3").unwrap();
+    let mut intact = HashSet::<String>::new();
+    assert_eq!(store.find_corrupt_blob(&manifest, &mut intact), None);
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::MetadataExt;
+      let linked = tmp_dir.path().join("linked/node_modules");
+      store
+        .materialize(&key1, &linked, Strategy::Hardlink)
+        .unwrap();
+      assert!(fs::metadata(linked.join("a/index.js")).unwrap().nlink() > 1);
+    }
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_ingest_in_parallel() {
+    let tmp_dir = TempDir::new().unwrap();
+    let store = Store::new(tmp_dir.path().join("cache"));
+    let node_modules = tmp_dir.path().join("node_modules");
+    for i in 0..32 {
+      fs::create_dir_all(node_modules.join(i.to_string())).unwrap();
+      fs::write(
+        node_modules.join(format!("{i}/index.js")),
+        "module.exports = 1",
+      )
+      .unwrap();
+    }
+    // threads of `run --recursive` ingest the same files at once
+    std::thread::scope(|scope| {
+      for key in ["a-b-c", "d-e-f", "g-h-i", "j-k-l"] {
+        let (store, node_modules) = (&store, &node_modules);
+        scope.spawn(move || store.ingest(&Hash(key.to_string()), node_modules).unwrap());
+      }
+    });
+    assert_eq!(store.list_blobs().len(), 1);
+    assert_eq!(
+      store.find_unreferenced_blobs().unwrap(),
+      Vec::<String>::new()
+    );
+
+    store.remove_manifest(&Hash(String::from("a-b-c"))).unwrap();
+    assert!(store.find_unreferenced_blobs().unwrap().is_empty());
+    for key in ["d-e-f", "g-h-i", "j-k-l"] {
+      store.remove_manifest(&Hash(key.to_string())).unwrap();
+    }
+    assert_eq!(store.find_unreferenced_blobs().unwrap(), store.list_blobs());
+    tmp_dir.close().unwrap();
+  }
+}
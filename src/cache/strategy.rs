@@ -0,0 +1,263 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use strum_macros::{Display, EnumString, EnumVariantNames};
+use tempfile::NamedTempFile;
+
+use crate::{errors::to_error, utils::fs as fs_utils};
+
+/// How a cache entry is materialised as node_modules.
+#[derive(Display, EnumString, EnumVariantNames, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[strum(serialize_all = "lowercase")]
+pub enum Strategy {
+  /// rename on the same filesystem, otherwise copy, or the cheapest of reflink, hardlink and copy to duplicate an entry in use
+  #[default]
+  Auto,
+  /// move the entry, so that it is in use by one project at a time
+  Rename,
+  /// clone files sharing their blocks on btrfs, xfs or apfs, otherwise copy
+  Reflink,
+  /// hardlink files, which must not be modified in place in node_modules
+  Hardlink,
+  /// copy files
+  Copy,
+}
+
+impl Strategy {
+  /// Decide the concrete strategy to put `from` at `to`, falling back to copy across filesystems.
+  pub fn resolve(self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Self {
+    let is_same_device = fs_utils::is_same_device(&from, &to);
+    match self {
+      Strategy::Auto if is_same_device => Strategy::Rename,
+      Strategy::Auto => Strategy::Copy,
+      Strategy::Rename | Strategy::Reflink | Strategy::Hardlink if !is_same_device => {
+        log::info!(
+          "Copy {} instead of {} across filesystems",
+          from.as_ref().to_string_lossy(),
+          self
+        );
+        Strategy::Copy
+      }
+      strategy => strategy,
+    }
+  }
+
+  /// Decide the concrete strategy to put a duplicate of `from` at `to`, keeping `from` as it is in use.
+  /// `Auto` takes the cheapest method supported: reflink, then hardlink, then copy across filesystems.
+  pub fn resolve_duplicate(self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Self {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    match self {
+      Strategy::Auto if !fs_utils::is_same_device(from, to) => Strategy::Copy,
+      Strategy::Auto if supports_reflink(from, to) => Strategy::Reflink,
+      Strategy::Auto => Strategy::Hardlink,
+      Strategy::Rename => Strategy::Reflink.resolve(from, to),
+      strategy => strategy.resolve(from, to),
+    }
+  }
+
+  /// Put the tree of `from` at `to`, replacing `to`. `from` is left as is unless renamed.
+  pub fn materialize(self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<Self> {
+    let strategy = self.resolve(&from, &to);
+    match strategy {
+      Strategy::Rename => fs_utils::rename(from, to)?,
+      _ => {
+        fs::remove_dir_all(&to).unwrap_or_default();
+        copy_tree(from.as_ref(), to.as_ref(), strategy)?
+      }
+    };
+    Ok(strategy)
+  }
+
+  /// Put a file of `from` at `to` which doesn't exist, copying unless linked or cloned.
+  pub fn place_file(self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    match self {
+      Strategy::Hardlink => fs::hard_link(from, to).map_err(to_error),
+      Strategy::Reflink => {
+        let permissions = fs::metadata(&from).map_err(to_error)?.permissions();
+        reflink_copy::reflink_or_copy(from, &to).map_err(to_error)?;
+        fs::set_permissions(to, permissions).map_err(to_error)
+      }
+      _ => fs::copy(from, to).map(|_| ()).map_err(to_error),
+    }
+  }
+}
+
+/// Move the tree of `from` to `to`, copying and removing it across filesystems.
+pub fn move_tree(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+  if Strategy::Rename.materialize(&from, &to)? != Strategy::Rename {
+    fs::remove_dir_all(from).map_err(to_error)?;
+  }
+  Ok(())
+}
+
+/// Probe whether files on the filesystem of `from` can be cloned to `to` by cloning a temporary file.
+fn supports_reflink(from: &Path, to: &Path) -> bool {
+  let nearest_dir = |path: &Path| path.ancestors().find(|p| p.is_dir()).map(Path::to_path_buf);
+  let (Some(from_dir), Some(to_dir)) = (nearest_dir(from), nearest_dir(to)) else {
+    return false;
+  };
+  let Ok(source) = NamedTempFile::new_in(from_dir) else {
+    return false;
+  };
+  let Ok(target) = NamedTempFile::new_in(to_dir).map(NamedTempFile::into_temp_path) else {
+    return false;
+  };
+  // a clone is created at a path not existing
+  fs::remove_file(&target).unwrap_or_default();
+  reflink_copy::reflink(source.path(), &target).is_ok()
+}
+
+fn copy_tree(from: &Path, to: &Path, strategy: Strategy) -> Result<()> {
+  let metadata = fs::symlink_metadata(from).map_err(to_error)?;
+  let file_type = metadata.file_type();
+  if file_type.is_symlink() {
+    // keep relative links such as `.bin` shims as they are
+    let original = fs::read_link(from).map_err(to_error)?;
+    fs_utils::symlink(&original, to)?;
+  } else if file_type.is_dir() {
+    fs::create_dir_all(to).map_err(to_error)?;
+    for entry in fs::read_dir(from).map_err(to_error)? {
+      let entry = entry.map_err(to_error)?;
+      copy_tree(&entry.path(), &to.join(entry.file_name()), strategy)?;
+    }
+    fs::set_permissions(to, metadata.permissions()).map_err(to_error)?;
+  } else {
+    strategy.place_file(from, to)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use tempfile::TempDir;
+
+  use super::*;
+
+  fn create_tree(dir: &Path) {
+    fs::create_dir_all(dir.join("foo/bin")).unwrap();
+    fs::create_dir_all(dir.join(".bin")).unwrap();
+    fs::write(dir.join("foo/bin/cli.js"), "console.log(1)").unwrap();
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let cli = dir.join("foo/bin/cli.js");
+      fs::set_permissions(&cli, fs::Permissions::from_mode(0o755)).unwrap();
+      std::os::unix::fs::symlink("../foo/bin/cli.js", dir.join(".bin/foo")).unwrap();
+    }
+  }
+
+  fn assert_tree(dir: &Path) {
+    assert_eq!(
+      fs::read_to_string(dir.join("foo/bin/cli.js")).unwrap(),
+      "console.log(1)"
+    );
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(dir.join("foo/bin/cli.js"))
+        .unwrap()
+        .permissions()
+        .mode();
+      assert_eq!(mode & 0o777, 0o755);
+      assert_eq!(
+        fs::read_link(dir.join(".bin/foo")).unwrap(),
+        Path::new("../foo/bin/cli.js")
+      );
+      assert_eq!(
+        fs::read_to_string(dir.join(".bin/foo")).unwrap(),
+        "console.log(1)"
+      );
+    }
+  }
+
+  #[test]
+  fn test_from_str() {
+    assert_eq!(Strategy::from_str("hardlink").unwrap(), Strategy::Hardlink);
+    assert!(Strategy::from_str("symlink").is_err());
+  }
+
+  #[test]
+  fn test_resolve() {
+    let tmp_dir = TempDir::new().unwrap();
+    let from = tmp_dir.path().join("from");
+    let to = tmp_dir.path().join("to");
+    assert_eq!(Strategy::Auto.resolve(&from, &to), Strategy::Rename);
+    assert_eq!(Strategy::Hardlink.resolve(&from, &to), Strategy::Hardlink);
+    assert_eq!(Strategy::Copy.resolve(&from, &to), Strategy::Copy);
+  }
+
+  #[test]
+  fn test_resolve_duplicate() {
+    let tmp_dir = TempDir::new().unwrap();
+    let from = tmp_dir.path().join("from");
+    let to = tmp_dir.path().join("to");
+    let expected = match supports_reflink(&from, &to) {
+      true => Strategy::Reflink,
+      false => Strategy::Hardlink,
+    };
+    assert_eq!(Strategy::Auto.resolve_duplicate(&from, &to), expected);
+    assert_eq!(
+      Strategy::Rename.resolve_duplicate(&from, &to),
+      Strategy::Reflink
+    );
+    assert_eq!(Strategy::Copy.resolve_duplicate(&from, &to), Strategy::Copy);
+    // no temporary file is left by probing
+    assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+
+    #[cfg(target_os = "linux")]
+    if let Ok(other_dir) = TempDir::new_in("/dev/shm") {
+      if !fs_utils::is_same_device(tmp_dir.path(), other_dir.path()) {
+        let to = other_dir.path().join("to");
+        assert_eq!(Strategy::Auto.resolve_duplicate(&from, &to), Strategy::Copy);
+        assert_eq!(
+          Strategy::Hardlink.resolve_duplicate(&from, &to),
+          Strategy::Copy
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_materialize() {
+    for strategy in [
+      Strategy::Rename,
+      Strategy::Reflink,
+      Strategy::Hardlink,
+      Strategy::Copy,
+    ] {
+      let tmp_dir = TempDir::new().unwrap();
+      let from = tmp_dir.path().join("from");
+      let to = tmp_dir.path().join("to");
+      create_tree(&from);
+      fs::create_dir_all(to.join("stale")).unwrap();
+
+      assert_eq!(strategy.materialize(&from, &to).unwrap(), strategy);
+      assert_tree(&to);
+      assert!(!to.join("stale").exists());
+      if strategy == Strategy::Rename {
+        assert!(!from.exists());
+      } else {
+        assert_tree(&from);
+      }
+      #[cfg(unix)]
+      if strategy == Strategy::Hardlink {
+        use std::os::unix::fs::MetadataExt;
+        let ino = |dir: &Path| fs::metadata(dir.join("foo/bin/cli.js")).unwrap().ino();
+        assert_eq!(ino(&from), ino(&to));
+      }
+    }
+  }
+
+  #[test]
+  fn test_move_tree() {
+    let tmp_dir = TempDir::new().unwrap();
+    let from = tmp_dir.path().join("from");
+    let to = tmp_dir.path().join("cache/to");
+    create_tree(&from);
+    move_tree(&from, &to).unwrap();
+    assert!(!from.exists());
+    assert_tree(&to);
+  }
+}
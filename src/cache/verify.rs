@@ -0,0 +1,446 @@
+use std::{
+  collections::HashSet,
+  fmt::Display,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+use strum_macros::IntoStaticStr;
+
+use crate::{
+  cache::{
+    entry::Entry,
+    lib::resolve_cache_dir,
+    metadata::{EntryMeta, Metadata},
+    store::Store,
+  },
+  core::generate_cache_key,
+  errors::to_error,
+  project::{Fingerprint, Lockfile, ProjectRoot},
+  utils::{
+    fs as fs_utils,
+    hash::Hash,
+    path::{to_dir_key, DirKey},
+  },
+};
+
+#[derive(Debug, PartialEq, Clone, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Problem {
+  /// metadata refers to a cache which doesn't exist in the cache directory
+  MissingCache(DirKey, Hash),
+  /// a cache in the cache directory isn't referred by metadata
+  UnreferencedCache(Hash),
+  /// a symlink saved as the current cache points to node_modules which doesn't exist
+  DanglingSymlink(DirKey, Hash, PathBuf),
+  /// the current cache key doesn't match the one generated from the project now
+  StaleCurrentCache(DirKey, Hash, Hash, PathBuf),
+  /// a cache in the store refers to a blob which is missing or modified, or its manifest is unreadable if `None`
+  CorruptCache(Hash, Option<String>),
+  /// a blob in the store isn't referred by any cache
+  UnreferencedBlob(Hash),
+}
+
+impl Display for Problem {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Problem::MissingCache(_, key) => write!(f, "Missing cache: {key}"),
+      Problem::UnreferencedCache(key) => write!(f, "Unreferenced cache: {key}"),
+      Problem::DanglingSymlink(_, key, target) => {
+        write!(f, "Dangling symlink: {key} -> {}", target.to_string_lossy())
+      }
+      Problem::StaleCurrentCache(_, current, actual, base_dir) => write!(
+        f,
+        "Stale current cache: {current} (expected {actual}), run again in {}",
+        base_dir.to_string_lossy()
+      ),
+      Problem::CorruptCache(key, Some(blob)) => {
+        write!(f, "Corrupt cache: {key} (missing or modified file {blob})")
+      }
+      Problem::CorruptCache(key, None) => write!(f, "Corrupt cache: {key} (unreadable manifest)"),
+      Problem::UnreferencedBlob(blob) => write!(f, "Unreferenced file in the store: {blob}"),
+    }
+  }
+}
+
+impl Problem {
+  /// The kind of the problem in snake case, such as `missing_cache`.
+  pub fn kind(&self) -> &'static str {
+    self.into()
+  }
+
+  /// The key of the cache which has the problem.
+  pub fn key(&self) -> &Hash {
+    match self {
+      Problem::MissingCache(_, key)
+      | Problem::UnreferencedCache(key)
+      | Problem::DanglingSymlink(_, key, _)
+      | Problem::StaleCurrentCache(_, key, _, _)
+      | Problem::CorruptCache(key, _)
+      | Problem::UnreferencedBlob(key) => key,
+    }
+  }
+
+  /// A stale current cache is fixed only by syncing the project.
+  pub fn is_fixable(&self) -> bool {
+    !matches!(self, Problem::StaleCurrentCache(..))
+  }
+}
+
+/// Detect inconsistencies between metadata and the entries in the cache directory, and in the store of the entries.
+/// The cache directory must be locked by `lock_cache_dir`, since entries being saved look inconsistent.
+pub fn verify(cache_dir: Option<impl AsRef<Path>>) -> Result<Vec<Problem>> {
+  let cache_dir = resolve_cache_dir(cache_dir)?;
+  let metadata = Metadata::new(&cache_dir)?;
+  let mut problems = Vec::<Problem>::new();
+
+  let referred = metadata
+    .contents
+    .iter()
+    .flat_map(|(dir_key, c)| {
+      c.caches
+        .keys()
+        .chain(c.current_hash_key.iter())
+        .map(|key| (dir_key.clone(), key.clone()))
+    })
+    .unique()
+    .sorted_by(|a, b| a.1 .0.cmp(&b.1 .0))
+    .collect_vec();
+  for (dir_key, key) in referred.iter() {
+    if Entry::find(&cache_dir, key).is_none() {
+      problems.push(Problem::MissingCache(dir_key.clone(), key.clone()));
+    }
+  }
+
+  let store = Store::new(&cache_dir);
+  let mut intact = HashSet::<String>::new();
+  for (key, entry) in Entry::list(&cache_dir) {
+    if let Entry::Manifest(_) = &entry {
+      let corrupt = match store.read_manifest(&key) {
+        Ok(manifest) => store.find_corrupt_blob(&manifest, &mut intact).map(Some),
+        Err(_) => Some(None),
+      };
+      if let Some(blob) = corrupt {
+        problems.push(Problem::CorruptCache(key.clone(), blob));
+      }
+    }
+    let target = match &entry {
+      Entry::InUse(path) => Some(fs::read_link(path).map_err(to_error)?),
+      _ => None,
+    };
+    // the project using the entry is the one of node_modules which the symlink points to
+    let owner = target.as_ref().and_then(|t| t.parent()).map(to_dir_key);
+    if let (Some(target), Some(owner)) = (target.as_ref().filter(|t| !t.is_dir()), &owner) {
+      problems.push(Problem::DanglingSymlink(owner.clone(), key, target.clone()));
+      continue;
+    }
+    if !referred.iter().any(|(_, k)| *k == key) && !metadata.entries.contains_key(&key) {
+      problems.push(Problem::UnreferencedCache(key.clone()));
+    }
+    let (Some(target), Some(owner)) = (target, owner) else {
+      continue;
+    };
+    let is_current = metadata
+      .contents
+      .get(&owner)
+      .and_then(|c| c.current_hash_key.as_ref())
+      == Some(&key);
+    if let (true, Some(base_dir)) = (is_current, target.parent()) {
+      if let Some(actual) = generate_current_cache_key(base_dir) {
+        if actual != key {
+          problems.push(Problem::StaleCurrentCache(
+            owner,
+            key,
+            actual,
+            base_dir.to_path_buf(),
+          ));
+        }
+      }
+    }
+  }
+
+  // blobs can't be told unreferenced while any manifest is unreadable
+  if let Ok(blobs) = store.find_unreferenced_blobs() {
+    problems.extend(
+      blobs
+        .into_iter()
+        .map(|blob| Problem::UnreferencedBlob(Hash(blob))),
+    );
+  }
+  Ok(problems)
+}
+
+/// Fix `problems` found by `verify`, and return the ones fixed.
+/// The lock of the cache directory must be held since `verify`, so that no entry has been saved meanwhile.
+pub fn repair(cache_dir: Option<impl AsRef<Path>>, problems: &[Problem]) -> Result<Vec<Problem>> {
+  let cache_dir = resolve_cache_dir(cache_dir)?;
+  let problems = problems
+    .iter()
+    .filter(|p| p.is_fixable())
+    .cloned()
+    .collect_vec();
+  let store = Store::new(&cache_dir);
+  for problem in problems.iter() {
+    match problem {
+      Problem::DanglingSymlink(_, key, _) => {
+        fs_utils::remove_symlink(cache_dir.join(key.to_string()))?
+      }
+      Problem::CorruptCache(key, blob) => {
+        store.remove_manifest(key)?;
+        // the blob may be removed for another cache already
+        if let Some(blob) = blob {
+          store.remove_blob(blob).unwrap_or_default();
+        }
+      }
+      Problem::UnreferencedBlob(blob) => store.remove_blob(&blob.0)?,
+      _ => {}
+    }
+  }
+  Metadata::new(&cache_dir)?.modify_all(|contents, entries| {
+    for problem in problems.iter() {
+      match problem {
+        Problem::MissingCache(dir_key, key) | Problem::DanglingSymlink(dir_key, key, _) => {
+          if let Some(c) = contents.get_mut(dir_key) {
+            c.caches.remove(key);
+            if c.current_hash_key.as_ref() == Some(key) {
+              c.current_hash_key = None;
+            }
+            if c.caches.is_empty() && c.current_hash_key.is_none() {
+              contents.remove(dir_key);
+            }
+          }
+          if Entry::find(&cache_dir, key).is_none() {
+            entries.remove(key);
+          }
+        }
+        Problem::UnreferencedCache(key) => {
+          let origin = fs::read_link(cache_dir.join(&key.0))
+            .ok()
+            .and_then(|target| target.parent().map(Path::to_path_buf));
+          entries.insert(
+            key.clone(),
+            EntryMeta {
+              origin,
+              inputs: None,
+            },
+          );
+        }
+        Problem::CorruptCache(key, _) => {
+          contents.retain(|_, c| {
+            c.caches.remove(key);
+            if c.current_hash_key.as_ref() == Some(key) {
+              c.current_hash_key = None;
+            }
+            !c.caches.is_empty() || c.current_hash_key.is_some()
+          });
+          entries.remove(key);
+        }
+        Problem::StaleCurrentCache(..) | Problem::UnreferencedBlob(_) => {}
+      }
+    }
+  })?;
+  Ok(problems)
+}
+
+fn generate_current_cache_key(base_dir: &Path) -> Option<Hash> {
+  let base_dir = base_dir.to_path_buf();
+  let lockfile = Lockfile::new(&base_dir).ok()?;
+  let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind)).ok()?;
+  generate_cache_key(&lockfile, &project_root, &Fingerprint::current()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    cache::{
+      metadata::{CacheMeta, MetadataContents},
+      store::{Manifest, Node},
+    },
+    utils::fs::create_symlink,
+  };
+
+  #[test]
+  fn test_verify_and_repair() {
+    let cache_dir = TempDir::new().unwrap();
+    let project_dir = TempDir::new().unwrap();
+    let base_dir = project_dir.path().to_path_buf();
+    fs::write(base_dir.join("package.json"), "{}").unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    fs::create_dir(base_dir.join("node_modules")).unwrap();
+    let dir_key = to_dir_key(&base_dir);
+    let other_dir = base_dir.join("other");
+    let other_dir_key = to_dir_key(&other_dir);
+    let to_key = |key: &str| Hash(key.to_string());
+
+    // the current cache of the project, whose key doesn't match the project
+    let stale = to_key("a-b-x");
+    create_symlink(
+      base_dir.join("node_modules"),
+      cache_dir.path().join(stale.0.as_str()),
+    )
+    .unwrap();
+    // a revoked cache which is deleted by hand
+    let missing = to_key("c-d-x");
+    // a revoked cache which is not referred
+    let unreferenced = to_key("e-f-x");
+    fs::create_dir(cache_dir.path().join(unreferenced.0.as_str())).unwrap();
+    // the current cache of another project whose node_modules was deleted
+    let dangling = to_key("g-h-x");
+    create_symlink(
+      other_dir.join("node_modules"),
+      cache_dir.path().join(dangling.0.as_str()),
+    )
+    .unwrap();
+
+    Metadata::new(cache_dir.path())
+      .unwrap()
+      .modify_all(|contents, entries| {
+        contents.insert(
+          dir_key.clone(),
+          MetadataContents {
+            current_hash_key: Some(stale.clone()),
+            caches: HashMap::from([
+              (stale.clone(), CacheMeta::default()),
+              (missing.clone(), CacheMeta::default()),
+            ]),
+            repository: None,
+            stat_cache: None,
+          },
+        );
+        contents.insert(
+          other_dir_key.clone(),
+          MetadataContents {
+            current_hash_key: Some(dangling.clone()),
+            caches: HashMap::from([(dangling.clone(), CacheMeta::default())]),
+            repository: None,
+            stat_cache: None,
+          },
+        );
+        entries.insert(
+          stale.clone(),
+          EntryMeta {
+            origin: Some(base_dir.clone()),
+            inputs: None,
+          },
+        );
+      })
+      .unwrap();
+
+    let problems = verify(Some(cache_dir.path())).unwrap();
+    let actual = generate_current_cache_key(&base_dir).unwrap();
+    assert_eq!(
+      problems,
+      vec![
+        Problem::MissingCache(dir_key.clone(), missing.clone()),
+        Problem::StaleCurrentCache(dir_key.clone(), stale.clone(), actual, base_dir.clone()),
+        Problem::UnreferencedCache(unreferenced.clone()),
+        Problem::DanglingSymlink(
+          other_dir_key.clone(),
+          dangling.clone(),
+          other_dir.join("node_modules")
+        ),
+      ]
+    );
+
+    let fixed = repair(Some(cache_dir.path()), &problems).unwrap();
+    assert_eq!(fixed.len(), 3);
+    assert!(!cache_dir.path().join(dangling.0.as_str()).is_symlink());
+    let metadata = Metadata::new(cache_dir.path()).unwrap();
+    assert_eq!(
+      metadata.contents,
+      HashMap::from([(
+        dir_key.clone(),
+        MetadataContents {
+          current_hash_key: Some(stale.clone()),
+          caches: HashMap::from([(stale.clone(), CacheMeta::default())]),
+          repository: None,
+          stat_cache: None,
+        },
+      )])
+    );
+    assert_eq!(
+      metadata.entries,
+      HashMap::from([
+        (
+          stale.clone(),
+          EntryMeta {
+            origin: Some(base_dir.clone()),
+            inputs: None,
+          },
+        ),
+        (unreferenced.clone(), EntryMeta::default()),
+      ])
+    );
+    // only the stale cache remains
+    assert_eq!(verify(Some(cache_dir.path())).unwrap().len(), 1);
+
+    cache_dir.close().unwrap();
+    project_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_verify_and_repair_store() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache_dir = tmp_dir.path().join("cache");
+    let node_modules = tmp_dir.path().join("node_modules");
+    fs::create_dir_all(node_modules.join("a")).unwrap();
+    fs::write(node_modules.join("a/index.js"), "module.exports = 1").unwrap();
+    fs::write(node_modules.join("a/package.json"), "{}").unwrap();
+    let store = Store::new(&cache_dir);
+    let (intact, corrupt, removed) = (
+      Hash(String::from("a-b-x")),
+      Hash(String::from("c-d-x")),
+      Hash(String::from("e-f-x")),
+    );
+    store.ingest(&intact, &node_modules).unwrap();
+    fs::write(node_modules.join("a/index.js"), "module.exports = 2").unwrap();
+    let manifest = store.ingest(&corrupt, &node_modules).unwrap();
+    fs::write(node_modules.join("a/index.js"), "module.exports = 3").unwrap();
+    store.ingest(&removed, &node_modules).unwrap();
+    let blob = |manifest: &Manifest| match &manifest.nodes["a/index.js"] {
+      Node::File { blob, .. } => blob.clone(),
+      node => panic!("{:?}", node),
+    };
+    // a blob modified in place through a hardlink
+    let corrupt_blob = blob(&manifest);
+    let blob_path = store.to_blob_path(&corrupt_blob);
+    fs::remove_file(&blob_path).unwrap();
+    fs::write(&blob_path, "module.exports = 4").unwrap();
+    let removed_blob = blob(&store.read_manifest(&removed).unwrap());
+    store.remove_manifest(&removed).unwrap();
+    Metadata::new(&cache_dir)
+      .unwrap()
+      .modify_all(|_, entries| {
+        for key in [&intact, &corrupt] {
+          entries.insert(key.clone(), EntryMeta::default());
+        }
+      })
+      .unwrap();
+
+    let problems = verify(Some(&cache_dir)).unwrap();
+    assert_eq!(
+      problems,
+      vec![
+        Problem::CorruptCache(corrupt.clone(), Some(corrupt_blob.clone())),
+        Problem::UnreferencedBlob(Hash(removed_blob.clone())),
+      ]
+    );
+    assert_eq!(repair(Some(&cache_dir), &problems).unwrap().len(), 2);
+    assert!(!store.to_manifest_path(&corrupt).exists());
+    assert!(!store.to_blob_path(&corrupt_blob).exists());
+    assert!(!store.to_blob_path(&removed_blob).exists());
+    assert!(!Metadata::new(&cache_dir)
+      .unwrap()
+      .entries
+      .contains_key(&corrupt));
+    assert_eq!(verify(Some(&cache_dir)).unwrap(), vec![]);
+    tmp_dir.close().unwrap();
+  }
+}
@@ -1,15 +1,76 @@
-use std::path::PathBuf;
+use std::{
+  env,
+  path::{Path, PathBuf},
+  process,
+  str::FromStr,
+  thread,
+  time::Duration,
+};
 
-use clap::{value_parser, Arg, Command};
+use clap::{builder::PossibleValuesParser, value_parser, Arg, ArgAction, ArgMatches, Command};
+use serde::Serialize;
+use serde_json::json;
+use strum::VariantNames;
+use strum_macros::{EnumString, EnumVariantNames};
 
-use crate::core::{self, APP_NAME};
+use crate::{
+  cache::{self, CacheOptions, Problem, Strategy, REMOTE_TOKEN_ENV},
+  core::{self, Action, Explanation, Outcome, Status, APP_NAME},
+  errors::{find_error, to_exit_code, Error, ErrorReport},
+  hook::{self, Check, HookMode, Shell, HOOK_RUN_CMD},
+  plan::Plan,
+  project::find_project_root,
+  utils::hash::Hash,
+  watch,
+};
 
+const CACHE_CMD: &str = "cache";
+const VERIFY_CMD: &str = "verify";
+const REPAIR_CMD: &str = "repair";
+const EXPORT_CMD: &str = "export";
+const IMPORT_CMD: &str = "import";
 const INSTALL_CMD: &str = "install";
+const KEY_CMD: &str = "key";
 const RUN_CMD: &str = "run";
+const STATUS_CMD: &str = "status";
 const UNINSTALL_CMD: &str = "uninstall";
+const WATCH_CMD: &str = "watch";
+const HOOK_CMD: &str = "hook";
 
 const BASE_DIR_ARG: &str = "base_dir";
 const CACHE_DIR_ARG: &str = "cache_dir";
+const RECURSIVE_ARG: &str = "recursive";
+const JOBS_ARG: &str = "jobs";
+const LOCK_TIMEOUT_ARG: &str = "lock_timeout";
+const STRATEGY_ARG: &str = "strategy";
+const DEDUPE_ARG: &str = "dedupe";
+const COMPRESS_AFTER_ARG: &str = "compress_after";
+const REMOTE_ARG: &str = "remote";
+const KEY_ARG: &str = "key";
+const OUTPUT_ARG: &str = "output";
+const BUNDLE_ARG: &str = "bundle";
+const FORCE_ARG: &str = "force";
+const FORMAT_ARG: &str = "format";
+const VERBOSE_ARG: &str = "verbose";
+const DRY_RUN_ARG: &str = "dry_run";
+const EXPLAIN_ARG: &str = "explain";
+const DEBOUNCE_ARG: &str = "debounce";
+const SHELL_ARG: &str = "shell";
+
+const DEFAULT_LOCK_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_DEBOUNCE_MILLIS: u64 = 500;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How results of commands are printed.
+#[derive(EnumString, EnumVariantNames, Debug, Default, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "lowercase")]
+enum Format {
+  /// lines for humans
+  #[default]
+  Text,
+  /// a JSON object per command for editor plugins and CI scripts
+  Json,
+}
 
 fn path_buf_arg(id: &'static str) -> Arg {
   Arg::new(id).value_parser(value_parser!(PathBuf))
@@ -31,10 +92,50 @@ pub fn run() {
       format!("A path to a cache store directory (%LOCALAPPDATA%/{APP_NAME} or ~\\AppData\\Local\\{APP_NAME} by default) ",),
     );
 
+  let lock_timeout_arg = Arg::new(LOCK_TIMEOUT_ARG)
+    .long("lock-timeout")
+    .value_parser(value_parser!(u64))
+    .help(format!("Seconds to wait for another {APP_NAME} process syncing the same project or repairing the cache directory ({DEFAULT_LOCK_TIMEOUT_SECS} by default)"));
+
+  // options of syncing shared by run and watch
+  let sync_args = [
+    lock_timeout_arg.clone(),
+    Arg::new(STRATEGY_ARG)
+      .long("strategy")
+      .value_parser(PossibleValuesParser::new(Strategy::VARIANTS))
+      .help("How to restore node_modules from a cache (\"auto\" renames on the same filesystem, otherwise copies, and takes the cheapest of reflink, hardlink and copy for a cache in use by another project, by default)"),
+    Arg::new(DEDUPE_ARG)
+      .long("dedupe")
+      .action(ArgAction::SetTrue)
+      .help("Keep caches in a content-addressed store sharing the same files among them"),
+    Arg::new(COMPRESS_AFTER_ARG)
+      .long("compress-after")
+      .value_parser(value_parser!(u64))
+      .help("Compress caches into tar.zst archives after the number of days unused"),
+    Arg::new(REMOTE_ARG)
+      .long("remote")
+      .help(format!("A URL (http(s):// or s3://<bucket>/<prefix>) or a directory of a remote cache to download caches missing locally and upload new ones (a bearer token is read from ${REMOTE_TOKEN_ENV}, and S3 credentials from AWS_* variables)")),
+  ];
+
   let cli = Command::new(APP_NAME)
     .about("Sync node_modules when your local dependency list changes")
     .subcommand_required(true)
     .arg_required_else_help(true)
+    .arg(
+      Arg::new(FORMAT_ARG)
+        .long("format")
+        .global(true)
+        .value_parser(PossibleValuesParser::new(Format::VARIANTS))
+        .help("How to print results (\"text\" by default, or \"json\" for scripts)"),
+    )
+    .arg(
+      Arg::new(VERBOSE_ARG)
+        .long("verbose")
+        .short('v')
+        .global(true)
+        .action(ArgAction::SetTrue)
+        .help("Print every cause of errors"),
+    )
     .subcommand(
       Command::new(INSTALL_CMD)
         .about(format!("Install {APP_NAME} at your local project"))
@@ -45,8 +146,124 @@ pub fn run() {
       Command::new(RUN_CMD)
         .about(format!("Run {APP_NAME}"))
         .arg(base_dir_arg.clone())
+        .arg(cache_dir_arg.clone())
+        .arg(
+          Arg::new(RECURSIVE_ARG)
+            .long("recursive")
+            .short('r')
+            .action(ArgAction::SetTrue)
+            .help("Run every project with a lockfile under the directory"),
+        )
+        .arg(
+          Arg::new(JOBS_ARG)
+            .long("jobs")
+            .short('j')
+            .value_parser(value_parser!(usize))
+            .requires(RECURSIVE_ARG)
+            .help("The number of projects to run in parallel with --recursive (the number of CPUs by default)"),
+        )
+        .args(sync_args.clone())
+        .arg(
+          Arg::new(DRY_RUN_ARG)
+            .long("dry-run")
+            .short('n')
+            .action(ArgAction::SetTrue)
+            .help("Print what would be done without changing node_modules, caches or lockfiles"),
+        ),
+    )
+    .subcommand(
+      Command::new(WATCH_CMD)
+        .about("Run whenever the lockfile, package.json of workspaces or configuration files change")
+        .arg(base_dir_arg.clone())
+        .arg(cache_dir_arg.clone())
+        .args(sync_args)
+        .arg(
+          Arg::new(DEBOUNCE_ARG)
+            .long("debounce")
+            .value_parser(value_parser!(u64))
+            .help(format!("Milliseconds to wait for changes to settle before running ({DEFAULT_DEBOUNCE_MILLIS} by default)")),
+        ),
+    )
+    .subcommand(
+      Command::new(HOOK_CMD)
+        .about(format!("Print a snippet for your shell config to check node_modules before each prompt (e.g. eval \"$({APP_NAME} hook bash)\")"))
+        .long_about(format!("Print a snippet for your shell config to check node_modules before each prompt (e.g. eval \"$({APP_NAME} hook bash)\").\nWhen node_modules is out of sync, it prints a warning, or runs {APP_NAME} with \"{APP_NAME}\": {{\"hook\": \"sync\"}} in package.json of the project (\"off\" disables it)."))
+        .arg(
+          Arg::new(SHELL_ARG)
+            .required(true)
+            .value_parser(PossibleValuesParser::new(Shell::VARIANTS)),
+        ),
+    )
+    .subcommand(
+      Command::new(HOOK_RUN_CMD)
+        .about("Check node_modules of the project at the current directory, as the snippet of hook does")
+        .hide(true)
         .arg(cache_dir_arg.clone()),
     )
+    .subcommand(
+      Command::new(STATUS_CMD)
+        .about("Show whether node_modules is in sync with the lockfile")
+        .arg(base_dir_arg.clone())
+        .arg(cache_dir_arg.clone()),
+    )
+    .subcommand(
+      Command::new(KEY_CMD)
+        .about("Print the cache key of the project")
+        .arg(base_dir_arg.clone())
+        .arg(cache_dir_arg.clone())
+        .arg(
+          Arg::new(EXPLAIN_ARG)
+            .long("explain")
+            .action(ArgAction::SetTrue)
+            .help("Print what the key is generated from, and what has changed since node_modules was synced last"),
+        ),
+    )
+    .subcommand(
+      Command::new(CACHE_CMD)
+        .about("Manage the cache store")
+        .subcommand_required(true)
+        .subcommand(
+          Command::new(VERIFY_CMD)
+            .about("Report inconsistencies between metadata and caches")
+            .arg(cache_dir_arg.clone())
+            .arg(lock_timeout_arg.clone()),
+        )
+        .subcommand(
+          Command::new(REPAIR_CMD)
+            .about("Fix inconsistencies between metadata and caches")
+            .arg(cache_dir_arg.clone())
+            .arg(lock_timeout_arg),
+        )
+        .subcommand(
+          Command::new(EXPORT_CMD)
+            .about("Export a cache as a bundle to share with other machines")
+            .arg(Arg::new(KEY_ARG).required(true).help("A cache key to export"))
+            .arg(
+              path_buf_arg(OUTPUT_ARG)
+                .long("output")
+                .short('o')
+                .required(true)
+                .help("A path to write the bundle (.tar.zst)"),
+            )
+            .arg(cache_dir_arg.clone()),
+        )
+        .subcommand(
+          Command::new(IMPORT_CMD)
+            .about("Import a cache from a bundle made by export")
+            .arg(path_buf_arg(BUNDLE_ARG).required(true).help("A path to the bundle"))
+            .arg(base_dir_arg.clone().help(
+              "A path to a local project to register the cache for",
+            ))
+            .arg(
+              Arg::new(FORCE_ARG)
+                .long("force")
+                .short('f')
+                .action(ArgAction::SetTrue)
+                .help("Import a bundle built on another platform"),
+            )
+            .arg(cache_dir_arg.clone()),
+        ),
+    )
     .subcommand(
       Command::new(UNINSTALL_CMD)
         .about(format!("Uninstall {APP_NAME} from your local project"))
@@ -65,12 +282,482 @@ pub fn run() {
         .map(PathBuf::from)
         .unwrap_or_default();
       let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
-      let result = core::run(base_dir, cache_dir);
-      dbg!(&result);
+      let lock_timeout = to_lock_timeout(args);
+      let options = to_cache_options(args);
+      let output = Output::from_args(args);
+      if args.get_flag(DRY_RUN_ARG) {
+        if args.get_flag(RECURSIVE_ARG) {
+          let results = core::dry_run_recursive(base_dir, cache_dir, &options);
+          print_plans(output, &results);
+        } else {
+          report(
+            output,
+            core::dry_run(base_dir, cache_dir, &options),
+            print_plan,
+          );
+        }
+      } else if args.get_flag(RECURSIVE_ARG) {
+        let jobs = args.get_one::<usize>(JOBS_ARG).copied().unwrap_or(
+          thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        );
+        let results = core::run_recursive(base_dir, cache_dir, lock_timeout, options, jobs);
+        print_summary(output, &results);
+        // the first failure tells the status as a single run does
+        if let Some((_, Err(error))) = results.iter().find(|(_, result)| result.is_err()) {
+          process::exit(to_exit_code(error));
+        }
+      } else {
+        let result = core::run(base_dir, cache_dir, lock_timeout, options);
+        report(output, result, print_outcome);
+      }
     }
+    Some((WATCH_CMD, args)) => {
+      let base_dir = args
+        .get_one::<PathBuf>(BASE_DIR_ARG)
+        .map(PathBuf::from)
+        .unwrap_or_default();
+      let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+      let debounce = Duration::from_millis(
+        args
+          .get_one::<u64>(DEBOUNCE_ARG)
+          .copied()
+          .unwrap_or(DEFAULT_DEBOUNCE_MILLIS),
+      );
+      let output = Output::from_args(args);
+      // a failed sync is reported and waits for the next change, and only a failure of watching ends the command
+      let result = watch::watch(
+        base_dir,
+        cache_dir,
+        to_lock_timeout(args),
+        to_cache_options(args),
+        debounce,
+        |result| match (output.format, &result) {
+          (Format::Text, Ok(outcome)) => print_outcome(outcome),
+          (Format::Json, Ok(outcome)) => print_json(outcome),
+          (Format::Text, Err(error)) => print_error(error, output.verbose),
+          (Format::Json, Err(error)) => print_json(&json!({ "error": ErrorReport::from(error) })),
+        },
+      );
+      report(output, result, |_| {});
+    }
+    Some((HOOK_CMD, args)) => {
+      let shell = args
+        .get_one::<String>(SHELL_ARG)
+        .and_then(|s| Shell::from_str(s).ok())
+        .unwrap_or(Shell::Bash);
+      let executable = env::current_exe()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or(String::from(APP_NAME));
+      print!("{}", shell.to_snippet(&executable));
+    }
+    Some((HOOK_RUN_CMD, args)) => {
+      let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+      // the hook stays silent outside projects and never fails the prompt
+      let Ok(base_dir) = find_project_root(PathBuf::default()) else {
+        return;
+      };
+      let mode = HookMode::read(&base_dir);
+      if mode == HookMode::Off {
+        return;
+      }
+      match hook::check(&base_dir, cache_dir.as_ref()) {
+        Ok(Check::OutOfSync) if mode == HookMode::Sync => {
+          let result = core::run(
+            &base_dir,
+            cache_dir,
+            Duration::from_secs(DEFAULT_LOCK_TIMEOUT_SECS),
+            CacheOptions::default(),
+          );
+          match result {
+            Ok(outcome) => eprintln!("{APP_NAME}: {} {}", outcome.action, outcome.key),
+            Err(error) => print_error(&error, Output::from_args(args).verbose),
+          }
+        }
+        Ok(Check::OutOfSync) => eprintln!(
+          "{APP_NAME}: node_modules is out of sync with the dependencies, run `{APP_NAME} run` to sync it"
+        ),
+        Ok(_) => {}
+        Err(error) => log::debug!("Failed to check node_modules: {:?}", error),
+      }
+    }
+    Some((STATUS_CMD, args)) => {
+      let base_dir = args
+        .get_one::<PathBuf>(BASE_DIR_ARG)
+        .map(PathBuf::from)
+        .unwrap_or_default();
+      let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+      report(
+        Output::from_args(args),
+        core::status(base_dir, cache_dir),
+        print_status,
+      );
+    }
+    Some((KEY_CMD, args)) => {
+      let base_dir = args
+        .get_one::<PathBuf>(BASE_DIR_ARG)
+        .map(PathBuf::from)
+        .unwrap_or_default();
+      let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+      let output = Output::from_args(args);
+      let result = core::explain(base_dir, cache_dir);
+      if args.get_flag(EXPLAIN_ARG) {
+        report(output, result, print_explanation);
+      } else {
+        let result = result.map(|explanation| json!({ "key": explanation.key }));
+        report(output, result, |value| {
+          println!("{}", value["key"].as_str().unwrap_or_default())
+        });
+      }
+    }
+    Some((CACHE_CMD, args)) => match args.subcommand() {
+      Some((VERIFY_CMD, args)) => {
+        let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+        // entries being saved by syncs look inconsistent
+        let result = cache::resolve_cache_dir(cache_dir)
+          .and_then(|cache_dir| {
+            let _lock = cache::lock_cache_dir(&cache_dir, to_lock_timeout(args))?;
+            cache::verify(Some(cache_dir))
+          })
+          .map(|problems| {
+            json!({ "problems": problems.iter().map(ProblemReport::from).collect::<Vec<_>>() })
+          });
+        report(Output::from_args(args), result, |value| {
+          print_problems(&value["problems"])
+        });
+      }
+      Some((REPAIR_CMD, args)) => {
+        let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+        // no sync may save an entry between verifying and repairing, whose blobs would be taken as unreferenced
+        let result = cache::resolve_cache_dir(cache_dir).and_then(|cache_dir| {
+          let _lock = cache::lock_cache_dir(&cache_dir, to_lock_timeout(args))?;
+          let problems = cache::verify(Some(&cache_dir))?;
+          let fixed = cache::repair(Some(&cache_dir), &problems)?;
+          Ok(json!({
+            "problems": problems.iter().map(ProblemReport::from).collect::<Vec<_>>(),
+            "fixed": fixed.len(),
+          }))
+        });
+        report(Output::from_args(args), result, |value| {
+          print_problems(&value["problems"]);
+          println!("{} fixed", value["fixed"]);
+        });
+      }
+      Some((EXPORT_CMD, args)) => {
+        let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+        let key = Hash(args.get_one::<String>(KEY_ARG).cloned().unwrap_or_default());
+        let output = args
+          .get_one::<PathBuf>(OUTPUT_ARG)
+          .map(PathBuf::from)
+          .unwrap_or_default();
+        let result =
+          cache::export(cache_dir, &key, &output).map(|_| json!({ "key": key, "output": output }));
+        report(Output::from_args(args), result, |_| {
+          println!("Exported {} to {}", key, output.to_string_lossy())
+        });
+      }
+      Some((IMPORT_CMD, args)) => {
+        let cache_dir = args.get_one::<PathBuf>(CACHE_DIR_ARG).map(PathBuf::from);
+        let bundle = args
+          .get_one::<PathBuf>(BUNDLE_ARG)
+          .map(PathBuf::from)
+          .unwrap_or_default();
+        let base_dir = args.get_one::<PathBuf>(BASE_DIR_ARG).map(PathBuf::from);
+        let result = cache::import(cache_dir, &bundle, base_dir, args.get_flag(FORCE_ARG))
+          .map(|key| json!({ "key": key }));
+        report(Output::from_args(args), result, |value| {
+          println!("Imported {}", value["key"].as_str().unwrap_or_default())
+        });
+      }
+      _ => unreachable!(),
+    },
     _ => {
       // TODO: implement
       unimplemented!()
     }
   }
 }
+
+/// How results and errors of a command are printed.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+struct Output {
+  format: Format,
+  verbose: bool,
+}
+
+impl Output {
+  fn from_args(args: &ArgMatches) -> Self {
+    Self {
+      format: args
+        .get_one::<String>(FORMAT_ARG)
+        .and_then(|f| Format::from_str(f).ok())
+        .unwrap_or_default(),
+      verbose: args.get_flag(VERBOSE_ARG),
+    }
+  }
+}
+
+fn to_lock_timeout(args: &ArgMatches) -> Duration {
+  Duration::from_secs(
+    args
+      .get_one::<u64>(LOCK_TIMEOUT_ARG)
+      .copied()
+      .unwrap_or(DEFAULT_LOCK_TIMEOUT_SECS),
+  )
+}
+
+fn to_cache_options(args: &ArgMatches) -> CacheOptions {
+  CacheOptions {
+    strategy: args
+      .get_one::<String>(STRATEGY_ARG)
+      .and_then(|s| Strategy::from_str(s).ok())
+      .unwrap_or_default(),
+    dedupe: args.get_flag(DEDUPE_ARG),
+    compress_after: args
+      .get_one::<u64>(COMPRESS_AFTER_ARG)
+      .map(|days| Duration::from_secs(days * SECS_PER_DAY)),
+    remote: args.get_one::<String>(REMOTE_ARG).cloned(),
+  }
+}
+
+fn print_json(value: &impl Serialize) {
+  match serde_json::to_string(value) {
+    Ok(json) => println!("{}", json),
+    Err(error) => eprintln!("Failed to serialize the result: {}", error),
+  }
+}
+
+/// Print the result of a command, and exit with the code of the error on failure.
+fn report<T: Serialize>(output: Output, result: anyhow::Result<T>, print_text: impl FnOnce(&T)) {
+  match (output.format, &result) {
+    (Format::Text, Ok(value)) => print_text(value),
+    (Format::Json, Ok(value)) => print_json(value),
+    (Format::Text, Err(error)) => print_error(error, output.verbose),
+    (Format::Json, Err(error)) => print_json(&json!({ "error": ErrorReport::from(error) })),
+  }
+  if let Err(error) = result {
+    process::exit(to_exit_code(&error));
+  }
+}
+
+/// Print `error` for humans with the cause and a hint, or with every context added on the way if `verbose`.
+fn print_error(error: &anyhow::Error, verbose: bool) {
+  eprintln!("Error: {}", error);
+  let inner = find_error(error);
+  if verbose {
+    for (i, cause) in error.chain().skip(1).enumerate() {
+      eprintln!("  {}: {}", i, cause);
+    }
+  } else if let Some(inner) = inner.filter(|e| !matches!(e, Error::Any(_))) {
+    if error.chain().count() > 1 {
+      eprintln!("  Caused by: {}", inner);
+    }
+  }
+  if let Some(hint) = inner.and_then(Error::hint) {
+    eprintln!("Hint: {}", hint);
+  }
+}
+
+#[derive(Serialize)]
+struct ProblemReport<'a> {
+  kind: &'static str,
+  key: &'a Hash,
+  message: String,
+  fixable: bool,
+}
+
+impl<'a> From<&'a Problem> for ProblemReport<'a> {
+  fn from(problem: &'a Problem) -> Self {
+    Self {
+      kind: problem.kind(),
+      key: problem.key(),
+      message: problem.to_string(),
+      fixable: problem.is_fixable(),
+    }
+  }
+}
+
+fn print_problems(problems: &serde_json::Value) {
+  let problems = problems.as_array().cloned().unwrap_or_default();
+  if problems.is_empty() {
+    println!("No problems found");
+  }
+  for problem in problems {
+    println!("{}", problem["message"].as_str().unwrap_or_default());
+  }
+}
+
+fn print_outcome(outcome: &Outcome) {
+  println!("{:<12}{}", outcome.action.to_string(), outcome.key)
+}
+
+fn print_status(status: &Status) {
+  let yes_or_no = |b: bool| if b { "yes" } else { "no" };
+  println!("{:<12}{}", "project", status.base_dir.to_string_lossy());
+  println!("{:<12}{}", "manager", status.package_manager);
+  println!("{:<12}{}", "key", status.key);
+  println!(
+    "{:<12}{}",
+    "current",
+    status
+      .current_key
+      .as_ref()
+      .map_or(String::from("none"), |k| k.to_string())
+  );
+  println!("{:<12}{}", "synced", yes_or_no(status.synced));
+  println!("{:<12}{}", "cached", yes_or_no(status.cached));
+}
+
+fn print_explanation(explanation: &Explanation) {
+  fn to_json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+  }
+  let inputs = &explanation.inputs;
+  println!(
+    "{:<12}{}",
+    "project",
+    explanation.base_dir.to_string_lossy()
+  );
+  println!("{:<12}{}", "dir key", explanation.dir_key);
+  println!(
+    "{:<12}{}",
+    "lockfile",
+    explanation.lockfile.to_string_lossy()
+  );
+  println!("{:<12}{}", "", inputs.lockfile_hash);
+  println!("{:<12}{}", "root", to_json(&inputs.root));
+  for workspace in inputs.workspaces.values() {
+    println!(
+      "{:<12}{}@{} {}",
+      "workspace",
+      workspace.name,
+      workspace.version,
+      to_json(&workspace.dependencies)
+    );
+  }
+  println!("{:<12}{}", "", inputs.project_hash);
+  println!("{:<12}{}", "fingerprint", to_json(&inputs.fingerprint));
+  println!("{:<12}{}", "", inputs.fingerprint_hash);
+  println!("{:<12}{}", "key", explanation.key);
+  let Some(current_key) = &explanation.current_key else {
+    println!("{:<12}none", "current");
+    return;
+  };
+  println!("{:<12}{}", "current", current_key);
+  match &explanation.changes {
+    None => println!("The inputs of the current key are not recorded"),
+    Some(changes) if changes.is_empty() => println!("No changes since synced last"),
+    Some(changes) => {
+      println!("Changes since synced last:");
+      for change in changes {
+        println!("  {}", change);
+      }
+    }
+  }
+}
+
+fn print_plan(plan: &Plan) {
+  let key = match (&plan.key, plan.approximate) {
+    (Some(key), false) => key.to_string(),
+    (Some(key), true) => format!("approximately {}", key),
+    (None, _) => String::from("no lockfile"),
+  };
+  println!(
+    "{} ({}, {})",
+    plan.base_dir.to_string_lossy(),
+    plan.package_manager,
+    key
+  );
+  let mut otherwise = false;
+  for step in &plan.steps {
+    println!("  {}{}", if otherwise { "otherwise " } else { "" }, step);
+    otherwise = step.is_alternative();
+  }
+}
+
+fn print_plans(output: Output, results: &[(PathBuf, anyhow::Result<Plan>)]) {
+  match output.format {
+    Format::Text => {
+      for (base_dir, result) in results {
+        match result {
+          Ok(plan) => print_plan(plan),
+          Err(error) => println!("{}: {}", base_dir.to_string_lossy(), error),
+        }
+      }
+    }
+    Format::Json => {
+      let projects = results
+        .iter()
+        .map(|(base_dir, result)| match result {
+          Ok(plan) => json!(plan),
+          Err(error) => json!({ "base_dir": base_dir, "error": ErrorReport::from(error) }),
+        })
+        .collect::<Vec<_>>();
+      print_json(&json!({ "projects": projects }));
+    }
+  }
+}
+
+/// A project in the result of a recursive run.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ProjectReport<'a> {
+  Synced(&'a Outcome),
+  Failed {
+    base_dir: &'a Path,
+    error: ErrorReport,
+  },
+}
+
+fn print_summary(output: Output, results: &[(PathBuf, anyhow::Result<Outcome>)]) {
+  let (mut noop, mut restored, mut installed, mut failed) = (0, 0, 0, 0);
+  for (base_dir, result) in results {
+    match result {
+      Ok(outcome) => {
+        match outcome.action {
+          Action::Noop => noop += 1,
+          Action::Restored => restored += 1,
+          Action::Installed => installed += 1,
+        }
+        if output.format == Format::Text {
+          println!(
+            "{:<12}{}",
+            outcome.action.to_string(),
+            base_dir.to_string_lossy()
+          );
+        }
+      }
+      Err(error) => {
+        failed += 1;
+        if output.format == Format::Text {
+          println!("{:<12}{}: {}", "failed", base_dir.to_string_lossy(), error);
+        }
+      }
+    }
+  }
+  match output.format {
+    Format::Text => {
+      println!("{noop} noop, {restored} restored, {installed} installed, {failed} failed")
+    }
+    Format::Json => {
+      let projects = results
+        .iter()
+        .map(|(base_dir, result)| match result {
+          Ok(outcome) => ProjectReport::Synced(outcome),
+          Err(error) => ProjectReport::Failed {
+            base_dir,
+            error: ErrorReport::from(error),
+          },
+        })
+        .collect::<Vec<_>>();
+      print_json(&json!({
+        "projects": projects,
+        "noop": noop,
+        "restored": restored,
+        "installed": installed,
+        "failed": failed,
+      }));
+    }
+  }
+}
@@ -1,69 +1,514 @@
-use std::path::{Path, PathBuf};
+use std::{
+  path::{Path, PathBuf},
+  sync::Mutex,
+  thread,
+  time::{Duration, Instant},
+};
 
 use anyhow::Result;
+use serde::{Serialize, Serializer};
+use strum_macros::Display;
 
 use crate::{
-  cache::Cache,
-  project::{Lockfile, PackageManager, ProjectRoot},
+  cache::{
+    compress_unused, find_current_key, find_inputs, find_stat_cache, has_entry, lock_cache_dir,
+    lock_project, record_stat_cache, resolve_cache_dir, share_cache_dir, to_cache_dir, Cache,
+    CacheOptions, StatCache,
+  },
+  errors::Error,
+  plan::{plan, Plan, Step},
+  project::{
+    discover_projects, find_project_root, to_input_candidates, to_input_files, Change, Fingerprint,
+    KeyInputs, Lockfile, LockfileBackup, PackageManager, ProjectRoot,
+  },
   utils::{
-    hash::{Hash, Hashable},
-    path::to_dir_key,
+    hash::Hash,
+    path::{to_dir_key, DirKey},
+    process,
   },
 };
 
 pub const APP_NAME: &str = "syncnm";
 
-pub fn run(base_dir: impl AsRef<Path>, cache_dir: Option<impl AsRef<Path>>) -> Result<()> {
-  let base_dir = base_dir.as_ref().to_path_buf();
-  let node_modules_dir = to_node_modules_dir(&base_dir);
+#[derive(Display, Serialize, Debug, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+  /// node_modules is already in sync with the lockfile
+  Noop,
+  /// node_modules is restored from the cache
+  Restored,
+  /// dependencies are installed by the package manager
+  Installed,
+}
 
-  let lockfile = Lockfile::new(&base_dir);
-  let lockfile_kind = lockfile.as_ref().map(|l| l.kind).ok();
-  let project_root = ProjectRoot::new(&base_dir, lockfile_kind)?;
+/// What syncing a project did.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Outcome {
+  /// the root of the project synced
+  pub base_dir: PathBuf,
+  pub action: Action,
+  /// the cache key of node_modules after syncing
+  pub key: Hash,
+  /// the executable name of the package manager of the project
+  pub package_manager: String,
+  pub timings: Timings,
+}
 
-  if let Ok(lockfile) = &lockfile {
-    let cache = Cache::new(&base_dir, &node_modules_dir, cache_dir.as_ref());
-    let cache_hash_key = generate_cache_key(&base_dir, lockfile, &project_root);
-    if let (Ok(cache), Ok(cache_hash_key)) = (cache.as_ref(), cache_hash_key) {
-      if cache.restore(&base_dir, &cache_hash_key).is_ok() {
-        return Ok(());
-      }
+/// How long each step of syncing took, serialized in milliseconds.
+#[derive(Serialize, Debug, PartialEq, Clone, Default)]
+pub struct Timings {
+  /// looking up and restoring caches, including the remote cache
+  #[serde(rename = "restore_ms", serialize_with = "serialize_millis")]
+  pub restore: Duration,
+  /// installing dependencies, or `None` if restored
+  #[serde(rename = "install_ms", serialize_with = "serialize_optional_millis")]
+  pub install: Option<Duration>,
+  #[serde(rename = "total_ms", serialize_with = "serialize_millis")]
+  pub total: Duration,
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_u128(duration.as_millis())
+}
+
+fn serialize_optional_millis<S: Serializer>(
+  duration: &Option<Duration>,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  match duration {
+    Some(duration) => serialize_millis(duration, serializer),
+    None => serializer.serialize_none(),
+  }
+}
+
+pub fn run(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  lock_timeout: Duration,
+  options: CacheOptions,
+) -> Result<Outcome> {
+  let outcome = sync(base_dir, cache_dir.as_ref(), lock_timeout, &options)?;
+  compress_unused_caches(cache_dir, &options);
+  Ok(outcome)
+}
+
+fn sync(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  lock_timeout: Duration,
+  options: &CacheOptions,
+) -> Result<Outcome> {
+  let started_at = Instant::now();
+  let base_dir = find_project_root(base_dir)?;
+  // hold the locks until node_modules and metadata are settled
+  let resolved_cache_dir = resolve_cache_dir(cache_dir.as_ref())?;
+  let _cache_dir_lock = share_cache_dir(&resolved_cache_dir, lock_timeout)?;
+  let _lock = lock_project(&resolved_cache_dir, &base_dir, lock_timeout)?;
+  let plan = plan(&base_dir, cache_dir.as_ref(), options)?;
+  let outcome = execute(&plan, cache_dir.as_ref(), options, started_at)?;
+  // the key generated in the plan is still valid unless installing updated the lockfile, which is recorded on saving
+  if let (Some(stat_cache), false) = (&plan.stat_cache, outcome.action == Action::Installed) {
+    let recorded = to_cache_dir(cache_dir)
+      .and_then(|cache_dir| record_stat_cache(cache_dir, &base_dir, stat_cache));
+    if let Err(error) = recorded {
+      log::warn!("Failed to record the stats of the files: {:?}", error);
     }
-    if let Ok(cache) = &cache {
-      // save the current cache before update node_modules and a lockfile
-      cache.revoke_current_cache(&base_dir)?;
+  }
+  Ok(outcome)
+}
+
+/// Take the steps of `plan` in order until an alternative step syncs node_modules.
+fn execute(
+  plan: &Plan,
+  cache_dir: Option<impl AsRef<Path>>,
+  options: &CacheOptions,
+  started_at: Instant,
+) -> Result<Outcome> {
+  let base_dir = &plan.base_dir;
+  let node_modules_dir = to_node_modules_dir(base_dir);
+  let new_cache = || {
+    Cache::new(base_dir, &node_modules_dir, cache_dir.as_ref())
+      .map(|cache| cache.with_options(options.clone()))
+  };
+  let to_outcome =
+    |action: Action, key: Hash, restore: Duration, install: Option<Duration>| Outcome {
+      base_dir: base_dir.clone(),
+      action,
+      key,
+      package_manager: plan.package_manager.clone(),
+      timings: Timings {
+        restore,
+        install,
+        total: started_at.elapsed(),
+      },
+    };
+
+  let mut revoked = None;
+  let mut restore_time = started_at.elapsed();
+  let mut install_time = None;
+  let mut saved = None;
+  for step in &plan.steps {
+    match step {
+      Step::Keep { key } => {
+        return Ok(to_outcome(
+          Action::Noop,
+          key.clone(),
+          started_at.elapsed(),
+          None,
+        ));
+      }
+      Step::Restore { key } => {
+        if new_cache()
+          .and_then(|cache| cache.restore(base_dir, key))
+          .is_ok()
+        {
+          return Ok(to_outcome(
+            Action::Restored,
+            key.clone(),
+            started_at.elapsed(),
+            None,
+          ));
+        }
+      }
+      Step::Fetch { key, .. } => {
+        let Ok(cache) = new_cache() else {
+          continue;
+        };
+        match cache.fetch(key) {
+          Ok(true) if cache.restore(base_dir, key).is_ok() => {
+            return Ok(to_outcome(
+              Action::Restored,
+              key.clone(),
+              started_at.elapsed(),
+              None,
+            ));
+          }
+          Ok(_) => {}
+          Err(error) => log::warn!("Failed to fetch the remote cache: {:?}", error),
+        }
+      }
+      Step::Evacuate { .. } => {
+        // save the current cache before update node_modules and a lockfile
+        if let Ok(cache) = new_cache() {
+          if let Some(key) = cache.revoke_current_cache(base_dir)? {
+            revoked = Some((cache, key));
+          }
+        }
+      }
+      Step::Install { .. } => {
+        let lockfile_backup = Lockfile::new(base_dir).ok().and_then(|l| l.backup().ok());
+        restore_time = started_at.elapsed();
+        let package_manager: PackageManager = plan.project_root.kind.into();
+        if let Err(error) = package_manager.execute_install(base_dir) {
+          return Err(rollback(error, revoked.take(), lockfile_backup));
+        }
+        install_time = Some(started_at.elapsed() - restore_time);
+      }
+      Step::Save { .. } => {
+        // a lockfile may updated after executing install
+        let lockfile = Lockfile::new(base_dir)?;
+        let stats = StatCache::take(
+          &to_input_files(base_dir, &lockfile, &plan.project_root),
+          &to_input_candidates(base_dir),
+        );
+        let inputs = KeyInputs::new(&lockfile, &plan.project_root, &Fingerprint::current())?;
+        let key = inputs.to_key();
+        // reevaluate the cache because cache directory may change
+        let cache = new_cache()?.save(key.clone())?.record_inputs(&inputs)?;
+        if let Err(error) = cache.record_stat_cache(&stats.with_key(key.clone())) {
+          log::warn!("Failed to record the stats of the files: {:?}", error);
+        }
+        saved = Some((cache, key));
+      }
+      Step::Publish { .. } => {
+        if let Some((cache, key)) = &saved {
+          if let Err(error) = cache.publish(key) {
+            log::warn!(
+              "Failed to upload the cache to the remote cache: {:?}",
+              error
+            );
+          }
+        }
+      }
     }
   }
+  let (_, key) = saved.ok_or(Error::Any(String::from(
+    "The plan ended without syncing node_modules",
+  )))?;
+  Ok(to_outcome(
+    Action::Installed,
+    key,
+    restore_time,
+    install_time,
+  ))
+}
+
+/// Plan syncing the project which `base_dir` belongs to, without changing anything.
+pub fn dry_run(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  options: &CacheOptions,
+) -> Result<Plan> {
+  let base_dir = find_project_root(base_dir)?;
+  plan_without_spawning(&base_dir, cache_dir.as_ref(), options)
+}
 
+/// Plan syncing every project found under `root_dir` in order, without changing anything.
+pub fn dry_run_recursive(
+  root_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  options: &CacheOptions,
+) -> Vec<(PathBuf, Result<Plan>)> {
+  discover_projects(root_dir)
+    .into_iter()
+    .map(|base_dir| {
+      let plan = plan_without_spawning(&base_dir, cache_dir.as_ref(), options);
+      (base_dir, plan)
+    })
+    .collect()
+}
+
+/// Plan without running `node` or package managers to detect their versions, which the key is approximated without.
+fn plan_without_spawning(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  options: &CacheOptions,
+) -> Result<Plan> {
+  let (plan, refused) = process::without_spawning(|| plan(base_dir, cache_dir, options));
+  let mut plan = plan?;
+  plan.approximate |= refused;
+  Ok(plan)
+}
+
+/// Whether node_modules of a project is in sync with its lockfile, inspected without changing anything.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Status {
+  pub base_dir: PathBuf,
+  /// the executable name of the package manager of the project
+  pub package_manager: String,
+  /// the cache key generated from the project now
+  pub key: Hash,
+  /// the cache key of node_modules synced last, or `None` if never synced
+  pub current_key: Option<Hash>,
+  /// whether node_modules is synced with `key`, so that running does nothing
+  pub synced: bool,
+  /// whether the cache of `key` is in the cache directory, so that running restores it
+  pub cached: bool,
+}
+
+pub fn status(base_dir: impl AsRef<Path>, cache_dir: Option<impl AsRef<Path>>) -> Result<Status> {
+  let base_dir = find_project_root(base_dir)?;
+  let lockfile = Lockfile::new(&base_dir)?;
+  let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind))?;
+  let cache_dir = to_cache_dir(cache_dir)?;
+  let (key, _) = resolve_cache_key(&cache_dir, &base_dir, &lockfile, &project_root)?;
+  let current_key = find_current_key(&cache_dir, &base_dir);
   let package_manager: PackageManager = project_root.kind.into();
-  package_manager.execute_install(&base_dir)?;
+  Ok(Status {
+    synced: current_key.as_ref() == Some(&key) && to_node_modules_dir(&base_dir).is_dir(),
+    cached: has_entry(&cache_dir, &key),
+    package_manager: package_manager.executable_name,
+    base_dir,
+    key,
+    current_key,
+  })
+}
+
+/// What the cache key of a project is generated from, compared with the inputs recorded for node_modules synced last.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Explanation {
+  pub base_dir: PathBuf,
+  /// the key of the project in metadata, which tells which project node_modules synced last belongs to
+  pub dir_key: DirKey,
+  pub lockfile: PathBuf,
+  /// the cache key generated from `inputs`
+  pub key: Hash,
+  pub inputs: KeyInputs,
+  /// the cache key of node_modules synced last, or `None` if never synced
+  pub current_key: Option<Hash>,
+  /// what differs from the inputs of `current_key`, or `None` if they are not recorded
+  pub changes: Option<Vec<Change>>,
+}
 
-  // a lockfile may updated after executing install
+pub fn explain(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+) -> Result<Explanation> {
+  let base_dir = find_project_root(base_dir)?;
   let lockfile = Lockfile::new(&base_dir)?;
-  let cache_key = generate_cache_key(&base_dir, &lockfile, &project_root)?;
-  // reevaluate the cache because cache directory may change
-  let cache = Cache::new(&base_dir, &node_modules_dir, cache_dir.as_ref());
-  cache.and_then(|cache| cache.save(cache_key))?;
-  Ok(())
+  let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind))?;
+  let inputs = KeyInputs::new(&lockfile, &project_root, &Fingerprint::current())?;
+  let cache_dir = to_cache_dir(cache_dir)?;
+  let current_key = find_current_key(&cache_dir, &base_dir);
+  let changes = match current_key
+    .as_ref()
+    .and_then(|key| find_inputs(&cache_dir, key))
+  {
+    Some(recorded) => Some(inputs.diff(&recorded)?),
+    None => None,
+  };
+  Ok(Explanation {
+    dir_key: to_dir_key(&base_dir),
+    lockfile: lockfile.path().to_path_buf(),
+    key: inputs.to_key(),
+    base_dir,
+    inputs,
+    current_key,
+    changes,
+  })
+}
+
+/// Run every project found under `root_dir` in parallel by `jobs` threads at most, and return the results in order of the projects.
+pub fn run_recursive(
+  root_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path> + Sync>,
+  lock_timeout: Duration,
+  options: CacheOptions,
+  jobs: usize,
+) -> Vec<(PathBuf, Result<Outcome>)> {
+  let projects = discover_projects(root_dir);
+  let queue = Mutex::new(projects.into_iter().enumerate());
+  let results = Mutex::new(Vec::<(usize, PathBuf, Result<Outcome>)>::new());
+  thread::scope(|scope| {
+    for _ in 0..jobs.max(1) {
+      scope.spawn(|| loop {
+        let next = match queue.lock() {
+          Ok(mut queue) => queue.next(),
+          Err(_) => None,
+        };
+        let Some((index, base_dir)) = next else {
+          break;
+        };
+        let result = sync(&base_dir, cache_dir.as_ref(), lock_timeout, &options);
+        if let Ok(mut results) = results.lock() {
+          results.push((index, base_dir, result));
+        }
+      });
+    }
+  });
+  compress_unused_caches(cache_dir, &options);
+  let mut results = results.into_inner().unwrap_or_default();
+  results.sort_by_key(|(index, _, _)| *index);
+  results
+    .into_iter()
+    .map(|(_, base_dir, result)| (base_dir, result))
+    .collect()
 }
 
-fn generate_cache_key(
-  base_dir: &PathBuf,
+/// Generate the content key of node_modules, which doesn't depend on where the project is so that checkouts of the same project share caches.
+pub fn generate_cache_key(
   lockfile: &Lockfile,
   project: &ProjectRoot,
+  fingerprint: &Fingerprint,
 ) -> Result<Hash> {
-  let lockfile_hash = lockfile.generate_hash()?;
-  let project_hash = project.generate_hash()?;
-  let base_dir = to_dir_key(base_dir);
-  Ok(Hash(
-    format!(
-      "{}-{}-{}",
-      &lockfile_hash.to_string(),
-      &project_hash.to_string(),
-      &base_dir.to_string()
-    )
-    .to_string(),
-  ))
+  KeyInputs::new(lockfile, project, fingerprint).map(|inputs| inputs.to_key())
+}
+
+/// Generate the cache key with the stats of the files it is generated from, taken before reading them.
+pub fn generate_stat_cache(
+  base_dir: impl AsRef<Path>,
+  lockfile: &Lockfile,
+  project: &ProjectRoot,
+  fingerprint: &Fingerprint,
+) -> Result<StatCache> {
+  let stats = StatCache::take(
+    &to_input_files(&base_dir, lockfile, project),
+    &to_input_candidates(&base_dir),
+  );
+  let key = generate_cache_key(lockfile, project, fingerprint)?;
+  Ok(stats.with_key(key))
+}
+
+/// Return the cache key of the project, reusing the one recorded at `base_dir` while none of its files is modified,
+/// with the stat cache to record if the key is generated. Nothing is written here.
+pub fn resolve_cache_key(
+  cache_dir: impl AsRef<Path>,
+  base_dir: impl AsRef<Path>,
+  lockfile: &Lockfile,
+  project: &ProjectRoot,
+) -> Result<(Hash, Option<StatCache>)> {
+  let files = to_input_files(&base_dir, lockfile, project);
+  match find_stat_cache(&cache_dir, &base_dir).filter(|s| s.has_files(&files) && s.is_fresh()) {
+    Some(stat_cache) => {
+      log::debug!(
+        "Reuse the key {} as its files are unmodified",
+        stat_cache.key
+      );
+      Ok((stat_cache.key, None))
+    }
+    None => {
+      let fingerprint = resolve_fingerprint(&cache_dir, &base_dir);
+      let stat_cache = generate_stat_cache(&base_dir, lockfile, project, &fingerprint)?;
+      Ok((stat_cache.key.clone(), Some(stat_cache)))
+    }
+  }
+}
+
+/// Return the fingerprint of the environment. Without spawning processes, Node.js is assumed to be the one recorded
+/// for node_modules synced last at `base_dir`, as detecting it runs `node --version`.
+fn resolve_fingerprint(cache_dir: impl AsRef<Path>, base_dir: impl AsRef<Path>) -> Fingerprint {
+  let current = Fingerprint::current();
+  if process::is_spawning_allowed() {
+    return current;
+  }
+  find_current_key(&cache_dir, &base_dir)
+    .and_then(|key| find_inputs(&cache_dir, &key))
+    .map(|inputs| inputs.fingerprint)
+    .filter(|recorded| recorded.os == current.os && recorded.arch == current.arch)
+    .unwrap_or(current)
+}
+
+/// Compress caches unused for `options.compress_after` if set, which doesn't fail syncing.
+/// The cache directory is locked exclusively as caches of any project are compressed, and compressing is left to the
+/// next sync without waiting while another process is syncing.
+fn compress_unused_caches(cache_dir: Option<impl AsRef<Path>>, options: &CacheOptions) {
+  let Some(unused_for) = options.compress_after else {
+    return;
+  };
+  let compressed = resolve_cache_dir(cache_dir).and_then(|cache_dir| {
+    let _lock = lock_cache_dir(&cache_dir, Duration::ZERO)?;
+    compress_unused(cache_dir, unused_for)
+  });
+  match compressed {
+    Ok(keys) => keys
+      .iter()
+      .for_each(|key| log::info!("Compressed the unused cache {}", key)),
+    Err(error) if matches!(error.downcast_ref::<Error>(), Some(Error::Locked(..))) => {
+      log::info!("Skip compressing unused caches while the cache directory is in use")
+    }
+    Err(error) => log::warn!("Failed to compress unused caches: {:?}", error),
+  }
+}
+
+/// Put back node_modules and the lockfile as they were before the failed install.
+fn rollback(
+  error: anyhow::Error,
+  revoked: Option<(Cache, Hash)>,
+  lockfile_backup: Option<LockfileBackup>,
+) -> anyhow::Error {
+  let mut rolled_back = true;
+  if let Some((cache, key)) = revoked {
+    if let Err(e) = cache.rollback(&key) {
+      log::error!(
+        "Failed to restore node_modules from the cache {}: {:?}",
+        key,
+        e
+      );
+      rolled_back = false;
+    }
+  }
+  if let Some(lockfile_backup) = lockfile_backup {
+    if let Err(e) = lockfile_backup.restore() {
+      log::error!("Failed to restore the lockfile: {:?}", e);
+      rolled_back = false;
+    }
+  }
+  if rolled_back {
+    error.context("Rolled back node_modules and the lockfile")
+  } else {
+    error.context("Failed to roll back node_modules and the lockfile")
+  }
 }
 
 fn to_node_modules_dir(base_dir: impl AsRef<Path>) -> PathBuf {
@@ -83,11 +528,215 @@ mod tests {
     let base_dir = PathBuf::from("tests/fixtures/core");
     let lockfile = Lockfile::new(&base_dir)?;
     let project = ProjectRoot::new(&base_dir, Some(lockfile.kind))?;
-    let result = generate_cache_key(&base_dir, &lockfile, &project)?;
+    let fingerprint = Fingerprint {
+      os: String::from("linux"),
+      arch: String::from("x86_64"),
+      node: None,
+    };
+    let result = generate_cache_key(&lockfile, &project, &fingerprint)?;
     let r = Regex::new(
-      r"^l3cuczxmteircrzf6dw52asj6vt6opt2-ilchfsie572gsieon7up5cbljysxda5p-[a-z_]+tests_fixtures_core$",
+      r"^l3cuczxmteircrzf6dw52asj6vt6opt2-ilchfsie572gsieon7up5cbljysxda5p-[a-z2-7]{32}$",
     );
     assert!(r.unwrap().is_match(&result.to_string()));
+
+    // the same project elsewhere shares the key
+    let tmp_dir = tempfile::TempDir::new()?;
+    for file in ["package.json", "bun.lockb"] {
+      std::fs::copy(base_dir.join(file), tmp_dir.path().join(file))?;
+    }
+    let lockfile = Lockfile::new(tmp_dir.path())?;
+    let project = ProjectRoot::new(tmp_dir.path(), Some(lockfile.kind))?;
+    assert_eq!(
+      generate_cache_key(&lockfile, &project, &fingerprint)?,
+      result
+    );
     Ok(())
   }
+
+  #[test]
+  fn test_dry_run_without_spawning() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let cache_dir = tmp_dir.path().join("cache");
+    std::fs::create_dir_all(base_dir.join("packages/b")).unwrap();
+    // the version of Bun is detected without `packageManager`
+    std::fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","workspaces":["packages/*"]}"#,
+    )
+    .unwrap();
+    std::fs::write(base_dir.join("packages/b/package.json"), r#"{"name":"b"}"#).unwrap();
+    std::fs::write(base_dir.join("bun.lockb"), "").unwrap();
+    let options = CacheOptions::default();
+
+    let spawned = process::count_spawned();
+    let plan = dry_run(&base_dir, Some(&cache_dir), &options).unwrap();
+    assert_eq!(process::count_spawned(), spawned);
+    assert!(plan.approximate);
+    assert!(!cache_dir.exists());
+
+    // Node.js recorded for node_modules synced last is assumed
+    let lockfile = Lockfile::new(&base_dir).unwrap();
+    let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind)).unwrap();
+    let fingerprint = Fingerprint {
+      node: Some(crate::project::Version(18, 0, 0)),
+      ..Fingerprint::current()
+    };
+    let inputs = KeyInputs::new(&lockfile, &project_root, &fingerprint).unwrap();
+    std::fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+    Cache::new(&base_dir, base_dir.join("node_modules"), Some(&cache_dir))
+      .unwrap()
+      .save(inputs.to_key())
+      .unwrap()
+      .record_inputs(&inputs)
+      .unwrap();
+    let spawned = process::count_spawned();
+    let plan = dry_run(&base_dir, Some(&cache_dir), &options).unwrap();
+    assert_eq!(process::count_spawned(), spawned);
+    assert_eq!(plan.key, Some(inputs.to_key()));
+    assert_eq!(
+      plan.steps,
+      vec![Step::Keep {
+        key: inputs.to_key()
+      }]
+    );
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_run_locked() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let cache_dir = tmp_dir.path().join("cache");
+    std::fs::create_dir_all(&base_dir).unwrap();
+    std::fs::write(base_dir.join("package.json"), r#"{"name":"a"}"#).unwrap();
+    std::fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    let run = |cache_dir: &Path| {
+      run(
+        &base_dir,
+        Some(cache_dir),
+        Duration::from_millis(100),
+        CacheOptions::default(),
+      )
+      .unwrap_err()
+      .downcast::<Error>()
+      .unwrap()
+    };
+
+    // verifying or repairing the cache directory
+    let lock = lock_cache_dir(&cache_dir, Duration::ZERO).unwrap();
+    assert!(matches!(run(&cache_dir), Error::Locked(..)));
+    drop(lock);
+
+    // the cache directory is not available
+    let file = tmp_dir.path().join("file");
+    std::fs::write(&file, "").unwrap();
+    assert!(!matches!(run(&file), Error::Locked(..)));
+    assert!(!base_dir.join("node_modules").exists());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_serialize_outcome() {
+    let outcome = Outcome {
+      base_dir: PathBuf::from("/work/app"),
+      action: Action::Restored,
+      key: Hash(String::from("a-b-c")),
+      package_manager: String::from("npm"),
+      timings: Timings {
+        restore: Duration::from_millis(1500),
+        install: None,
+        total: Duration::from_millis(1520),
+      },
+    };
+    assert_eq!(
+      serde_json::to_value(&outcome).unwrap(),
+      serde_json::json!({
+        "base_dir": "/work/app",
+        "action": "restored",
+        "key": "a-b-c",
+        "package_manager": "npm",
+        "timings": { "restore_ms": 1500, "install_ms": null, "total_ms": 1520 },
+      })
+    );
+  }
+
+  #[test]
+  fn test_explain() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let cache_dir = tmp_dir.path().join("cache");
+    std::fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+    std::fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","dependencies":{"b":"^1.0.0"}}"#,
+    )
+    .unwrap();
+    std::fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+
+    let explanation = explain(&base_dir, Some(&cache_dir)).unwrap();
+    assert_eq!(explanation.current_key, None);
+    assert_eq!(explanation.changes, None);
+    assert_eq!(explanation.inputs.lockfile, "package-lock.json");
+
+    let cache = Cache::new(&base_dir, base_dir.join("node_modules"), Some(&cache_dir)).unwrap();
+    cache
+      .save(explanation.key.clone())
+      .unwrap()
+      .record_inputs(&explanation.inputs)
+      .unwrap();
+    let explanation = explain(&base_dir, Some(&cache_dir)).unwrap();
+    assert_eq!(explanation.changes, Some(vec![]));
+
+    std::fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","dependencies":{"b":"^2.0.0"}}"#,
+    )
+    .unwrap();
+    let explanation = explain(&base_dir, Some(&cache_dir)).unwrap();
+    let paths = explanation
+      .changes
+      .unwrap()
+      .into_iter()
+      .map(|change| change.path)
+      .collect::<Vec<_>>();
+    assert_eq!(paths, vec!["project_hash", "root.dependencies.b"]);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_resolve_cache_key() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let cache_dir = tmp_dir.path().join("cache");
+    std::fs::create_dir_all(&base_dir).unwrap();
+    std::fs::create_dir_all(&cache_dir).unwrap();
+    std::fs::write(base_dir.join("package.json"), r#"{"name":"a"}"#).unwrap();
+    std::fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    let resolve = || {
+      let lockfile = Lockfile::new(&base_dir).unwrap();
+      let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind)).unwrap();
+      resolve_cache_key(&cache_dir, &base_dir, &lockfile, &project_root).unwrap()
+    };
+
+    let (key, stat_cache) = resolve();
+    let stat_cache = stat_cache.unwrap();
+    assert_eq!(stat_cache.key, key);
+    record_stat_cache(&cache_dir, &base_dir, &stat_cache).unwrap();
+    // a shim of Node.js in PATH never lets the key be reused
+    if !Fingerprint::find_node().is_some_and(Fingerprint::is_shim) {
+      assert_eq!(resolve(), (key.clone(), None));
+    }
+
+    std::fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","dependencies":{"b":"^1.0.0"}}"#,
+    )
+    .unwrap();
+    let (new_key, stat_cache) = resolve();
+    assert_ne!(new_key, key);
+    assert!(stat_cache.is_some());
+    tmp_dir.close().unwrap();
+  }
 }
@@ -1,11 +1,14 @@
 use std::{fmt::Debug, path::PathBuf};
 
 use itertools::Itertools;
+use serde::Serialize;
+use strum_macros::IntoStaticStr;
 use thiserror::Error;
 
 use crate::{project::PackageManager, utils::path::to_absolute_path};
 
-#[derive(Debug, Error, PartialEq)]
+#[derive(Debug, Error, PartialEq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Error {
   #[error(
     "Cannot access to a file or a directory: {}",
@@ -44,6 +47,13 @@ pub enum Error {
   )]
   InvalidWorkspace(PathBuf),
 
+  #[error(
+    "Multiple workspaces with the same name \"{}\": {}",
+    .0,
+    stringify_path(.1.to_vec())
+  )]
+  DuplicatedWorkspaceName(String, Vec<PathBuf>),
+
   #[error(
     "\"name\" or \"version\" are missing in {}",
     stringify_path(vec![.0.to_path_buf()])
@@ -84,13 +94,88 @@ pub enum Error {
   FailedToInstallDependencies(PackageManager, PathBuf, String),
 
   #[error(
-    "Error: {:?}",
-    .0
+    "Timed out waiting for a lock of {} held by PID {}",
+    stringify_path(vec![.0.to_path_buf()]),
+    .1.map(|pid| pid.to_string()).unwrap_or(String::from("unknown"))
+  )]
+  Locked(PathBuf, Option<u32>),
+
+  #[error(
+    "Incompatible bundle {}: {}",
+    stringify_path(vec![.0.to_path_buf()]),
+    .1
   )]
+  IncompatibleBundle(PathBuf, String),
+
+  #[error("{}", .0)]
+  #[strum(serialize = "unknown")]
   Any(String),
 }
 
 impl Error {
+  /// A code identifying the kind of the error, which is stable across versions for scripts.
+  pub fn code(&self) -> &'static str {
+    self.into()
+  }
+
+  /// The exit status of the process failed by the error, which is stable across versions for scripts.
+  /// 1 is any other error and 2 is a usage error reported by clap.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Error::Any(_) => 1,
+      Error::NotAccessible(_) => 10,
+      Error::NoEntry(_) => 11,
+      Error::NotDir(_) => 12,
+      Error::NoLockfile(_) => 20,
+      Error::MultipleLockfiles(..) => 21,
+      Error::InvalidWorkspace(_) => 22,
+      Error::DuplicatedWorkspaceName(..) => 23,
+      Error::InvalidPackageJsonFieldsForYarn(_) => 24,
+      Error::InvalidPackageJsonPrivateForYarn(_) => 25,
+      Error::InvalidPackageJsonFieldsForBun(_) => 26,
+      Error::Parse(..) => 30,
+      Error::InvalidGlobPattern(_) => 31,
+      Error::FailedToInstallDependencies(..) => 40,
+      Error::Locked(..) => 50,
+      Error::IncompatibleBundle(..) => 60,
+    }
+  }
+
+  /// What users can do to fix the error, if any.
+  pub fn hint(&self) -> Option<String> {
+    match self {
+      Error::NoLockfile(_) => Some(String::from(
+        "Run the install command of your package manager once to create a lockfile",
+      )),
+      Error::MultipleLockfiles(..) => Some(String::from(
+        "Remove the lockfiles not used by your package manager",
+      )),
+      Error::DuplicatedWorkspaceName(..) => {
+        Some(String::from("Rename one of the workspace packages"))
+      }
+      Error::InvalidPackageJsonFieldsForYarn(_) => Some(String::from(
+        "Add \"name\" and \"version\" to package.json as yarn requires",
+      )),
+      Error::InvalidPackageJsonPrivateForYarn(_) => Some(String::from(
+        "Add \"private\": true to package.json as yarn requires for workspaces",
+      )),
+      Error::InvalidPackageJsonFieldsForBun(_) => {
+        Some(String::from("Add \"name\" to package.json as bun requires"))
+      }
+      Error::FailedToInstallDependencies(package_manager, ..) => Some(format!(
+        "Fix the error above and run \"{}\" to see if it succeeds",
+        stringify_install_command(package_manager)
+      )),
+      Error::Locked(..) => Some(String::from(
+        "Wait for the other process to finish, or increase --lock-timeout",
+      )),
+      Error::IncompatibleBundle(..) => Some(String::from(
+        "Import it with --force if you are sure it works on this machine",
+      )),
+      _ => None,
+    }
+  }
+
   pub fn log_debug<E: Debug>(self, error: E) -> Self {
     log::debug!("{}: {:?}", &self.to_string(), error);
     self
@@ -111,11 +196,49 @@ impl Error {
     } else {
       log::error!("{}", &self.to_string());
     }
-    // TODO: terminate the process
+    // the process is not terminated here but by the command with the exit code of the error,
+    // so that the callers can roll back on the way
     self
   }
 }
 
+/// Find the innermost `Error` in the chain of `error`, which tells the kind of the failure better than the contexts added on the way.
+pub fn find_error(error: &anyhow::Error) -> Option<&Error> {
+  error
+    .chain()
+    .filter_map(|e| e.downcast_ref::<Error>())
+    .last()
+}
+
+/// The exit status of the process failed by `error`.
+pub fn to_exit_code(error: &anyhow::Error) -> i32 {
+  find_error(error).map_or(1, Error::exit_code)
+}
+
+/// An error reported in the output for machines.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErrorReport {
+  /// the code of the innermost `Error` in the chain, or `unknown` if none
+  pub code: &'static str,
+  pub exit_code: i32,
+  /// the message including the context chain
+  pub message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub hint: Option<String>,
+}
+
+impl From<&anyhow::Error> for ErrorReport {
+  fn from(error: &anyhow::Error) -> Self {
+    let inner = find_error(error);
+    Self {
+      code: inner.map_or("unknown", Error::code),
+      exit_code: to_exit_code(error),
+      message: format!("{:#}", error),
+      hint: inner.and_then(Error::hint),
+    }
+  }
+}
+
 /// convert to stringified absolute path
 fn stringify_path(paths: Vec<PathBuf>) -> String {
   paths
@@ -140,3 +263,30 @@ fn stringify_install_command(package_manager: &PackageManager) -> String {
 pub fn to_error<E: Debug>(error: E) -> anyhow::Error {
   Error::Any(format!("{:?}", error)).into()
 }
+
+#[cfg(test)]
+mod tests {
+  use anyhow::Context;
+
+  use super::*;
+
+  #[test]
+  fn test_error_report() {
+    let error = Err::<(), _>(Error::Locked(PathBuf::from("/a.lock"), Some(1)))
+      .context("Failed to sync")
+      .unwrap_err();
+    let report = ErrorReport::from(&error);
+    assert_eq!(report.code, "locked");
+    assert_eq!(report.exit_code, 50);
+    assert_eq!(
+      report.message,
+      "Failed to sync: Timed out waiting for a lock of /a.lock held by PID 1"
+    );
+    assert!(report.hint.is_some());
+
+    let report = ErrorReport::from(&anyhow::anyhow!("unexpected"));
+    assert_eq!(report.code, "unknown");
+    assert_eq!(report.exit_code, 1);
+    assert_eq!(report.hint, None);
+  }
+}
@@ -0,0 +1,226 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde_json::Value;
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use crate::{
+  cache::{find_current_key, find_stat_cache, record_stat_cache, to_cache_dir, StatCache},
+  core::{generate_stat_cache, APP_NAME},
+  project::{to_package_json_path, Fingerprint, Lockfile, ProjectRoot},
+};
+
+/// The command which the shell snippets run before each prompt.
+pub const HOOK_RUN_CMD: &str = "hook-run";
+
+/// Shells which the hook can be installed in.
+#[derive(EnumString, EnumVariantNames, Debug, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "lowercase")]
+pub enum Shell {
+  Bash,
+  Zsh,
+  Fish,
+}
+
+impl Shell {
+  /// A snippet for the shell config which runs `executable hook-run` before each prompt, and so after changing directories.
+  pub fn to_snippet(self, executable: &str) -> String {
+    let command = format!("\"{}\" {}", executable, HOOK_RUN_CMD);
+    match self {
+      Shell::Bash => format!(
+        r#"_{APP_NAME}_hook() {{
+  local previous_exit_status=$?
+  {command}
+  return $previous_exit_status
+}}
+if [[ ";${{PROMPT_COMMAND[*]:-}};" != *";_{APP_NAME}_hook;"* ]]; then
+  PROMPT_COMMAND="_{APP_NAME}_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+fi
+"#
+      ),
+      Shell::Zsh => format!(
+        r#"_{APP_NAME}_hook() {{
+  {command}
+}}
+typeset -ag precmd_functions
+if (( ! ${{precmd_functions[(I)_{APP_NAME}_hook]}} )); then
+  precmd_functions=(_{APP_NAME}_hook $precmd_functions)
+fi
+"#
+      ),
+      Shell::Fish => format!(
+        r#"function __{APP_NAME}_hook --on-event fish_prompt
+  {command}
+end
+"#
+      ),
+    }
+  }
+}
+
+/// What the hook does when node_modules of a project is out of sync, set by `"syncnm": {"hook": "..."}` in package.json.
+#[derive(EnumString, Display, Debug, Default, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "lowercase")]
+pub enum HookMode {
+  /// print a line telling to run
+  #[default]
+  Warn,
+  /// run to sync node_modules
+  Sync,
+  /// do nothing
+  Off,
+}
+
+impl HookMode {
+  /// Read the mode from package.json at `base_dir`, falling back to the default on unknown values rather than failing the prompt.
+  pub fn read(base_dir: impl AsRef<Path>) -> Self {
+    let mode = fs::read_to_string(to_package_json_path(base_dir))
+      .ok()
+      .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+      .and_then(|package_json| package_json[APP_NAME]["hook"].as_str().map(String::from));
+    match mode {
+      Some(mode) => mode.parse().unwrap_or_else(|_| {
+        log::warn!("Unknown hook mode \"{}\" in package.json", mode);
+        Self::default()
+      }),
+      None => Self::default(),
+    }
+  }
+}
+
+/// Whether node_modules of a project is in sync, found by the hook.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Check {
+  /// none of the files is modified since the key was generated last, which is the one of node_modules
+  Unmodified,
+  /// the files are modified, but the key is the same as the one of node_modules
+  InSync,
+  /// the key differs from the one of node_modules, or node_modules is missing
+  OutOfSync,
+}
+
+/// Check whether node_modules at `base_dir` is in sync with its lockfile, only generating the key if any of the files
+/// is modified since it was generated last, so that it takes little time before each prompt.
+/// Workspaces are not resolved while the files are unmodified, so that a new workspace is noticed by `run`.
+pub fn check(base_dir: impl AsRef<Path>, cache_dir: Option<impl AsRef<Path>>) -> Result<Check> {
+  let base_dir = base_dir.as_ref().to_path_buf();
+  let cache_dir = to_cache_dir(cache_dir)?;
+  if !base_dir.join("node_modules").is_dir() {
+    return Ok(Check::OutOfSync);
+  }
+  let current_key = find_current_key(&cache_dir, &base_dir);
+  if let Some(stat_cache) = find_stat_cache(&cache_dir, &base_dir).filter(StatCache::is_fresh) {
+    return Ok(match current_key == Some(stat_cache.key) {
+      true => Check::Unmodified,
+      false => Check::OutOfSync,
+    });
+  }
+  let lockfile = Lockfile::new(&base_dir)?;
+  let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind))?;
+  let stat_cache =
+    generate_stat_cache(&base_dir, &lockfile, &project_root, &Fingerprint::current())?;
+  // skip generating the key next time, only for projects synced ever
+  if current_key.is_some() {
+    if let Err(error) = record_stat_cache(&cache_dir, &base_dir, &stat_cache) {
+      log::debug!("Failed to record the stats of the files: {:?}", error);
+    }
+  }
+  Ok(match current_key == Some(stat_cache.key) {
+    true => Check::InSync,
+    false => Check::OutOfSync,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{str::FromStr, thread, time::Duration};
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::{
+    cache::Cache,
+    core::generate_cache_key,
+    project::{Fingerprint, Lockfile},
+  };
+
+  #[test]
+  fn test_to_snippet() {
+    for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+      let snippet = shell.to_snippet("/usr/bin/syncnm");
+      assert!(
+        snippet.contains("\"/usr/bin/syncnm\" hook-run"),
+        "{}",
+        snippet
+      );
+    }
+    assert_eq!(Shell::from_str("zsh"), Ok(Shell::Zsh));
+  }
+
+  #[test]
+  fn test_read_mode() {
+    let tmp_dir = TempDir::new().unwrap();
+    let base_dir = tmp_dir.path();
+    fs::write(base_dir.join("package.json"), r#"{"name":"a"}"#).unwrap();
+    assert_eq!(HookMode::read(base_dir), HookMode::Warn);
+    fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","syncnm":{"hook":"sync"}}"#,
+    )
+    .unwrap();
+    assert_eq!(HookMode::read(base_dir), HookMode::Sync);
+    fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","syncnm":{"hook":"always"}}"#,
+    )
+    .unwrap();
+    assert_eq!(HookMode::read(base_dir), HookMode::Warn);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_check() {
+    let tmp_dir = TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let cache_dir = tmp_dir.path().join("cache");
+    fs::create_dir_all(&base_dir).unwrap();
+    fs::write(base_dir.join("package.json"), r#"{"name":"a"}"#).unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    let run_check = || check(&base_dir, Some(&cache_dir)).unwrap();
+    // a shim of Node.js in PATH never lets the key be reused
+    let unmodified = match Fingerprint::find_node().is_some_and(Fingerprint::is_shim) {
+      true => Check::InSync,
+      false => Check::Unmodified,
+    };
+    assert_eq!(run_check(), Check::OutOfSync);
+
+    fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+    let lockfile = Lockfile::new(&base_dir).unwrap();
+    let project_root = ProjectRoot::new(&base_dir, Some(lockfile.kind)).unwrap();
+    let key = generate_cache_key(&lockfile, &project_root, &Fingerprint::current()).unwrap();
+    let cache = Cache::new(&base_dir, base_dir.join("node_modules"), Some(&cache_dir)).unwrap();
+    cache.save(key).unwrap();
+    // files modified in the same millisecond as the check are taken as modified after it
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(run_check(), Check::InSync);
+    assert_eq!(run_check(), unmodified);
+
+    // modified without changing the key
+    fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"a","private":true}"#,
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(run_check(), Check::InSync);
+    assert_eq!(run_check(), unmodified);
+
+    fs::write(base_dir.join("yarn.lock"), "").unwrap();
+    assert!(check(&base_dir, Some(&cache_dir)).is_err());
+    fs::remove_file(base_dir.join("yarn.lock")).unwrap();
+
+    fs::write(base_dir.join("package-lock.json"), r#"{"a":1}"#).unwrap();
+    assert_eq!(run_check(), Check::OutOfSync);
+    tmp_dir.close().unwrap();
+  }
+}
@@ -2,8 +2,11 @@ mod cache;
 mod cli;
 mod core;
 mod errors;
+mod hook;
+mod plan;
 mod project;
 mod utils;
+mod watch;
 
 fn main() {
   env_logger::init();
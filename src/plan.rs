@@ -0,0 +1,215 @@
+use std::{
+  fmt::Display,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+  cache::{find_current_key, has_entry, to_cache_dir, CacheOptions, StatCache},
+  core::resolve_cache_key,
+  project::{Lockfile, PackageManager, ProjectRoot},
+  utils::{hash::Hash, process},
+};
+
+/// A step to sync node_modules of a project.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+  /// node_modules is already the cache of `key`
+  Keep { key: Hash },
+  /// put the cache of `key` in the cache directory at node_modules
+  Restore { key: Hash },
+  /// download the cache of `key` from the remote cache and restore it
+  Fetch { key: Hash, remote: String },
+  /// move node_modules into the cache of `key` in use before installing
+  Evacuate { key: Hash, cache: PathBuf },
+  /// install dependencies by the package manager
+  Install { command: String, dir: PathBuf },
+  /// keep node_modules installed as the cache of the lockfile after installing
+  Save { cache_dir: PathBuf },
+  /// upload the cache saved to the remote cache
+  Publish { remote: String },
+}
+
+impl Step {
+  /// Whether the step syncs node_modules by itself, so that the following steps are taken only if it fails.
+  pub fn is_alternative(&self) -> bool {
+    matches!(
+      self,
+      Step::Keep { .. } | Step::Restore { .. } | Step::Fetch { .. }
+    )
+  }
+}
+
+impl Display for Step {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Step::Keep { key } => write!(f, "would keep node_modules of {key}"),
+      Step::Restore { key } => write!(f, "would restore {key}"),
+      Step::Fetch { key, remote } => write!(f, "would download {key} from {remote} and restore it"),
+      Step::Evacuate { cache, .. } => {
+        write!(f, "would move node_modules to {}", cache.to_string_lossy())
+      }
+      Step::Install { command, dir } => {
+        write!(f, "would run `{command}` in {}", dir.to_string_lossy())
+      }
+      Step::Save { cache_dir } => write!(
+        f,
+        "would save node_modules to {} under the key of the lockfile installed",
+        cache_dir.to_string_lossy()
+      ),
+      Step::Publish { remote } => write!(f, "would upload the cache to {remote}"),
+    }
+  }
+}
+
+/// What syncing a project will do, decided from the project and the cache directory before changing anything.
+/// Steps are taken in order, and the ones after an alternative step succeeding are skipped.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Plan {
+  pub base_dir: PathBuf,
+  /// the executable name of the package manager of the project
+  pub package_manager: String,
+  /// the cache key generated from the lockfile now, or `None` without a lockfile
+  pub key: Option<Hash>,
+  /// whether `key` is generated assuming the versions of Node.js and the package manager, as they are not detected
+  /// without spawning processes in a dry run
+  pub approximate: bool,
+  pub steps: Vec<Step>,
+  #[serde(skip)]
+  pub project_root: ProjectRoot,
+  /// the key generated with the stats of its files to record after syncing, or `None` if reused
+  #[serde(skip)]
+  pub stat_cache: Option<StatCache>,
+}
+
+/// Make the plan to sync the project at `base_dir`, only reading the project and the cache directory.
+/// The cache is available only if node_modules exists, as `Cache` is made of it.
+pub fn plan(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  options: &CacheOptions,
+) -> Result<Plan> {
+  let base_dir = base_dir.as_ref().to_path_buf();
+  let lockfile = Lockfile::new(&base_dir);
+  let lockfile_kind = lockfile.as_ref().map(|l| l.kind).ok();
+  let project_root = ProjectRoot::new(&base_dir, lockfile_kind)?;
+  let package_manager: PackageManager = project_root.kind.into();
+  let cache_dir = to_cache_dir(cache_dir)?;
+  let (key, stat_cache) = match lockfile
+    .as_ref()
+    .ok()
+    .and_then(|l| resolve_cache_key(&cache_dir, &base_dir, l, &project_root).ok())
+  {
+    Some((key, stat_cache)) => (Some(key), stat_cache),
+    None => (None, None),
+  };
+  let has_node_modules = base_dir.join("node_modules").is_dir();
+
+  let mut steps = Vec::<Step>::new();
+  let current_key = find_current_key(&cache_dir, &base_dir);
+  if let (Some(key), true) = (&key, has_node_modules) {
+    if !has_entry(&cache_dir, key) {
+      if let Some(remote) = &options.remote {
+        steps.push(Step::Fetch {
+          key: key.clone(),
+          remote: remote.clone(),
+        });
+      }
+    } else if current_key.as_ref() == Some(key) {
+      steps.push(Step::Keep { key: key.clone() });
+    } else {
+      steps.push(Step::Restore { key: key.clone() });
+    }
+  }
+  if !matches!(steps.first(), Some(Step::Keep { .. })) {
+    if let (Some(current_key), true, true) = (current_key, lockfile.is_ok(), has_node_modules) {
+      steps.push(Step::Evacuate {
+        cache: cache_dir.join(current_key.to_string()),
+        key: current_key,
+      });
+    }
+    steps.push(Step::Install {
+      command: format!(
+        "{} {}",
+        package_manager.executable_name, package_manager.install_sub_command
+      ),
+      dir: base_dir.clone(),
+    });
+    steps.push(Step::Save {
+      cache_dir: cache_dir.clone(),
+    });
+    if let Some(remote) = &options.remote {
+      steps.push(Step::Publish {
+        remote: remote.clone(),
+      });
+    }
+  }
+  Ok(Plan {
+    base_dir,
+    package_manager: package_manager.executable_name,
+    approximate: stat_cache.is_some() && !process::is_spawning_allowed(),
+    key,
+    steps,
+    project_root,
+    stat_cache,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::cache::Cache;
+
+  #[test]
+  fn test_plan() {
+    let tmp_dir = TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().join("project");
+    let cache_dir = tmp_dir.path().join("cache");
+    fs::create_dir_all(&base_dir).unwrap();
+    fs::write(base_dir.join("package.json"), r#"{"name":"a"}"#).unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    let options = CacheOptions::default();
+    let install = Step::Install {
+      command: String::from("npm install"),
+      dir: base_dir.clone(),
+    };
+    let save = Step::Save {
+      cache_dir: cache_dir.clone(),
+    };
+
+    // nothing is written to the cache directory
+    let result = plan(&base_dir, Some(&cache_dir), &options).unwrap();
+    assert_eq!(result.steps, vec![install.clone(), save.clone()]);
+    assert!(!cache_dir.exists());
+
+    fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+    let key = result.key.unwrap();
+    let cache = Cache::new(&base_dir, base_dir.join("node_modules"), Some(&cache_dir)).unwrap();
+    cache.save(key.clone()).unwrap();
+    let result = plan(&base_dir, Some(&cache_dir), &options).unwrap();
+    assert_eq!(result.steps, vec![Step::Keep { key: key.clone() }]);
+
+    // the lockfile is updated
+    fs::write(base_dir.join("package-lock.json"), r#"{"a":1}"#).unwrap();
+    let result = plan(&base_dir, Some(&cache_dir), &options).unwrap();
+    assert_eq!(
+      result.steps,
+      vec![
+        Step::Evacuate {
+          key: key.clone(),
+          cache: cache_dir.join(key.to_string()),
+        },
+        install,
+        save,
+      ]
+    );
+    tmp_dir.close().unwrap();
+  }
+}
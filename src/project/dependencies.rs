@@ -5,7 +5,7 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use super::{
-  lib::{is_valid_base_dir, Dependencies},
+  lib::Dependencies,
   package_json::{to_package_json_path, PackageJson},
   package_manager::PackageManagerKind,
 };
@@ -44,7 +44,7 @@ pub struct WorkspacePackage {
 impl WorkspacePackage {
   pub fn new(base_dir: impl AsRef<Path>, kind: PackageManagerKind) -> Result<Self> {
     let base_dir = base_dir.as_ref().to_path_buf();
-    if !is_valid_base_dir(&base_dir) {
+    if !base_dir.is_dir() {
       return Err(Error::InvalidWorkspace(base_dir).into());
     }
     let original = PackageJson::new(&base_dir)?;
@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::{
+  errors::Error,
+  utils::{self, path::to_absolute_path},
+};
+
+use super::{
+  lib::ProjectRoot, lockfile::Lockfile, package_json::to_package_json_path,
+  package_manager::PackageManagerKind, workspaces::has_pnpm_workspace,
+};
+
+/// Find the root of the project which `dir` belongs to, walking up as package managers do.
+/// The nearest package is the root if it has a lockfile, otherwise the nearest ancestor which has a lockfile or pnpm-workspace.yaml and includes the package as a workspace.
+/// The nearest package is also the root if no ancestor includes it, as npm installs a fresh project without a lockfile.
+pub fn find_project_root(dir: impl AsRef<Path>) -> Result<PathBuf> {
+  let dir = to_absolute_path(dir)?;
+  let package_dir = dir
+    .ancestors()
+    .find(|d| to_package_json_path(d).is_file())
+    .ok_or(Error::NoLockfile(dir.clone()))?;
+  for ancestor in package_dir.ancestors() {
+    let kind = match Lockfile::new(ancestor) {
+      Ok(lockfile) => Some(lockfile.kind),
+      Err(error) if matches!(error.downcast_ref(), Some(Error::MultipleLockfiles(..))) => {
+        return Err(error);
+      }
+      Err(_) => has_pnpm_workspace(ancestor).then_some(PackageManagerKind::Pnpm),
+    };
+    let kind = match kind {
+      Some(kind) => kind,
+      None => continue,
+    };
+    if ancestor == package_dir {
+      return Ok(ancestor.to_path_buf());
+    }
+    let is_workspace = ProjectRoot::new(ancestor, Some(kind))
+      .map(|p| p.workspace_dirs().iter().any(|w| w == package_dir))
+      .unwrap_or_default();
+    if is_workspace {
+      return Ok(ancestor.to_path_buf());
+    }
+  }
+  Ok(package_dir.to_path_buf())
+}
+
+/// Find every project which has its own lockfile under `root_dir`, including `root_dir` itself.
+/// `node_modules` and workspace packages of the projects found earlier are skipped.
+pub fn discover_projects(root_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+  let root_dir = root_dir.as_ref().to_path_buf();
+  // parents are always visited before their children because the directories are sorted
+  let dirs = utils::glob::collect(&root_dir, Some(vec![String::from("**/")]), false);
+  let mut projects = Vec::<PathBuf>::new();
+  let mut workspaces = Vec::<PathBuf>::new();
+  for dir in dirs {
+    if workspaces.contains(&dir) {
+      continue;
+    }
+    match Lockfile::new(&dir) {
+      Ok(lockfile) => {
+        if let Ok(project_root) = ProjectRoot::new(&dir, Some(lockfile.kind)) {
+          workspaces.extend(project_root.workspace_dirs());
+        }
+        projects.push(dir);
+      }
+      // let running the project report the error
+      Err(error) if matches!(error.downcast_ref(), Some(Error::MultipleLockfiles(..))) => {
+        projects.push(dir);
+      }
+      Err(_) => {}
+    }
+  }
+  projects
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::test_each;
+
+  struct FindProjectRootTestCase {
+    input: &'static str,
+    expected: Result<&'static str, Error>,
+  }
+
+  fn test_find_project_root_each(case: FindProjectRootTestCase) {
+    let result = find_project_root(case.input);
+    match case.expected {
+      Ok(expected) => assert_eq!(result.unwrap(), to_absolute_path(expected).unwrap()),
+      Err(error) => assert_eq!(result.unwrap_err().downcast::<Error>().unwrap(), error),
+    }
+  }
+
+  test_each!(
+    test_find_project_root,
+    test_find_project_root_each,
+    "root" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/npm",
+      expected: Ok("tests/fixtures/project_root/npm"),
+    },
+    "workspace" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/npm/packages/a",
+      expected: Ok("tests/fixtures/project_root/npm"),
+    },
+    "dir_in_workspace" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/npm/packages/a/src",
+      expected: Ok("tests/fixtures/project_root/npm"),
+    },
+    "dir_in_root" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/npm/src",
+      expected: Ok("tests/fixtures/project_root/npm"),
+    },
+    "not_workspace" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/npm/tools/x",
+      expected: Ok("tests/fixtures/project_root/npm/tools/x"),
+    },
+    "pnpm_workspace" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/pnpm/packages/b",
+      expected: Ok("tests/fixtures/project_root/pnpm"),
+    },
+    "pnpm_workspace_without_lockfile" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root/pnpm_without_lockfile/packages/b",
+      expected: Ok("tests/fixtures/project_root/pnpm_without_lockfile"),
+    },
+    "no_package_json" => FindProjectRootTestCase {
+      input: "tests/fixtures/project_root",
+      expected: Err(Error::NoLockfile(
+        to_absolute_path("tests/fixtures/project_root").unwrap(),
+      )),
+    },
+  );
+
+  #[test]
+  fn test_discover_projects() {
+    let tmp_dir = TempDir::new().unwrap();
+    let files = [
+      ("package.json", r#"{"workspaces": ["packages/*"]}"#),
+      ("package-lock.json", "{}"),
+      ("packages/a/package.json", "{}"),
+      ("packages/a/package-lock.json", "{}"),
+      ("packages/b/package.json", "{}"),
+      ("apps/c/package.json", "{}"),
+      ("apps/c/pnpm-lock.yaml", ""),
+      ("apps/d/package.json", "{}"),
+      ("apps/d/yarn.lock", ""),
+      ("apps/d/package-lock.json", "{}"),
+      ("apps/e/package.json", "{}"),
+      ("node_modules/f/package.json", "{}"),
+      ("node_modules/f/package-lock.json", "{}"),
+      (".cache/g/package.json", "{}"),
+      (".cache/g/package-lock.json", "{}"),
+    ];
+    for (path, contents) in files {
+      let path = tmp_dir.path().join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+
+    assert_eq!(
+      discover_projects(tmp_dir.path())
+        .iter()
+        .map(|p| p.canonicalize().unwrap())
+        .collect::<Vec<_>>(),
+      ["", "apps/c", "apps/d"]
+        .iter()
+        .map(|p| tmp_dir.path().join(p).canonicalize().unwrap())
+        .collect::<Vec<_>>()
+    );
+    tmp_dir.close().unwrap();
+  }
+}
@@ -0,0 +1,148 @@
+use std::{
+  env,
+  fs::{self, File},
+  io::Read,
+  path::{Path, PathBuf},
+  process::Command,
+  sync::OnceLock,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  project::package_manager::Version,
+  utils::{hash::Hashable, process},
+};
+
+/// The environment where node_modules is built, since native addons only work on the same platform and Node.js ABI.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Fingerprint {
+  pub os: String,
+  pub arch: String,
+  pub node: Option<Version>,
+}
+
+impl Fingerprint {
+  /// The version of Node.js is cached once per process, since keys are generated repeatedly by `run --recursive`, the hook and watch.
+  /// It's not cached while Node.js in PATH is a shim of a version manager, which may run another version at any time.
+  pub fn current() -> Self {
+    static DETECTED: OnceLock<Option<Version>> = OnceLock::new();
+    let node = match DETECTED.get() {
+      Some(node) => *node,
+      None => {
+        let node = Self::execute_node_version();
+        // a refused run tells nothing about the environment
+        if process::is_spawning_allowed() && !Self::find_node().is_some_and(Self::is_shim) {
+          let _ = DETECTED.set(node);
+        }
+        node
+      }
+    };
+    Self {
+      os: std::env::consts::OS.to_string(),
+      arch: std::env::consts::ARCH.to_string(),
+      node,
+    }
+  }
+
+  fn execute_node_version() -> Option<Version> {
+    process::output(Command::new("node").arg("--version"))
+      .ok()
+      .filter(|output| output.status.success())
+      .and_then(|output| Version::parse(&String::from_utf8_lossy(&output.stdout)))
+  }
+
+  /// Find the executable of Node.js in PATH, which `current` runs.
+  pub fn find_node() -> Option<PathBuf> {
+    let name = if cfg!(windows) { "node.exe" } else { "node" };
+    env::split_paths(&env::var_os("PATH")?)
+      .map(|dir| dir.join(name))
+      .find(|path| path.is_file())
+  }
+
+  /// Whether the executable of Node.js at `path` is a shim of a version manager such as asdf, mise or volta,
+  /// which runs the version chosen for each project, so that the file doesn't tell the version.
+  pub fn is_shim(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    // mise and volta link their own executables as node, while Homebrew and nvm link node of each version
+    let is_other = fs::canonicalize(path)
+      .ok()
+      .and_then(|p| p.file_stem().map(|stem| stem != "node"))
+      .unwrap_or_default();
+    // asdf and nodenv write scripts
+    let mut head = [0; 2];
+    let is_script = File::open(path)
+      .and_then(|mut file| file.read_exact(&mut head))
+      .is_ok_and(|_| &head == b"#!");
+    is_other || is_script
+  }
+}
+
+impl Hashable for Fingerprint {
+  // the ABI of Node.js changes only in major versions
+  fn to_hash_target(&self) -> Result<impl AsRef<[u8]>> {
+    let node = self
+      .node
+      .map(|v| v.0.to_string())
+      .unwrap_or(String::from("none"));
+    Ok(format!("{}-{}-{}", self.os, self.arch, node))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_generate_hash() {
+    let fingerprint = |node: Option<Version>| Fingerprint {
+      os: String::from("linux"),
+      arch: String::from("x86_64"),
+      node,
+    };
+    let hash = |node: Option<Version>| fingerprint(node).generate_hash().unwrap();
+    assert_eq!(
+      hash(Some(Version(20, 1, 0))),
+      hash(Some(Version(20, 11, 1)))
+    );
+    assert_ne!(hash(Some(Version(20, 1, 0))), hash(Some(Version(18, 1, 0))));
+    assert_ne!(hash(Some(Version(20, 1, 0))), hash(None));
+  }
+
+  #[test]
+  fn test_current() {
+    let current = Fingerprint::current();
+    let spawned = process::count_spawned();
+    assert_eq!(Fingerprint::current(), current);
+    // detected once per process unless Node.js in PATH is a shim
+    if !Fingerprint::find_node().is_some_and(Fingerprint::is_shim) {
+      assert_eq!(process::count_spawned(), spawned);
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_is_shim() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    fs::create_dir_all(dir.join("versions/20/bin")).unwrap();
+    fs::write(dir.join("versions/20/bin/node"), b"\x7fELF").unwrap();
+    fs::write(dir.join("volta-shim"), b"\x7fELF").unwrap();
+    fs::write(
+      dir.join("script"),
+      "#!/usr/bin/env bash\nexec asdf exec node \"$@\"\n",
+    )
+    .unwrap();
+    let link = |original: &str, name: &str| {
+      std::os::unix::fs::symlink(dir.join(original), dir.join(name)).unwrap();
+      dir.join(name)
+    };
+
+    assert!(!Fingerprint::is_shim(dir.join("versions/20/bin/node")));
+    assert!(!Fingerprint::is_shim(link("versions/20/bin/node", "brew")));
+    assert!(Fingerprint::is_shim(link("volta-shim", "volta")));
+    assert!(Fingerprint::is_shim(dir.join("script")));
+    tmp_dir.close().unwrap();
+  }
+}
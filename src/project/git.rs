@@ -0,0 +1,170 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+/// A git repository which a project belongs to, read from files in `.git` without running git.
+/// Worktrees added by `git worktree add` have their own `git_dir` and share `common_dir` with the main worktree.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Repository {
+  /// the directory of HEAD and the index of the worktree
+  pub git_dir: PathBuf,
+  /// the directory of refs and objects shared among worktrees, which identifies the repository
+  pub common_dir: PathBuf,
+}
+
+/// The commit checked out in a worktree.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Head {
+  /// the name of the branch, or `HEAD` if detached
+  pub branch: String,
+  /// the hash of the commit, or empty before the first commit
+  pub commit: String,
+}
+
+impl Repository {
+  /// Find the repository of `dir` walking up to the nearest `.git`, which is a file pointing to the git directory in a worktree.
+  pub fn discover(dir: impl AsRef<Path>) -> Option<Self> {
+    let dot_git = dir
+      .as_ref()
+      .ancestors()
+      .map(|d| d.join(".git"))
+      .find(|p| p.exists())?;
+    if dot_git.is_dir() {
+      let git_dir = normalize(&dot_git);
+      return Some(Self {
+        git_dir: git_dir.clone(),
+        common_dir: git_dir,
+      });
+    }
+    let base_dir = dot_git.parent()?;
+    let git_dir = fs::read_to_string(&dot_git)
+      .ok()?
+      .trim_end()
+      .strip_prefix("gitdir:")
+      .map(|p| base_dir.join(p.trim()))?;
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+      Ok(common_dir) => normalize(&git_dir.join(common_dir.trim_end())),
+      Err(_) => git_dir.clone(),
+    };
+    Some(Self {
+      git_dir: normalize(&git_dir),
+      common_dir,
+    })
+  }
+
+  /// Read the branch and the commit checked out in the worktree.
+  pub fn head(&self) -> Option<Head> {
+    let head = fs::read_to_string(self.git_dir.join("HEAD")).ok()?;
+    let head = head.trim_end();
+    match head.strip_prefix("ref:").map(str::trim) {
+      Some(name) => Some(Head {
+        branch: name.strip_prefix("refs/heads/").unwrap_or(name).to_string(),
+        commit: self.resolve_ref(name).unwrap_or_default(),
+      }),
+      None => Some(Head {
+        branch: String::from("HEAD"),
+        commit: head.to_string(),
+      }),
+    }
+  }
+
+  /// Resolve a ref from a loose file or packed-refs, both of which are shared among worktrees.
+  fn resolve_ref(&self, name: &str) -> Option<String> {
+    if let Ok(commit) = fs::read_to_string(self.common_dir.join(name)) {
+      return Some(commit.trim_end().to_string());
+    }
+    let packed_refs = fs::read_to_string(self.common_dir.join("packed-refs")).ok()?;
+    packed_refs.lines().find_map(|line| {
+      let (commit, packed_name) = line.split_once(' ')?;
+      (packed_name == name).then(|| commit.to_string())
+    })
+  }
+}
+
+/// Resolve `..` and symlinks so that worktrees of the same repository have the same `common_dir`.
+fn normalize(path: &Path) -> PathBuf {
+  fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  /// Lay out a repository at `main` with a worktree at `feature` as `git worktree add` does.
+  fn create_repository(root: &Path) -> (PathBuf, PathBuf) {
+    let main = root.join("main");
+    let feature = root.join("feature");
+    let git_dir = main.join(".git");
+    let worktree_git_dir = git_dir.join("worktrees/feature");
+    fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+    fs::create_dir_all(&worktree_git_dir).unwrap();
+    fs::create_dir_all(feature.join("packages/a")).unwrap();
+    fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+    fs::write(git_dir.join("refs/heads/main"), "1234abcd\n").unwrap();
+    fs::write(
+      git_dir.join("packed-refs"),
+      "# pack-refs with: peeled fully-peeled sorted\n5678ef01 refs/heads/feature\n",
+    )
+    .unwrap();
+    fs::write(
+      feature.join(".git"),
+      format!("gitdir: {}\n", worktree_git_dir.display()),
+    )
+    .unwrap();
+    fs::write(worktree_git_dir.join("commondir"), "../..\n").unwrap();
+    fs::write(worktree_git_dir.join("HEAD"), "ref: refs/heads/feature\n").unwrap();
+    (main, feature)
+  }
+
+  #[test]
+  fn test_discover() {
+    let tmp_dir = TempDir::new().unwrap();
+    let root = fs::canonicalize(tmp_dir.path()).unwrap();
+    let (main, feature) = create_repository(&root);
+
+    let repository = Repository::discover(&main).unwrap();
+    assert_eq!(repository.git_dir, main.join(".git"));
+    assert_eq!(repository.common_dir, main.join(".git"));
+    let worktree = Repository::discover(feature.join("packages/a")).unwrap();
+    assert_eq!(
+      worktree.git_dir,
+      main.join(".git").join("worktrees").join("feature")
+    );
+    assert_eq!(worktree.common_dir, repository.common_dir);
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_head() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (main, feature) = create_repository(tmp_dir.path());
+
+    let head = |dir: &Path| Repository::discover(dir).unwrap().head().unwrap();
+    assert_eq!(
+      head(&main),
+      Head {
+        branch: String::from("main"),
+        commit: String::from("1234abcd"),
+      }
+    );
+    assert_eq!(
+      head(&feature),
+      Head {
+        branch: String::from("feature"),
+        commit: String::from("5678ef01"),
+      }
+    );
+    fs::write(main.join(".git/HEAD"), "1234abcd\n").unwrap();
+    assert_eq!(
+      head(&main),
+      Head {
+        branch: String::from("HEAD"),
+        commit: String::from("1234abcd"),
+      }
+    );
+    tmp_dir.close().unwrap();
+  }
+}
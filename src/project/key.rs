@@ -0,0 +1,244 @@
+use std::{
+  collections::BTreeMap,
+  fmt::Display,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+  errors::to_error,
+  project::{
+    dependencies::PackageDependencies, to_lockfile_paths, to_package_json_path,
+    to_pnpm_workspace_paths, Fingerprint, Lockfile, ProjectRoot,
+  },
+  utils::hash::{Hash, Hashable},
+};
+
+/// Dependencies of a workspace package feeding the cache key.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WorkspaceInputs {
+  pub name: String,
+  pub version: String,
+  pub dependencies: PackageDependencies,
+}
+
+/// Everything the cache key is generated from, recorded with the cache to explain why the key changes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct KeyInputs {
+  /// the file name of the lockfile, which doesn't depend on where the project is
+  pub lockfile: String,
+  pub lockfile_hash: Hash,
+  /// dependencies in package.json of the project root
+  pub root: PackageDependencies,
+  /// workspace packages keyed by their names
+  pub workspaces: BTreeMap<String, WorkspaceInputs>,
+  pub project_hash: Hash,
+  pub fingerprint: Fingerprint,
+  pub fingerprint_hash: Hash,
+}
+
+impl KeyInputs {
+  pub fn new(
+    lockfile: &Lockfile,
+    project: &ProjectRoot,
+    fingerprint: &Fingerprint,
+  ) -> Result<Self> {
+    let dependencies = project.to_dependencies();
+    Ok(Self {
+      lockfile: lockfile
+        .path()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default(),
+      lockfile_hash: lockfile.generate_hash()?,
+      root: dependencies.root,
+      workspaces: dependencies
+        .workspaces
+        .into_iter()
+        .map(|(key, (name, version, dependencies))| {
+          (
+            key,
+            WorkspaceInputs {
+              name,
+              version,
+              dependencies,
+            },
+          )
+        })
+        .collect(),
+      project_hash: project.generate_hash()?,
+      fingerprint: fingerprint.clone(),
+      fingerprint_hash: fingerprint.generate_hash()?,
+    })
+  }
+
+  /// The cache key formatted as `<lockfile hash>-<project hash>-<fingerprint>`.
+  pub fn to_key(&self) -> Hash {
+    Hash(format!(
+      "{}-{}-{}",
+      self.lockfile_hash, self.project_hash, self.fingerprint_hash
+    ))
+  }
+
+  /// List what differs from the `recorded` inputs, down to each dependency.
+  pub fn diff(&self, recorded: &KeyInputs) -> Result<Vec<Change>> {
+    let current = serde_json::to_value(self).map_err(to_error)?;
+    let recorded = serde_json::to_value(recorded).map_err(to_error)?;
+    let mut changes = Vec::<Change>::new();
+    diff_values(String::new(), Some(&recorded), Some(&current), &mut changes);
+    Ok(changes)
+  }
+}
+
+/// The files read to generate the key of the project at `base_dir`, whose modification may change the key.
+pub fn to_input_files(
+  base_dir: impl AsRef<Path>,
+  lockfile: &Lockfile,
+  project: &ProjectRoot,
+) -> Vec<PathBuf> {
+  let base_dir = base_dir.as_ref();
+  [
+    to_package_json_path(base_dir),
+    lockfile.path().to_path_buf(),
+  ]
+  .into_iter()
+  .chain(
+    to_pnpm_workspace_paths(base_dir)
+      .into_iter()
+      .filter(|p| p.is_file()),
+  )
+  .chain(
+    project
+      .workspace_dirs()
+      .into_iter()
+      .map(to_package_json_path),
+  )
+  .collect()
+}
+
+/// Files which change the key when added at `base_dir`, as another lockfile fails and pnpm-workspace.yaml adds workspaces.
+pub fn to_input_candidates(base_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+  to_lockfile_paths(&base_dir)
+    .into_iter()
+    .chain(to_pnpm_workspace_paths(&base_dir))
+    .collect()
+}
+
+/// A value of the inputs which differs between the recorded and the current ones, at a path joined by `.`.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Change {
+  pub path: String,
+  /// the recorded value, or `None` if added
+  pub recorded: Option<Value>,
+  /// the current value, or `None` if removed
+  pub current: Option<Value>,
+}
+
+impl Display for Change {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let to_string = |value: &Option<Value>| {
+      value
+        .as_ref()
+        .map_or(String::from("(none)"), |v| v.to_string())
+    };
+    write!(
+      f,
+      "{}: {} -> {}",
+      self.path,
+      to_string(&self.recorded),
+      to_string(&self.current)
+    )
+  }
+}
+
+fn diff_values(
+  path: String,
+  recorded: Option<&Value>,
+  current: Option<&Value>,
+  changes: &mut Vec<Change>,
+) {
+  match (recorded, current) {
+    (Some(Value::Object(recorded)), Some(Value::Object(current))) => {
+      let mut keys = recorded.keys().chain(current.keys()).collect::<Vec<_>>();
+      keys.sort();
+      keys.dedup();
+      for key in keys {
+        let path = if path.is_empty() {
+          key.to_string()
+        } else {
+          format!("{}.{}", path, key)
+        };
+        diff_values(path, recorded.get(key), current.get(key), changes);
+      }
+    }
+    (recorded, current) if recorded != current => changes.push(Change {
+      path,
+      recorded: recorded.cloned(),
+      current: current.cloned(),
+    }),
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn test_new() -> Result<()> {
+    let base_dir = PathBuf::from("tests/fixtures/core");
+    let lockfile = Lockfile::new(&base_dir)?;
+    let project = ProjectRoot::new(&base_dir, Some(lockfile.kind))?;
+    let fingerprint = Fingerprint::current();
+    let inputs = KeyInputs::new(&lockfile, &project, &fingerprint)?;
+    assert_eq!(inputs.lockfile, "bun.lockb");
+    assert_eq!(inputs.project_hash, project.generate_hash()?);
+    assert_eq!(
+      inputs.to_key(),
+      Hash(format!(
+        "{}-{}-{}",
+        lockfile.generate_hash()?,
+        project.generate_hash()?,
+        fingerprint.generate_hash()?
+      ))
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_diff_values() {
+    let mut changes = Vec::<Change>::new();
+    diff_values(
+      String::new(),
+      Some(&json!({ "a": 1, "b": { "c": "^1.0.0", "d": "1" } })),
+      Some(&json!({ "a": 1, "b": { "c": "^2.0.0", "e": "1" } })),
+      &mut changes,
+    );
+    assert_eq!(
+      changes,
+      vec![
+        Change {
+          path: String::from("b.c"),
+          recorded: Some(json!("^1.0.0")),
+          current: Some(json!("^2.0.0")),
+        },
+        Change {
+          path: String::from("b.d"),
+          recorded: Some(json!("1")),
+          current: None,
+        },
+        Change {
+          path: String::from("b.e"),
+          recorded: None,
+          current: Some(json!("1")),
+        },
+      ]
+    );
+    assert_eq!(changes[0].to_string(), r#"b.c: "^1.0.0" -> "^2.0.0""#);
+  }
+}
@@ -1,4 +1,8 @@
-use std::{collections::BTreeMap, hash::Hash, path::Path};
+use std::{
+  collections::BTreeMap,
+  hash::Hash,
+  path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use regex::Regex;
@@ -14,22 +18,11 @@ use crate::{
 use super::{
   dependencies::{PackageDependencies, WorkspacePackage},
   package_json::{to_package_json_path, PackageJson},
-  package_manager::PackageManagerKind,
+  package_manager::{PackageManager, PackageManagerKind, Version},
 };
 
 pub type Dependencies = BTreeMap<String, String>;
 
-/// Ignore `node_modules` directory
-pub fn is_valid_base_dir(base_dir: impl AsRef<Path>) -> bool {
-  const IGNORED: [&str; 1] = ["node_modules"];
-  let path = base_dir.as_ref().to_path_buf();
-  if !path.is_dir() {
-    return false;
-  }
-  let path = path.to_string_lossy();
-  !IGNORED.iter().any(|ignored| path.contains(ignored))
-}
-
 #[derive(Serialize, Deserialize, Hash, Clone, PartialEq, Debug, Default)]
 pub struct ProjectRoot {
   original: PackageJson,
@@ -38,32 +31,16 @@ pub struct ProjectRoot {
   workspaces: BTreeMap<String, WorkspacePackage>,
 }
 
+/// Dependencies of the project hashed into the cache key, with workspace packages as `(name, version, dependencies)`.
 #[derive(Serialize, Deserialize, Debug)]
-struct ProjectDependencies {
-  root: PackageDependencies,
-  workspaces: BTreeMap<String, (String, String, PackageDependencies)>,
+pub struct ProjectDependencies {
+  pub root: PackageDependencies,
+  pub workspaces: BTreeMap<String, (String, String, PackageDependencies)>,
 }
 
 impl Hashable for ProjectRoot {
   fn to_hash_target(&self) -> Result<impl AsRef<[u8]>> {
-    let base = &ProjectDependencies {
-      root: self.root.clone(),
-      workspaces: self
-        .workspaces
-        .iter()
-        .map(|(k, v)| {
-          (
-            k.to_owned(),
-            (
-              v.original.name.clone().unwrap_or_default(),
-              v.original.version.clone().unwrap_or_default(),
-              v.dependencies.clone(),
-            ),
-          )
-        })
-        .collect::<BTreeMap<_, _>>(),
-    };
-    serde_json::to_string(base).map_err(to_error)
+    serde_json::to_string(&self.to_dependencies()).map_err(to_error)
   }
 }
 
@@ -76,7 +53,12 @@ impl ProjectRoot {
         original: original.clone(),
         kind,
         root: PackageDependencies::new(original.clone()),
-        workspaces: Self::resolve_workspaces(&base_dir, kind, original.workspaces),
+        workspaces: Self::resolve_workspaces(
+          &base_dir,
+          kind,
+          Self::resolve_package_manager_version(&original, kind),
+          original.workspaces,
+        )?,
       }
       .validate_package_json_fields(&base_dir)
     } else {
@@ -84,26 +66,55 @@ impl ProjectRoot {
     }
   }
 
+  pub fn to_dependencies(&self) -> ProjectDependencies {
+    ProjectDependencies {
+      root: self.root.clone(),
+      workspaces: self
+        .workspaces
+        .iter()
+        .map(|(k, v)| {
+          (
+            k.to_owned(),
+            (
+              v.original.name.clone().unwrap_or_default(),
+              v.original.version.clone().unwrap_or_default(),
+              v.dependencies.clone(),
+            ),
+          )
+        })
+        .collect::<BTreeMap<_, _>>(),
+    }
+  }
+
+  pub fn workspace_dirs(&self) -> Vec<PathBuf> {
+    self
+      .workspaces
+      .values()
+      .map(|w| w.base_dir.clone())
+      .collect()
+  }
+
   fn resolve_workspaces(
     base_dir: impl AsRef<Path>,
     kind: PackageManagerKind,
+    version: Option<Version>,
     patterns: Option<Vec<String>>,
-  ) -> BTreeMap<String, WorkspacePackage> {
-    let workspaces = Workspaces::new(base_dir.as_ref().to_path_buf(), kind, patterns);
+  ) -> Result<BTreeMap<String, WorkspacePackage>> {
+    let workspaces = Workspaces::new(base_dir.as_ref().to_path_buf(), kind, version, patterns)?;
     let mut workspace_map = BTreeMap::<String, WorkspacePackage>::new();
     for path in workspaces.packages.iter() {
       if let Ok(w) =
         WorkspacePackage::new(path, kind).and_then(|w| w.validate_package_json_fields(path))
       {
         let (name, fallback) = w.get_package_name();
-        if workspace_map.get(&name).is_none() {
+        if !workspace_map.contains_key(&name) {
           workspace_map.insert(name, w);
         } else {
           workspace_map.insert(fallback, w);
         }
       };
     }
-    workspace_map
+    Ok(workspace_map)
   }
 
   fn resolve_package_manager_kind(
@@ -143,6 +154,20 @@ impl ProjectRoot {
     }
   }
 
+  /// Read the version from `packageManager` field only if it points to the same package manager as `kind`.
+  /// Bun is not supported by corepack, but the field is still useful to pin its version.
+  fn resolve_package_manager_version(
+    original: &PackageJson,
+    kind: PackageManagerKind,
+  ) -> Option<Version> {
+    let package_manager = original.packageManager.as_ref()?;
+    let (name, version) = package_manager.split_once('@')?;
+    let executable_name = PackageManager::from(kind).executable_name;
+    (name == executable_name)
+      .then(|| Version::parse(version))
+      .flatten()
+  }
+
   fn validate_package_json_fields(self, base_dir: impl AsRef<Path>) -> Result<Self> {
     match self.kind {
       PackageManagerKind::Yarn
@@ -154,11 +179,7 @@ impl ProjectRoot {
           .is_empty()
           && !self.original.private.unwrap_or_default() =>
       {
-        Err(
-          Error::InvalidPackageJsonPrivateForYarn(to_package_json_path(&base_dir))
-            .log_error(None)
-            .into(),
-        )
+        Err(Error::InvalidPackageJsonPrivateForYarn(to_package_json_path(&base_dir)).into())
       }
       _ => Ok(self),
     }
@@ -167,8 +188,7 @@ impl ProjectRoot {
 
 #[cfg(test)]
 mod tests {
-  use std::{fs, path::PathBuf};
-  use tempfile::TempDir;
+  use std::path::PathBuf;
 
   use super::*;
   use crate::{btree_map, test_each, test_each_serial, utils::path::to_absolute_path};
@@ -207,40 +227,6 @@ mod tests {
     },
   );
 
-  struct IsValidBaseDirTestCase {
-    input: &'static str,
-    expected: bool,
-  }
-
-  fn test_is_valid_base_dir_each(case: IsValidBaseDirTestCase) {
-    let temp_dir = TempDir::new().unwrap();
-    let base_dir = temp_dir.path().join(case.input);
-    fs::create_dir_all(&base_dir).unwrap();
-    assert_eq!(is_valid_base_dir(&base_dir), case.expected);
-    temp_dir.close().unwrap();
-  }
-
-  test_each_serial!(
-    test_is_valid_base_dir,
-    test_is_valid_base_dir_each,
-    "valid_0" => IsValidBaseDirTestCase{
-      input: "./",
-      expected: true,
-    },
-    "valid_1" => IsValidBaseDirTestCase{
-      input: "./foo/bar/",
-      expected: true,
-    },
-    "invalid_0" => IsValidBaseDirTestCase{
-      input: "./node_modules",
-      expected: false,
-    },
-    "invalid_1" => IsValidBaseDirTestCase{
-      input: "./foo/bar/packages/node_modules",
-      expected: false,
-    },
-  );
-
   struct ResolvePackageManagerKindTestCase {
     input: (&'static str, Option<PackageManagerKind>),
     expected: Option<PackageManagerKind>,
@@ -306,6 +292,43 @@ mod tests {
     },
   );
 
+  struct ResolvePackageManagerVersionTestCase {
+    input: (&'static str, PackageManagerKind),
+    expected: Option<Version>,
+  }
+
+  fn test_resolve_package_manager_version_each(case: ResolvePackageManagerVersionTestCase) {
+    let original = PackageJson {
+      packageManager: Some(String::from(case.input.0)),
+      ..Default::default()
+    };
+    assert_eq!(
+      ProjectRoot::resolve_package_manager_version(&original, case.input.1),
+      case.expected,
+    );
+  }
+
+  test_each!(
+    test_resolve_package_manager_version,
+    test_resolve_package_manager_version_each,
+    "bun" => ResolvePackageManagerVersionTestCase {
+      input: ("bun@1.1.0", PackageManagerKind::Bun),
+      expected: Some(Version(1, 1, 0)),
+    },
+    "pnpm_with_hash" => ResolvePackageManagerVersionTestCase {
+      input: ("pnpm@9.0.0-alpha.4+sha256.2dfc103b0859426dc338ab2796cad7bf83ffb92be0fdd79f65f26ffeb5114ce2", PackageManagerKind::Pnpm),
+      expected: Some(Version(9, 0, 0)),
+    },
+    "other_package_manager" => ResolvePackageManagerVersionTestCase {
+      input: ("yarn@4.1.0", PackageManagerKind::Bun),
+      expected: None,
+    },
+    "without_version" => ResolvePackageManagerVersionTestCase {
+      input: ("bun", PackageManagerKind::Bun),
+      expected: None,
+    },
+  );
+
   struct NewTestCase {
     input: (PathBuf, Option<PackageManagerKind>),
     expected: Result<ProjectRoot>,
@@ -459,7 +482,8 @@ mod tests {
         );
         Ok(ProjectRoot {
           original: PackageJson {
-            workspaces: Some(vec![String::from("packages/*")]),
+            packageManager: Some(String::from("bun@1.1.0")),
+            workspaces: Some(vec![String::from("packages/*"), String::from("!packages/c")]),
             devDependencies: Some(dev_dependencies.clone()),
             ..Default::default()
           },
@@ -478,13 +502,48 @@ mod tests {
               base_dir: to_absolute_path("tests/fixtures/workspaces/bun/packages/a").unwrap(),
               ..Default::default()
             },
+          ),
+        })
+      }
+    },
+    "bun_legacy" => NewTestCase {
+      input: (
+        PathBuf::from("tests/fixtures/workspaces/bun_legacy"),
+        Some(PackageManagerKind::Bun),
+      ),
+      expected: {
+        let dev_dependencies = btree_map!(
+          String::from("typescript") => String::from("^5.3.3"),
+        );
+        Ok(ProjectRoot {
+          original: PackageJson {
+            packageManager: Some(String::from("bun@1.0.0")),
+            workspaces: Some(vec![String::from("packages/*"), String::from("!packages/c")]),
+            devDependencies: Some(dev_dependencies.clone()),
+            ..Default::default()
+          },
+          kind: PackageManagerKind::Bun,
+          root: PackageDependencies {
+            dev_dependencies,
+            ..Default::default()
+          },
+          workspaces: btree_map!(
+            String::from("@bun/a") => WorkspacePackage {
+              original: PackageJson {
+                name: Some(String::from("@bun/a")),
+                ..Default::default()
+              },
+              kind: PackageManagerKind::Bun,
+              base_dir: to_absolute_path("tests/fixtures/workspaces/bun_legacy/packages/a").unwrap(),
+              ..Default::default()
+            },
             String::from("@bun/c") => WorkspacePackage {
               original: PackageJson {
                 name: Some(String::from("@bun/c")),
                 ..Default::default()
               },
               kind: PackageManagerKind::Bun,
-              base_dir: to_absolute_path("tests/fixtures/workspaces/bun/packages/c").unwrap(),
+              base_dir: to_absolute_path("tests/fixtures/workspaces/bun_legacy/packages/c").unwrap(),
               ..Default::default()
             },
           ),
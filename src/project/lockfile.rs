@@ -22,12 +22,48 @@ pub struct Lockfile {
 }
 
 impl Hashable for Lockfile {
-  fn to_hash_target(&self) -> Result<Vec<u8>> {
+  fn to_hash_target(&self) -> Result<impl AsRef<[u8]>> {
     fs::read(&self.path).map_err(to_error)
   }
 }
 
+/// Contents of a lockfile to restore it when a package manager rewrites it.
+#[derive(Debug, PartialEq)]
+pub struct LockfileBackup {
+  path: PathBuf,
+  contents: Vec<u8>,
+}
+
+impl LockfileBackup {
+  /// Write the original contents back only if changed.
+  pub fn restore(&self) -> Result<()> {
+    if fs::read(&self.path).ok().as_ref() == Some(&self.contents) {
+      return Ok(());
+    }
+    fs::write(&self.path, &self.contents).map_err(to_error)
+  }
+}
+
+/// Every path where a lockfile of any package manager may be at `base_dir`.
+pub fn to_lockfile_paths(base_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+  PackageManagerKind::iter()
+    .flat_map(|kind| kind.to_lockfile_names())
+    .map(|name| base_dir.as_ref().join(name))
+    .collect()
+}
+
 impl Lockfile {
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn backup(&self) -> Result<LockfileBackup> {
+    Ok(LockfileBackup {
+      path: self.path.clone(),
+      contents: fs::read(&self.path).map_err(to_error)?,
+    })
+  }
+
   pub fn new(base_dir: impl AsRef<Path>) -> Result<Self> {
     let base_dir = base_dir.as_ref().to_path_buf();
     Lockfile::try_to_read_lockfile(base_dir).map(|(kind, path)| Self { kind, path })
@@ -186,4 +222,16 @@ mod tests {
       expected: "qubt74wrmoca7vuruv3xnfaaclzvmvwm",
     },
   );
+
+  #[test]
+  fn test_backup() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let path = tmp_dir.path().join("package-lock.json");
+    fs::write(&path, "foo").unwrap();
+    let backup = Lockfile::new(tmp_dir.path()).unwrap().backup().unwrap();
+    fs::write(&path, "bar").unwrap();
+    backup.restore().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "foo");
+    tmp_dir.close().unwrap();
+  }
 }
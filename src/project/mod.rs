@@ -1,10 +1,20 @@
 mod dependencies;
+mod discovery;
+mod fingerprint;
+mod git;
+mod key;
 mod lib;
 mod lockfile;
 mod package_json;
 mod package_manager;
 mod workspaces;
 
+pub use crate::project::discovery::{discover_projects, find_project_root};
+pub use crate::project::fingerprint::Fingerprint;
+pub use crate::project::git::Repository;
+pub use crate::project::key::{to_input_candidates, to_input_files, Change, KeyInputs};
 pub use crate::project::lib::ProjectRoot;
-pub use crate::project::lockfile::Lockfile;
-pub use crate::project::package_manager::PackageManager;
+pub use crate::project::lockfile::{to_lockfile_paths, Lockfile, LockfileBackup};
+pub use crate::project::package_json::to_package_json_path;
+pub use crate::project::package_manager::{PackageManager, Version};
+pub use crate::project::workspaces::{to_pnpm_workspace_paths, to_workspace_read_dirs};
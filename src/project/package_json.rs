@@ -1,6 +1,7 @@
 use std::{
   fs,
   hash::Hash,
+  io,
   path::{Path, PathBuf},
 };
 
@@ -37,7 +38,10 @@ impl PackageJson {
     match contents {
       Ok(contents) => serde_json::from_str::<Self>(&contents)
         .map_err(|error| Error::Parse(vec![file_path], error.to_string()).into()),
-      Err(_) => Err(Error::NoEntry(vec![file_path]).into()),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => {
+        Err(Error::NoEntry(vec![file_path]).into())
+      }
+      Err(error) => Err(Error::NotAccessible(file_path).log_debug(error).into()),
     }
   }
 }
@@ -1,10 +1,18 @@
-use std::{path::Path, process::Command, vec};
+use std::{
+  collections::HashMap,
+  fmt::Display,
+  path::Path,
+  process::Command,
+  sync::{Mutex, OnceLock},
+  vec,
+};
 
 use anyhow::Result;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use strum_macros::EnumIter;
 
-use crate::errors::Error;
+use crate::{errors::Error, utils::process};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct PackageManager {
@@ -60,8 +68,9 @@ impl PackageManager {
       .map_err(|error| to_error(error.to_string()))?;
 
     // TODO: stream
+    // stdout is left for results of commands, which may be read by scripts
     let text = String::from_utf8_lossy(&output.stdout);
-    println!("{}", text);
+    eprintln!("{}", text);
 
     if output.status.success() {
       Ok(())
@@ -92,4 +101,97 @@ impl PackageManagerKind {
     let package_manager: PackageManager = self.into();
     package_manager.corepack_name
   }
+
+  /// Detect the version of the package manager installed in the environment by `<executable> --version`.
+  /// The result is cached once per process, since the projects of `run --recursive`, the hook and watch resolve it repeatedly.
+  pub fn detect_version(self, base_dir: impl AsRef<Path>) -> Option<Version> {
+    static DETECTED: OnceLock<Mutex<HashMap<PackageManagerKind, Option<Version>>>> =
+      OnceLock::new();
+    let mut detected = DETECTED
+      .get_or_init(Default::default)
+      .lock()
+      .unwrap_or_else(|error| error.into_inner());
+    if let Some(version) = detected.get(&self) {
+      return *version;
+    }
+    let version = self.execute_version(base_dir);
+    // a refused run tells nothing about the environment
+    if process::is_spawning_allowed() {
+      detected.insert(self, version);
+    }
+    version
+  }
+
+  fn execute_version(self, base_dir: impl AsRef<Path>) -> Option<Version> {
+    let package_manager: PackageManager = self.into();
+    let output = process::output(
+      Command::new(&package_manager.executable_name)
+        .arg("--version")
+        .current_dir(base_dir),
+    )
+    .ok()?;
+    if !output.status.success() {
+      return None;
+    }
+    Version::parse(&String::from_utf8_lossy(&output.stdout))
+  }
+}
+
+#[derive(Serialize, Deserialize, Hash, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Version(pub u64, pub u64, pub u64);
+
+impl Display for Version {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{}.{}", self.0, self.1, self.2)
+  }
+}
+
+impl Version {
+  /// Parse the first `major.minor.patch` in the given text, ignoring pre-release and build metadata.
+  pub fn parse(text: &str) -> Option<Self> {
+    let regex = Regex::new(r"(\d+)\.(\d+)\.(\d+)").ok()?;
+    let captures = regex.captures(text)?;
+    let to_number = |i: usize| captures.get(i)?.as_str().parse::<u64>().ok();
+    Some(Self(to_number(1)?, to_number(2)?, to_number(3)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_each;
+
+  struct VersionParseTestCase {
+    input: &'static str,
+    expected: Option<Version>,
+  }
+
+  fn test_version_parse_each(case: VersionParseTestCase) {
+    assert_eq!(Version::parse(case.input), case.expected);
+  }
+
+  test_each!(
+    test_version_parse,
+    test_version_parse_each,
+    "plain" => VersionParseTestCase {
+      input: "1.1.0",
+      expected: Some(Version(1, 1, 0)),
+    },
+    "with_new_line" => VersionParseTestCase {
+      input: "10.2.4\n",
+      expected: Some(Version(10, 2, 4)),
+    },
+    "pre_release" => VersionParseTestCase {
+      input: "9.0.0-alpha.4+sha256.2dfc103b0859426dc338ab2796cad7bf83ffb92be0fdd79f65f26ffeb5114ce2",
+      expected: Some(Version(9, 0, 0)),
+    },
+    "missing_patch" => VersionParseTestCase {
+      input: "8.1",
+      expected: None,
+    },
+    "empty" => VersionParseTestCase {
+      input: "",
+      expected: None,
+    },
+  );
 }
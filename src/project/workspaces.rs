@@ -1,4 +1,5 @@
 use std::{
+  cmp::Ordering,
   fs,
   path::{Path, PathBuf},
 };
@@ -8,7 +9,13 @@ use serde::{Deserialize, Serialize};
 
 use crate::{errors::Error, utils};
 
-use super::package_manager::PackageManagerKind;
+use super::{
+  package_json::PackageJson,
+  package_manager::{PackageManagerKind, Version},
+};
+
+/// The first version of Bun which resolves `workspaces` with full glob syntax including negate patterns.
+const BUN_FULL_GLOB_VERSION: Version = Version(1, 1, 0);
 
 #[derive(Debug)]
 pub struct Workspaces {
@@ -16,21 +23,98 @@ pub struct Workspaces {
 }
 
 impl Workspaces {
-  pub fn new(base_dir: PathBuf, kind: PackageManagerKind, patterns: Option<Vec<String>>) -> Self {
-    Self {
+  pub fn new(
+    base_dir: PathBuf,
+    kind: PackageManagerKind,
+    version: Option<Version>,
+    patterns: Option<Vec<String>>,
+  ) -> Result<Self> {
+    Ok(Self {
       packages: match &kind {
-        PackageManagerKind::Npm => Workspaces::resolve_npm_workspaces(base_dir, patterns),
-        PackageManagerKind::Bun => Workspaces::resolve_bun_workspaces(base_dir, patterns),
+        PackageManagerKind::Npm => Workspaces::resolve_npm_workspaces(base_dir, patterns)?,
+        PackageManagerKind::Bun => Workspaces::resolve_bun_workspaces(base_dir, version, patterns),
         PackageManagerKind::Yarn => Workspaces::resolve_yarn_workspaces(base_dir, patterns),
         PackageManagerKind::Pnpm => Workspaces::resolve_pnpm_workspaces(base_dir),
       },
-    }
+    })
   }
 
-  /// Support full glob syntax including negate patterns.
+  /// Port of [@npmcli/map-workspaces](https://github.com/npm/map-workspaces/blob/v2.0.4/lib/index.js) used by npm CLI.
+  /// Each pattern matches directories containing package.json in order, and a negate pattern removes exactly the directories it matches from the workspaces registered so far.
+  /// Workspaces with the same name are invalid, and fail as map-workspaces throws.
   /// - [workspaces | npm Docs](https://docs.npmjs.com/cli/v7/using-npm/workspaces)
-  fn resolve_npm_workspaces(base_dir: PathBuf, patterns: Option<Vec<String>>) -> Vec<PathBuf> {
-    utils::glob::collect(&base_dir, patterns, true)
+  fn resolve_npm_workspaces(
+    base_dir: PathBuf,
+    patterns: Option<Vec<String>>,
+  ) -> Result<Vec<PathBuf>> {
+    // keep the order in which names are seen first
+    let mut seen = Vec::<(String, Vec<PathBuf>)>::new();
+    for pattern in patterns.unwrap_or_default() {
+      let (pattern, negate) = Self::parse_npm_pattern(pattern);
+      let mut matches = utils::glob::collect(&base_dir, Some(vec![pattern]), false);
+      matches.sort_by(|a, b| Self::compare_as_locale(a, b));
+      for path in matches {
+        let name = match PackageJson::new(&path) {
+          Ok(package_json) => package_json.name.unwrap_or(
+            path
+              .file_name()
+              .unwrap_or(path.as_os_str())
+              .to_string_lossy()
+              .to_string(),
+          ),
+          // a directory without package.json is not a workspace, while the other errors fail as map-workspaces does
+          Err(error) if matches!(error.downcast_ref::<Error>(), Some(Error::NoEntry(_))) => {
+            continue
+          }
+          Err(error) => return Err(error),
+        };
+        let index = match seen.iter().position(|(n, _)| *n == name) {
+          Some(index) => index,
+          None => {
+            seen.push((name, vec![]));
+            seen.len() - 1
+          }
+        };
+        let paths = &mut seen[index].1;
+        if negate {
+          paths.retain(|p| *p != path);
+        } else if !paths.contains(&path) {
+          paths.push(path);
+        }
+      }
+    }
+    let mut packages = Vec::<PathBuf>::new();
+    for (name, mut paths) in seen {
+      match paths.len() {
+        0 => {}
+        1 => packages.append(&mut paths),
+        _ => return Err(Error::DuplicatedWorkspaceName(name, paths).into()),
+      }
+    }
+    Ok(packages)
+  }
+
+  /// Strip leading `!`, `./` and `/`, and append `/` to match directories only, as map-workspaces does.
+  fn parse_npm_pattern(pattern: String) -> (String, bool) {
+    let (mut pattern, negate) = utils::glob::parse_negate(pattern, true);
+    while let Some(p) = pattern
+      .strip_prefix("./")
+      .or_else(|| pattern.strip_prefix('/'))
+    {
+      pattern = p.to_string();
+    }
+    if !pattern.ends_with('/') {
+      pattern.push('/');
+    }
+    (pattern, negate)
+  }
+
+  /// Approximate `String.prototype.localeCompare` with `en` locale, which map-workspaces uses to sort matches.
+  fn compare_as_locale(a: &Path, b: &Path) -> Ordering {
+    let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+    a.to_lowercase()
+      .cmp(&b.to_lowercase())
+      .then_with(|| b.cmp(&a))
   }
 
   /// Evaluate the given patterns individually and return the paths of matched entries in case of yarn.
@@ -39,11 +123,18 @@ impl Workspaces {
     utils::glob::collect(&base_dir, patterns, false)
   }
 
-  /// Full glob syntax is not supported yet.
-  /// - https://bun.sh/docs/install/workspaces
+  /// Support full glob syntax including negate patterns since `BUN_FULL_GLOB_VERSION`, and evaluate the given patterns individually in older versions.
+  /// The version is taken from `packageManager` field in package.json, or detected from the installed Bun. The latest behavior is assumed if neither is available.
+  /// - [Workspaces | Bun](https://bun.sh/docs/install/workspaces)
   /// - https://github.com/oven-sh/bun/issues/1918
-  fn resolve_bun_workspaces(base_dir: PathBuf, patterns: Option<Vec<String>>) -> Vec<PathBuf> {
-    utils::glob::collect(&base_dir, patterns, false)
+  fn resolve_bun_workspaces(
+    base_dir: PathBuf,
+    version: Option<Version>,
+    patterns: Option<Vec<String>>,
+  ) -> Vec<PathBuf> {
+    let version = version.or_else(|| PackageManagerKind::Bun.detect_version(&base_dir));
+    let enable_negate = version.is_none_or(|v| v >= BUN_FULL_GLOB_VERSION);
+    utils::glob::collect(&base_dir, patterns, enable_negate)
   }
 
   /// Support full glob syntax including negate patterns.
@@ -57,6 +148,28 @@ impl Workspaces {
   }
 }
 
+pub fn has_pnpm_workspace(base_dir: impl AsRef<Path>) -> bool {
+  PnpmWorkspace::to_pnpm_workspace(base_dir)
+    .iter()
+    .any(|p| p.is_file())
+}
+
+/// Every path where pnpm-workspace.yaml may be at `base_dir`.
+pub fn to_pnpm_workspace_paths(base_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+  PnpmWorkspace::to_pnpm_workspace(base_dir).to_vec()
+}
+
+/// Directories read to resolve workspaces of the project at `base_dir`, where new workspaces appear.
+/// The patterns are taken from pnpm-workspace.yaml if any, otherwise from `workspaces` in package.json.
+pub fn to_workspace_read_dirs(base_dir: impl AsRef<Path>) -> Vec<PathBuf> {
+  let base_dir = base_dir.as_ref().to_path_buf();
+  let patterns = match has_pnpm_workspace(&base_dir) {
+    true => PnpmWorkspace::new(&base_dir).ok().and_then(|p| p.packages),
+    false => PackageJson::new(&base_dir).ok().and_then(|p| p.workspaces),
+  };
+  utils::glob::collect_read_dirs(&base_dir, patterns)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct PnpmWorkspace {
   packages: Option<Vec<String>>,
@@ -89,21 +202,28 @@ impl PnpmWorkspace {
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::{test_each_serial, utils::path::clean_path_separator};
+  use crate::{test_each, test_each_serial, utils::path::clean_path_separator};
+  use tempfile::TempDir;
 
   struct NewTestCase {
-    input: (PathBuf, PackageManagerKind, Option<Vec<String>>),
+    input: (
+      PathBuf,
+      PackageManagerKind,
+      Option<Version>,
+      Option<Vec<String>>,
+    ),
     expected: Workspaces,
   }
 
   fn test_new_each(case: NewTestCase) {
     let base_dir = clean_path_separator(case.input.0);
-    let patterns = case.input.2.map(|p| {
+    let patterns = case.input.3.map(|p| {
       p.iter()
         .map(|p| clean_path_separator(p).to_string_lossy().to_string())
         .collect::<Vec<_>>()
     });
-    let workspaces = Workspaces::new(base_dir.clone(), case.input.1, patterns);
+    let workspaces =
+      Workspaces::new(base_dir.clone(), case.input.1, case.input.2, patterns).unwrap();
     assert_eq!(
       workspaces
         .packages
@@ -129,6 +249,7 @@ mod tests {
       input: (
         PathBuf::from("tests/fixtures/workspaces/npm"),
         PackageManagerKind::Npm,
+        None,
         Some(vec![
           String::from("packages/*"),
           String::from("!packages/c"),
@@ -137,7 +258,6 @@ mod tests {
       expected: Workspaces {
         packages: vec![
           PathBuf::from("./packages/a"),
-          PathBuf::from("./packages/b"),
         ],
       },
     },
@@ -145,6 +265,7 @@ mod tests {
       input: (
         PathBuf::from("tests/fixtures/workspaces/yarn"),
         PackageManagerKind::Yarn,
+        None,
         Some(vec![
           String::from("packages/*"),
           String::from("!packages/c"),
@@ -163,6 +284,7 @@ mod tests {
         PathBuf::from("tests/fixtures/workspaces/pnpm"),
         PackageManagerKind::Pnpm,
         None,
+        None,
       ),
       expected: Workspaces {
         packages: vec![
@@ -175,6 +297,41 @@ mod tests {
       input: (
         PathBuf::from("tests/fixtures/workspaces/bun"),
         PackageManagerKind::Bun,
+        Some(Version(1, 1, 0)),
+        Some(vec![
+          String::from("packages/*"),
+          String::from("!packages/c"),
+        ]),
+      ),
+      expected: Workspaces {
+        packages: vec![
+          PathBuf::from("./packages/a"),
+          PathBuf::from("./packages/b"),
+        ],
+      },
+    },
+    "bun_globstar" => NewTestCase {
+      input: (
+        PathBuf::from("tests/fixtures/workspaces/bun"),
+        PackageManagerKind::Bun,
+        Some(Version(1, 1, 0)),
+        Some(vec![
+          String::from("**/packages/*"),
+          String::from("!**/c"),
+        ]),
+      ),
+      expected: Workspaces {
+        packages: vec![
+          PathBuf::from("./packages/a"),
+          PathBuf::from("./packages/b"),
+        ],
+      },
+    },
+    "bun_legacy" => NewTestCase {
+      input: (
+        PathBuf::from("tests/fixtures/workspaces/bun_legacy"),
+        PackageManagerKind::Bun,
+        Some(Version(1, 0, 0)),
         Some(vec![
           String::from("packages/*"),
           String::from("!packages/c"),
@@ -189,4 +346,174 @@ mod tests {
       },
     },
   );
+
+  struct ResolveNpmWorkspacesTestCase {
+    patterns: Vec<&'static str>,
+    /// directories and `name` fields of package.json in them, or `None` if package.json doesn't exist
+    file_system: Vec<(&'static str, Option<&'static str>)>,
+    /// the paths of workspaces, or the name duplicated
+    expected: Result<Vec<&'static str>, &'static str>,
+  }
+
+  fn test_resolve_npm_workspaces_each(case: ResolveNpmWorkspacesTestCase) {
+    let tmp_dir = TempDir::new().unwrap();
+    for (dir, name) in case.file_system.iter() {
+      let dir = tmp_dir.path().join(dir);
+      fs::create_dir_all(&dir).unwrap();
+      if let Some(name) = name {
+        let contents = if name.is_empty() {
+          String::from("{}")
+        } else {
+          format!("{{\"name\": \"{name}\"}}")
+        };
+        fs::write(dir.join("package.json"), contents).unwrap();
+      }
+    }
+    let result = Workspaces::resolve_npm_workspaces(
+      tmp_dir.path().to_path_buf(),
+      Some(case.patterns.iter().map(|p| p.to_string()).collect()),
+    );
+    match case.expected {
+      Ok(expected) => assert_eq!(
+        result
+          .unwrap()
+          .iter()
+          .map(|p| p.canonicalize().unwrap())
+          .collect::<Vec<_>>(),
+        expected
+          .iter()
+          .map(|p| tmp_dir.path().join(p).canonicalize().unwrap())
+          .collect::<Vec<_>>()
+      ),
+      Err(expected) => match result.unwrap_err().downcast::<Error>().unwrap() {
+        Error::DuplicatedWorkspaceName(name, _) => assert_eq!(name, expected),
+        error => panic!("{:?}", error),
+      },
+    }
+    tmp_dir.close().unwrap();
+  }
+
+  // derived from the test cases of @npmcli/map-workspaces
+  test_each!(
+    test_resolve_npm_workspaces,
+    test_resolve_npm_workspaces_each,
+    "simple" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["./packages/a", "./././packages/b"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", Some("b"))],
+      expected: Ok(vec!["packages/a", "packages/b"]),
+    },
+    "leading_slash" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["/packages/*", "!/packages/b"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", Some("b"))],
+      expected: Ok(vec!["packages/a"]),
+    },
+    "glob" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*"],
+      file_system: vec![("packages/b", Some("")), ("packages/a", Some(""))],
+      expected: Ok(vec!["packages/a", "packages/b"]),
+    },
+    "missing_package_json" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", None)],
+      expected: Ok(vec!["packages/a"]),
+    },
+    "negate" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*", "!packages/b"],
+      file_system: vec![
+        ("packages/a", Some("a")),
+        ("packages/b", Some("b")),
+        ("packages/c", Some("c")),
+      ],
+      expected: Ok(vec!["packages/a", "packages/c"]),
+    },
+    "negate_before_include" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["!packages/b", "packages/*"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", Some("b"))],
+      // the name is registered in order of appearance even by a negate pattern
+      expected: Ok(vec!["packages/b", "packages/a"]),
+    },
+    "include_after_negate" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*", "!packages/a", "packages/a"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", Some("b"))],
+      expected: Ok(vec!["packages/a", "packages/b"]),
+    },
+    "double_negate" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["!!packages/b"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", Some("b"))],
+      expected: Ok(vec!["packages/b"]),
+    },
+    "negate_glob" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*", "!packages/*/"],
+      file_system: vec![("packages/a", Some("a")), ("packages/b", Some("b"))],
+      expected: Ok(vec![]),
+    },
+    "negate_is_not_prefix" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/**", "!packages/a"],
+      file_system: vec![
+        ("packages/a", Some("a")),
+        ("packages/a/nested", Some("nested")),
+        ("packages/b", Some("b")),
+      ],
+      expected: Ok(vec!["packages/a/nested", "packages/b"]),
+    },
+    "ignore_node_modules" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/**"],
+      file_system: vec![
+        ("packages/a", Some("a")),
+        ("packages/a/node_modules/b", Some("b")),
+        ("node_modules/c", Some("c")),
+      ],
+      expected: Ok(vec!["packages/a"]),
+    },
+    "duplicated_name" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*"],
+      file_system: vec![
+        ("packages/a", Some("x")),
+        ("packages/b", Some("x")),
+        ("packages/c", Some("c")),
+      ],
+      expected: Err("x"),
+    },
+    "duplicated_name_resolved_by_negate" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*", "!packages/b"],
+      file_system: vec![
+        ("packages/a", Some("x")),
+        ("packages/b", Some("x")),
+      ],
+      expected: Ok(vec!["packages/a"]),
+    },
+    "default_name" => ResolveNpmWorkspacesTestCase {
+      patterns: vec!["packages/*", "apps/*"],
+      file_system: vec![
+        ("packages/a", Some("")),
+        ("apps/a", Some("")),
+        ("apps/b", Some("")),
+      ],
+      // the names default to the directory names
+      expected: Err("a"),
+    },
+  );
+
+  #[test]
+  fn test_resolve_npm_workspaces_unreadable() {
+    let tmp_dir = TempDir::new().unwrap();
+    let patterns = Some(vec![String::from("packages/*")]);
+    fs::create_dir_all(tmp_dir.path().join("packages/a")).unwrap();
+    fs::write(tmp_dir.path().join("packages/a/package.json"), "{").unwrap();
+    let error = Workspaces::resolve_npm_workspaces(tmp_dir.path().to_path_buf(), patterns.clone())
+      .unwrap_err()
+      .downcast::<Error>()
+      .unwrap();
+    assert!(matches!(error, Error::Parse(..)), "{:?}", error);
+
+    // package.json which is not a file can't be read
+    fs::remove_file(tmp_dir.path().join("packages/a/package.json")).unwrap();
+    fs::create_dir_all(tmp_dir.path().join("packages/a/package.json")).unwrap();
+    let error = Workspaces::resolve_npm_workspaces(tmp_dir.path().to_path_buf(), patterns)
+      .unwrap_err()
+      .downcast::<Error>()
+      .unwrap();
+    assert!(matches!(error, Error::NotAccessible(_)), "{:?}", error);
+    tmp_dir.close().unwrap();
+  }
 }
@@ -1,9 +1,11 @@
 use std::{
   fs,
+  io::Write,
   path::{Path, PathBuf},
 };
 
 use anyhow::Result;
+use tempfile::NamedTempFile;
 
 use crate::{
   errors::{to_error, Error},
@@ -62,12 +64,96 @@ pub fn create_symlink(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()
   std::os::windows::fs::symlink_dir(&from, &to).map_err(to_error)
 }
 
+/// Check if `a` and `b` are on the same filesystem, looking up the nearest existing ancestors of paths not created yet.
+#[cfg(unix)]
+pub fn is_same_device(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+  use std::os::unix::fs::MetadataExt;
+  let device = |path: &Path| {
+    path
+      .ancestors()
+      .find_map(|p| fs::metadata(p).ok())
+      .map(|m| m.dev())
+  };
+  match (device(a.as_ref()), device(b.as_ref())) {
+    (Some(a), Some(b)) => a == b,
+    _ => false,
+  }
+}
+
+#[cfg(windows)]
+pub fn is_same_device(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+  use std::path::Component;
+  let prefix = |path: &Path| match to_absolute_path(path).ok()?.components().next() {
+    Some(Component::Prefix(prefix)) => Some(prefix.as_os_str().to_ascii_lowercase()),
+    _ => None,
+  };
+  prefix(a.as_ref()) == prefix(b.as_ref())
+}
+
+/// Create a symlink at `link` as it is, which may be relative to the parent of `link`.
+#[cfg(unix)]
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+  std::os::unix::fs::symlink(original, link).map_err(to_error)
+}
+
+#[cfg(windows)]
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+  let link = link.as_ref();
+  let is_dir = link
+    .parent()
+    .map(|parent| parent.join(&original).is_dir())
+    .unwrap_or_default();
+  if is_dir {
+    std::os::windows::fs::symlink_dir(original, link).map_err(to_error)
+  } else {
+    std::os::windows::fs::symlink_file(original, link).map_err(to_error)
+  }
+}
+
+#[cfg(unix)]
+pub fn remove_symlink(path: impl AsRef<Path>) -> Result<()> {
+  fs::remove_file(path).map_err(to_error)
+}
+
+#[cfg(windows)]
+pub fn remove_symlink(path: impl AsRef<Path>) -> Result<()> {
+  // a symlink to a directory is removed as a directory on Windows
+  fs::remove_dir(path).map_err(to_error)
+}
+
+/// Create a temporary file in `dir` with a unique name among threads, to be persisted as a file of the usual permissions.
+#[cfg(unix)]
+pub fn create_temp_file_in(dir: impl AsRef<Path>) -> Result<NamedTempFile> {
+  use std::os::unix::fs::PermissionsExt;
+  // a temporary file is only readable by the owner by default, while the file persisted may be shared
+  tempfile::Builder::new()
+    .permissions(fs::Permissions::from_mode(0o666))
+    .tempfile_in(dir)
+    .map_err(to_error)
+}
+
+#[cfg(windows)]
+pub fn create_temp_file_in(dir: impl AsRef<Path>) -> Result<NamedTempFile> {
+  NamedTempFile::new_in(dir).map_err(to_error)
+}
+
 pub fn read_to_string(file_path: impl AsRef<Path>) -> Result<String> {
   fs::read_to_string(file_path).map_err(to_error)
 }
 
-pub fn write(file_path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
-  fs::write(&file_path, &contents).map_err(to_error)
+/// Write to a temporary file in the same directory and rename it, so that the file is never left half-written.
+/// The temporary file is named uniquely, since threads of a process may write the same file at once.
+pub fn write_atomic(file_path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+  let file_path = file_path.as_ref();
+  let parent = match file_path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent,
+    _ => Path::new("."),
+  };
+  let mut file = NamedTempFile::new_in(parent).map_err(to_error)?;
+  file.write_all(contents.as_ref()).map_err(to_error)?;
+  file.as_file().sync_all().map_err(to_error)?;
+  file.persist(file_path).map_err(to_error)?;
+  Ok(())
 }
 
 #[cfg(test)]
@@ -200,6 +286,33 @@ mod tests {
     assert!(result.is_ok());
   }
 
+  #[test]
+  fn test_write_atomic() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let file_path = tmp_dir.path().join("foo.json");
+    write_atomic(&file_path, "foo").unwrap();
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "foo");
+    // overwrite
+    write_atomic(&file_path, "bar").unwrap();
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "bar");
+    // no temporary file is left
+    assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+
+    // threads of a process write at once
+    let contents = (0..8)
+      .map(|i| i.to_string().repeat(4096))
+      .collect::<Vec<_>>();
+    let file_path = &file_path;
+    std::thread::scope(|scope| {
+      for contents in contents.iter() {
+        scope.spawn(move || write_atomic(file_path, contents).unwrap());
+      }
+    });
+    assert!(contents.contains(&fs::read_to_string(file_path).unwrap()));
+    assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+    tmp_dir.close().unwrap();
+  }
+
   // TODO* fix this test for windows
   #[test]
   #[cfg(not(windows))]
@@ -269,4 +382,21 @@ mod tests {
     fs::remove_dir_all(&base_dir).unwrap();
     assert!(result.is_ok());
   }
+
+  #[test]
+  fn test_is_same_device() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path();
+    assert!(is_same_device(dir, dir.join("not_exists/node_modules")));
+    // skipped unless another filesystem is mounted at /dev/shm
+    #[cfg(unix)]
+    if let Ok(other_dir) = tempfile::TempDir::new_in("/dev/shm") {
+      use std::os::unix::fs::MetadataExt;
+      let device = |path: &Path| fs::metadata(path).unwrap().dev();
+      assert_eq!(
+        is_same_device(dir, other_dir.path()),
+        device(dir) == device(other_dir.path())
+      );
+    }
+  }
 }
@@ -1,70 +1,94 @@
-use std::path::PathBuf;
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
 
-use glob::glob;
 use itertools::Itertools;
 
 use crate::errors::Error;
-use crate::utils::path::{run_in_base_dir, to_absolute_path};
+use crate::utils::path::to_absolute_path;
 
 const NEGATE: char = '!';
+const GLOBSTAR: &str = "**";
+const ESCAPE: char = '\\';
+const SEPARATOR: char = '/';
 
+/// Directories which are never traversed nor matched.
+const IGNORED_DIRS: [&str; 1] = ["node_modules"];
+
+/// Collect entries under `base_dir` matched with `patterns` without changing the current directory.
 pub fn collect(
   base_dir: &PathBuf,
   patterns: Option<Vec<String>>,
   enable_negate: bool,
 ) -> Vec<PathBuf> {
-  let collect = || {
-    let mut entries = Vec::<PathBuf>::new();
-    for pattern in patterns.unwrap_or_default() {
-      if let (Some(matched), negate) = resolve_glob(pattern, enable_negate) {
-        if negate {
-          entries = entries
-            .iter()
-            .filter(|entry| !matched.iter().any(|p| entry.starts_with(p)))
-            .cloned()
-            .collect::<Vec<_>>();
-        } else {
-          entries.extend(matched);
-        }
+  let base_dir = match to_absolute_path(base_dir) {
+    Ok(base_dir) => base_dir,
+    Err(_) => return vec![],
+  };
+  let mut entries = Vec::<PathBuf>::new();
+  for pattern in patterns.unwrap_or_default() {
+    if let (Some(matched), negate) = resolve_glob(&base_dir, pattern, enable_negate) {
+      if negate {
+        entries = entries
+          .iter()
+          .filter(|entry| !matched.iter().any(|p| entry.starts_with(p)))
+          .cloned()
+          .collect::<Vec<_>>();
+      } else {
+        entries.extend(matched);
       }
     }
-    let result = entries
-      .iter()
-      .unique()
-      .filter_map(|entry| to_absolute_path(entry).ok())
-      .collect::<Vec<_>>();
-    result
-  };
+  }
+  entries
+    .iter()
+    .unique()
+    .filter_map(|entry| to_absolute_path(base_dir.join(entry)).ok())
+    .collect::<Vec<_>>()
+}
 
-  run_in_base_dir(base_dir, collect, None)
+/// Collect directories under `base_dir` read to match `patterns`, where entries to be matched would appear.
+/// A missing literal segment leaves the directory which it would be created in. Negated patterns are skipped as they only exclude entries.
+pub fn collect_read_dirs(base_dir: &PathBuf, patterns: Option<Vec<String>>) -> Vec<PathBuf> {
+  let base_dir = match to_absolute_path(base_dir) {
+    Ok(base_dir) => base_dir,
+    Err(_) => return vec![],
+  };
+  patterns
+    .unwrap_or_default()
+    .into_iter()
+    .map(|pattern| parse_negate(pattern, true))
+    .filter(|(pattern, negate)| !negate && !Path::new(pattern).has_root())
+    .flat_map(|(pattern, _)| Pattern::new(&pattern))
+    .flat_map(|p| p.read_dirs(&base_dir))
+    .unique()
+    .map(|dir| base_dir.join(dir))
+    .collect::<Vec<_>>()
 }
 
-fn resolve_glob(pattern: String, enable_negate: bool) -> (Option<Vec<PathBuf>>, bool) {
+/// Resolve `pattern` into paths relative to `base_dir`.
+fn resolve_glob(
+  base_dir: &Path,
+  pattern: String,
+  enable_negate: bool,
+) -> (Option<Vec<PathBuf>>, bool) {
   let (pattern, negate) = parse_negate(pattern, enable_negate);
-  match glob(&pattern) {
-    Ok(entries) => {
-      let entries = entries
-        .filter_map(|entry| {
-          if let Err(error) = &entry {
-            Error::NotAccessible(error.path().to_path_buf())
-              .log_debug(error)
-              .log_warn(None);
-          }
-          entry.ok()
-        })
-        .collect::<Vec<_>>();
-      (Some(entries), negate)
-    }
-    Err(error) => {
-      Error::InvalidGlobPattern(error.msg).log_warn(None);
-      (None, negate)
-    }
+  if Path::new(&pattern).has_root() {
+    Error::InvalidGlobPattern("absolute path is not allowed").log_warn(None);
+    return (None, negate);
   }
+  let entries = Pattern::new(&pattern)
+    .iter()
+    .flat_map(|p| p.walk(base_dir))
+    .sorted()
+    .dedup()
+    .collect::<Vec<_>>();
+  (Some(entries), negate)
 }
 
 /// npm CLI [uses mimimatch](https://github.com/npm/cli/blob/latest/lib/workspaces/get-workspaces.js) for glob pattern matching, and supports "negates" patterns.
 /// See minimatch"s [docs](https://github.com/isaacs/minimatch?tab=readme-ov-file#nonegate) and [implementation](https://github.com/isaacs/minimatch/blob/ef8f2672bdbbf6a632ea815636659fb31b5169aa/src/index.ts#L736-L750) for details.
-fn parse_negate(pattern: String, enable_negate: bool) -> (String, bool) {
+pub fn parse_negate(pattern: String, enable_negate: bool) -> (String, bool) {
   if !enable_negate {
     return (pattern, false);
   }
@@ -79,9 +103,403 @@ fn parse_negate(pattern: String, enable_negate: bool) -> (String, bool) {
   (pattern[counts..].to_string(), negate)
 }
 
+/// Expand braces like `{a,b}`, `{1..3}` and `{a..c}` as [brace-expansion](https://github.com/juliangruber/brace-expansion) used by minimatch does.
+/// Braces without any comma or range are left as they are.
+fn expand_braces(pattern: &str) -> Vec<String> {
+  let chars = pattern.chars().collect_vec();
+  let (mut depth, mut start, mut i) = (0, 0, 0);
+  while i < chars.len() {
+    match chars[i] {
+      ESCAPE => i += 1,
+      '{' => {
+        if depth == 0 {
+          start = i;
+        }
+        depth += 1;
+      }
+      '}' if depth > 0 => {
+        depth -= 1;
+        if depth == 0 {
+          let prefix = chars[..start].iter().collect::<String>();
+          let body = chars[start + 1..i].iter().collect::<String>();
+          let suffix = chars[i + 1..].iter().collect::<String>();
+          return match expand_brace_body(&body) {
+            Some(alternatives) => alternatives
+              .iter()
+              .flat_map(|alternative| expand_braces(&format!("{prefix}{alternative}{suffix}")))
+              .collect_vec(),
+            None => {
+              let literal = chars[..=i].iter().collect::<String>();
+              expand_braces(&suffix)
+                .iter()
+                .map(|s| format!("{literal}{s}"))
+                .collect_vec()
+            }
+          };
+        }
+      }
+      _ => {}
+    }
+    i += 1;
+  }
+  vec![pattern.to_string()]
+}
+
+fn expand_brace_body(body: &str) -> Option<Vec<String>> {
+  let (mut depth, mut escaped) = (0, false);
+  let mut alternatives = vec![String::new()];
+  for char in body.chars() {
+    match char {
+      _ if escaped => escaped = false,
+      ESCAPE => escaped = true,
+      '{' => depth += 1,
+      '}' => depth -= 1,
+      ',' if depth == 0 => {
+        alternatives.push(String::new());
+        continue;
+      }
+      _ => {}
+    }
+    if let Some(last) = alternatives.last_mut() {
+      last.push(char);
+    }
+  }
+  if alternatives.len() > 1 {
+    return Some(alternatives);
+  }
+  let (from, to) = body.split_once("..")?;
+  if let (Ok(from), Ok(to)) = (from.parse::<i64>(), to.parse::<i64>()) {
+    let range = if from <= to {
+      (from..=to).collect_vec()
+    } else {
+      (to..=from).rev().collect_vec()
+    };
+    return Some(range.iter().map(|n| n.to_string()).collect_vec());
+  }
+  let (from, to) = (from.chars().collect_vec(), to.chars().collect_vec());
+  match (&from[..], &to[..]) {
+    (&[from], &[to]) if from.is_ascii_alphabetic() && to.is_ascii_alphabetic() => {
+      let range = if from <= to {
+        (from..=to).collect_vec()
+      } else {
+        (to..=from).rev().collect_vec()
+      };
+      Some(range.iter().map(|c| c.to_string()).collect_vec())
+    }
+    _ => None,
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Char(char),
+  /// `?`
+  AnyChar,
+  /// `*`
+  AnyString,
+  /// `[...]`
+  Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+  /// `**`
+  GlobStar,
+  Literal(String),
+  Wildcard(Vec<Token>),
+}
+
+impl Segment {
+  fn new(segment: &str) -> Self {
+    if segment == GLOBSTAR {
+      return Segment::GlobStar;
+    }
+    let tokens = Self::tokenize(segment);
+    if tokens.iter().all(|t| matches!(t, Token::Char(_))) {
+      Segment::Literal(
+        tokens
+          .iter()
+          .filter_map(|t| match t {
+            Token::Char(c) => Some(c),
+            _ => None,
+          })
+          .collect(),
+      )
+    } else {
+      Segment::Wildcard(tokens)
+    }
+  }
+
+  fn tokenize(segment: &str) -> Vec<Token> {
+    let chars = segment.chars().collect_vec();
+    let mut tokens = Vec::<Token>::new();
+    let mut i = 0;
+    while i < chars.len() {
+      match chars[i] {
+        ESCAPE if i + 1 < chars.len() => {
+          i += 1;
+          tokens.push(Token::Char(chars[i]));
+        }
+        '?' => tokens.push(Token::AnyChar),
+        '*' => {
+          // consecutive stars in a segment are the same as a single star
+          if tokens.last() != Some(&Token::AnyString) {
+            tokens.push(Token::AnyString);
+          }
+        }
+        '[' => match Self::tokenize_class(&chars[i + 1..]) {
+          Some((token, consumed)) => {
+            tokens.push(token);
+            i += consumed;
+          }
+          // an unclosed bracket is treated as a literal
+          None => tokens.push(Token::Char('[')),
+        },
+        c => tokens.push(Token::Char(c)),
+      }
+      i += 1;
+    }
+    tokens
+  }
+
+  /// Parse a character class after `[`, and return the token and the number of consumed characters including `]`.
+  fn tokenize_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    if negated {
+      i += 1;
+    }
+    let mut ranges = Vec::<(char, char)>::new();
+    let mut first = true;
+    while i < chars.len() {
+      let mut c = chars[i];
+      if c == ']' && !first {
+        return Some((Token::Class { negated, ranges }, i + 1));
+      }
+      first = false;
+      if c == ESCAPE && i + 1 < chars.len() {
+        i += 1;
+        c = chars[i];
+      }
+      if i + 2 < chars.len() && chars[i + 1] == '-' && chars[i + 2] != ']' {
+        ranges.push((c, chars[i + 2]));
+        i += 3;
+      } else {
+        ranges.push((c, c));
+        i += 1;
+      }
+    }
+    None
+  }
+
+  /// Dotfiles are matched only if the segment starts with `.` explicitly.
+  fn is_match(&self, name: &str) -> bool {
+    match self {
+      Segment::GlobStar => !name.starts_with('.'),
+      Segment::Literal(literal) => literal == name,
+      Segment::Wildcard(tokens) => {
+        if name.starts_with('.') && tokens.first() != Some(&Token::Char('.')) {
+          return false;
+        }
+        Self::is_match_tokens(tokens, &name.chars().collect_vec())
+      }
+    }
+  }
+
+  fn is_match_tokens(tokens: &[Token], chars: &[char]) -> bool {
+    match tokens.split_first() {
+      None => chars.is_empty(),
+      Some((Token::AnyString, rest)) => {
+        (0..=chars.len()).any(|i| Self::is_match_tokens(rest, &chars[i..]))
+      }
+      Some((token, rest)) => match chars.split_first() {
+        Some((c, chars)) => {
+          let matched = match token {
+            Token::Char(expected) => expected == c,
+            Token::AnyChar => true,
+            Token::Class { negated, ranges } => {
+              ranges.iter().any(|(from, to)| from <= c && c <= to) != *negated
+            }
+            Token::AnyString => unreachable!(),
+          };
+          matched && Self::is_match_tokens(rest, chars)
+        }
+        None => false,
+      },
+    }
+  }
+}
+
+/// A glob pattern without braces, which follows the semantics of [minimatch](https://github.com/isaacs/minimatch).
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern {
+  segments: Vec<Segment>,
+  /// A pattern with a trailing separator matches directories only.
+  dir_only: bool,
+}
+
+impl Pattern {
+  fn new(pattern: &str) -> Vec<Self> {
+    #[cfg(windows)]
+    let pattern = &pattern.replace(ESCAPE, &SEPARATOR.to_string());
+    expand_braces(pattern)
+      .iter()
+      .map(|pattern| {
+        let segments = pattern
+          .split(SEPARATOR)
+          .filter(|s| !s.is_empty() && *s != ".")
+          .map(Segment::new)
+          .dedup_by(|a, b| *a == Segment::GlobStar && *b == Segment::GlobStar)
+          .collect_vec();
+        Self {
+          segments,
+          dir_only: pattern.ends_with(SEPARATOR),
+        }
+      })
+      .filter(|p| !p.segments.is_empty())
+      .collect_vec()
+  }
+
+  /// Walk from `base_dir` and return matched paths relative to it.
+  fn walk(&self, base_dir: &Path) -> Vec<PathBuf> {
+    let mut matched = Vec::<PathBuf>::new();
+    self.walk_segments(base_dir, &PathBuf::new(), &self.segments, &mut matched);
+    matched
+  }
+
+  /// Walk from `base_dir` and return directories relative to it whose entries are matched with the pattern.
+  fn read_dirs(&self, base_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::<PathBuf>::new();
+    self.read_dirs_segments(base_dir, &PathBuf::new(), &self.segments, &mut dirs);
+    dirs
+  }
+
+  fn read_dirs_segments(
+    &self,
+    base_dir: &Path,
+    relative: &Path,
+    segments: &[Segment],
+    dirs: &mut Vec<PathBuf>,
+  ) {
+    let Some((segment, rest)) = segments.split_first() else {
+      return;
+    };
+    if !base_dir.join(relative).is_dir() {
+      return;
+    }
+    match segment {
+      Segment::Literal(name) if is_ignored(name) => {}
+      Segment::Literal(name) => {
+        let next = relative.join(name);
+        if base_dir.join(&next).exists() {
+          self.read_dirs_segments(base_dir, &next, rest, dirs);
+        } else {
+          dirs.push(relative.to_path_buf());
+        }
+      }
+      Segment::Wildcard(_) => {
+        dirs.push(relative.to_path_buf());
+        for name in read_dir(&base_dir.join(relative)) {
+          if segment.is_match(&name) {
+            self.read_dirs_segments(base_dir, &relative.join(name), rest, dirs);
+          }
+        }
+      }
+      Segment::GlobStar => {
+        dirs.push(relative.to_path_buf());
+        self.read_dirs_segments(base_dir, relative, rest, dirs);
+        for name in read_dir(&base_dir.join(relative)) {
+          let next = relative.join(&name);
+          let is_dir = fs::symlink_metadata(base_dir.join(&next))
+            .map(|m| m.is_dir())
+            .unwrap_or_default();
+          if is_dir && segment.is_match(&name) {
+            self.read_dirs_segments(base_dir, &next, segments, dirs);
+          }
+        }
+      }
+    }
+  }
+
+  fn walk_segments(
+    &self,
+    base_dir: &Path,
+    relative: &Path,
+    segments: &[Segment],
+    matched: &mut Vec<PathBuf>,
+  ) {
+    let (segment, rest) = match segments.split_first() {
+      Some(s) => s,
+      None => {
+        if !self.dir_only || base_dir.join(relative).is_dir() {
+          matched.push(relative.to_path_buf());
+        }
+        return;
+      }
+    };
+    match segment {
+      Segment::Literal(name) => {
+        let next = relative.join(name);
+        if !is_ignored(name) && base_dir.join(&next).exists() {
+          self.walk_segments(base_dir, &next, rest, matched);
+        }
+      }
+      Segment::Wildcard(_) => {
+        for name in read_dir(&base_dir.join(relative)) {
+          if segment.is_match(&name) {
+            self.walk_segments(base_dir, &relative.join(name), rest, matched);
+          }
+        }
+      }
+      Segment::GlobStar => {
+        // match zero directories
+        self.walk_segments(base_dir, relative, rest, matched);
+        // match one or more directories without following symlinks
+        for name in read_dir(&base_dir.join(relative)) {
+          let next = relative.join(&name);
+          let is_dir = fs::symlink_metadata(base_dir.join(&next))
+            .map(|m| m.is_dir())
+            .unwrap_or_default();
+          if is_dir && segment.is_match(&name) {
+            self.walk_segments(base_dir, &next, segments, matched);
+          }
+        }
+      }
+    }
+  }
+}
+
+fn is_ignored(name: &str) -> bool {
+  IGNORED_DIRS.contains(&name)
+}
+
+/// Return sorted names of entries in `dir` except ignored ones.
+fn read_dir(dir: &Path) -> Vec<String> {
+  if !dir.is_dir() {
+    return vec![];
+  }
+  match fs::read_dir(dir) {
+    Ok(entries) => entries
+      .filter_map(|entry| entry.ok())
+      .map(|entry| entry.file_name().to_string_lossy().to_string())
+      .filter(|name| !is_ignored(name))
+      .sorted()
+      .collect_vec(),
+    Err(error) => {
+      Error::NotAccessible(dir.to_path_buf())
+        .log_debug(error)
+        .log_warn(None);
+      vec![]
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
-  use crate::{test_each, test_each_serial};
+  use crate::test_each;
 
   use super::*;
   use std::fs::File;
@@ -98,8 +516,8 @@ mod tests {
   fn test_collect_each(case: &CollectTestCase) {
     let tmp_dir = TempDir::new().unwrap();
     case.file_system.iter().for_each(|path| {
-      fs::create_dir_all(&tmp_dir.path().join(path.parent().unwrap())).unwrap();
-      File::create(&tmp_dir.path().join(path)).unwrap();
+      fs::create_dir_all(tmp_dir.path().join(path.parent().unwrap())).unwrap();
+      File::create(tmp_dir.path().join(path)).unwrap();
     });
 
     assert_eq!(
@@ -128,7 +546,7 @@ mod tests {
     tmp_dir.close().unwrap();
   }
 
-  test_each_serial! {
+  test_each! {
     test_collect,
     test_collect_each,
     0 => &CollectTestCase {
@@ -205,8 +623,172 @@ mod tests {
       file_system: vec![PathBuf::from("./foo"),PathBuf::from("./bar")],
       expected: vec![],
     },
+    12 => &CollectTestCase {
+      input: (vec!["**/bar"], true),
+      file_system: vec![
+        PathBuf::from("./node_modules/bar/index.js"),
+        PathBuf::from("./foo/node_modules/bar/index.js"),
+        PathBuf::from("./foo/bar/index.js"),
+      ],
+      expected: vec![PathBuf::from("./foo/bar")],
+    },
+    13 => &CollectTestCase {
+      input: (vec!["node_modules/*"], true),
+      file_system: vec![PathBuf::from("./node_modules/foo/index.js")],
+      expected: vec![],
+    },
+    14 => &CollectTestCase {
+      input: (vec!["*", "**/baz"], true),
+      file_system: vec![
+        PathBuf::from("./.foo/baz"),
+        PathBuf::from("./bar"),
+      ],
+      expected: vec![PathBuf::from("./bar")],
+    },
+    15 => &CollectTestCase {
+      input: (vec![".*"], true),
+      file_system: vec![
+        PathBuf::from("./.foo/baz"),
+        PathBuf::from("./bar"),
+      ],
+      expected: vec![PathBuf::from("./.foo")],
+    },
+    16 => &CollectTestCase {
+      input: (vec!["packages/{a,c}"], true),
+      file_system: vec![
+        PathBuf::from("./packages/a/index.js"),
+        PathBuf::from("./packages/b/index.js"),
+        PathBuf::from("./packages/c/index.js"),
+      ],
+      expected: vec![PathBuf::from("./packages/a"), PathBuf::from("./packages/c")],
+    },
+    17 => &CollectTestCase {
+      input: (vec!["packages/p{1..2}", "packages/[!p]*"], true),
+      file_system: vec![
+        PathBuf::from("./packages/p1/index.js"),
+        PathBuf::from("./packages/p2/index.js"),
+        PathBuf::from("./packages/p3/index.js"),
+        PathBuf::from("./packages/q1/index.js"),
+      ],
+      expected: vec![
+        PathBuf::from("./packages/p1"),
+        PathBuf::from("./packages/p2"),
+        PathBuf::from("./packages/q1"),
+      ],
+    },
+    18 => &CollectTestCase {
+      input: (vec!["*/"], true),
+      file_system: vec![PathBuf::from("./foo/bar"), PathBuf::from("./baz")],
+      expected: vec![PathBuf::from("./foo")],
+    },
+    19 => &CollectTestCase {
+      input: (vec!["./foo/*", "foo/**/qux"], true),
+      file_system: vec![PathBuf::from("./foo/bar/baz/qux")],
+      expected: vec![PathBuf::from("./foo/bar"), PathBuf::from("./foo/bar/baz/qux")],
+    },
+    20 => &CollectTestCase {
+      input: (vec!["/foo"], true),
+      file_system: vec![PathBuf::from("./foo")],
+      expected: vec![],
+    },
+  }
+
+  struct ExpandBracesTestCase {
+    input: &'static str,
+    expected: Vec<&'static str>,
+  }
+
+  test_each!(
+    test_expand_braces,
+    |case: ExpandBracesTestCase| {
+      assert_eq!(expand_braces(case.input), case.expected);
+    },
+    0 => ExpandBracesTestCase {
+      input: "foo",
+      expected: vec!["foo"],
+    },
+    1 => ExpandBracesTestCase {
+      input: "{a,b}/c",
+      expected: vec!["a/c", "b/c"],
+    },
+    2 => ExpandBracesTestCase {
+      input: "{a,b{c,d}}",
+      expected: vec!["a", "bc", "bd"],
+    },
+    3 => ExpandBracesTestCase {
+      input: "{a}/{b,c}",
+      expected: vec!["{a}/b", "{a}/c"],
+    },
+    4 => ExpandBracesTestCase {
+      input: "p{3..1}",
+      expected: vec!["p3", "p2", "p1"],
+    },
+    5 => ExpandBracesTestCase {
+      input: "{a..c}",
+      expected: vec!["a", "b", "c"],
+    },
+    6 => ExpandBracesTestCase {
+      input: "\\{a,b}",
+      expected: vec!["\\{a,b}"],
+    },
+  );
+
+  struct SegmentIsMatchTestCase {
+    input: (&'static str, &'static str),
+    expected: bool,
   }
 
+  test_each!(
+    test_segment_is_match,
+    |case: SegmentIsMatchTestCase| {
+      assert_eq!(Segment::new(case.input.0).is_match(case.input.1), case.expected);
+    },
+    0 => SegmentIsMatchTestCase {
+      input: ("foo", "foo"),
+      expected: true,
+    },
+    1 => SegmentIsMatchTestCase {
+      input: ("f?o", "foo"),
+      expected: true,
+    },
+    2 => SegmentIsMatchTestCase {
+      input: ("*", ".foo"),
+      expected: false,
+    },
+    3 => SegmentIsMatchTestCase {
+      input: (".f*", ".foo"),
+      expected: true,
+    },
+    4 => SegmentIsMatchTestCase {
+      input: ("[a-c]*", "bar"),
+      expected: true,
+    },
+    5 => SegmentIsMatchTestCase {
+      input: ("[^a-c]*", "bar"),
+      expected: false,
+    },
+    6 => SegmentIsMatchTestCase {
+      input: ("[]]", "]"),
+      expected: true,
+    },
+    7 => SegmentIsMatchTestCase {
+      input: ("[foo", "[foo"),
+      expected: true,
+    },
+    8 => SegmentIsMatchTestCase {
+      input: ("\\*", "foo"),
+      expected: false,
+    },
+    9 => SegmentIsMatchTestCase {
+      input: ("\\*", "*"),
+      expected: true,
+    },
+    10 => SegmentIsMatchTestCase {
+      input: ("**", ".foo"),
+      expected: false,
+    },
+  );
+
   struct ParseNegateTestCase {
     input: (&'static str, bool),
     expected: (&'static str, bool),
@@ -249,4 +831,36 @@ mod tests {
       expected: ("foo!!!!!!!!!!bar", false),
     },
   );
+
+  #[test]
+  fn test_collect_read_dirs() {
+    let tmp_dir = TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().to_path_buf();
+    for dir in ["packages/a/src", "packages/b", "apps/x/y", "node_modules/z"] {
+      fs::create_dir_all(base_dir.join(dir)).unwrap();
+    }
+    let patterns = |patterns: &[&str]| Some(patterns.iter().map(|p| p.to_string()).collect());
+    let read_dirs = |patterns| {
+      collect_read_dirs(&base_dir, patterns)
+        .iter()
+        .map(|p| p.strip_prefix(&base_dir).unwrap().to_path_buf())
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+      read_dirs(patterns(&["packages/*", "!packages/b"])),
+      vec![PathBuf::from("packages")]
+    );
+    assert_eq!(
+      read_dirs(patterns(&["apps/**", "tools/a", "node_modules/*"])),
+      vec![
+        PathBuf::from("apps"),
+        PathBuf::from("apps/x"),
+        PathBuf::from("apps/x/y"),
+        PathBuf::new(),
+      ]
+    );
+    assert!(read_dirs(None).is_empty());
+    tmp_dir.close().unwrap();
+  }
 }
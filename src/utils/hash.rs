@@ -1,4 +1,9 @@
-use std::fmt::{Debug, Display};
+use std::{
+  fmt::{Debug, Display},
+  fs::File,
+  io,
+  path::Path,
+};
 
 use anyhow::Result;
 use data_encoding::BASE32_NOPAD;
@@ -25,11 +30,22 @@ pub trait Hashable {
       Ok(bytes) => {
         let mut generator = Sha256::new();
         generator.update(bytes);
-        let raw_hash = generator.finalize();
-        let hash = BASE32_NOPAD.encode(&raw_hash[..20]).to_lowercase();
-        Ok(Hash(hash))
+        Ok(to_hash(generator))
       }
       Err(error) => Err(to_error(error)),
     }
   }
 }
+
+/// Hash contents of a file without reading the whole file into memory.
+pub fn hash_file(file_path: impl AsRef<Path>) -> Result<Hash> {
+  let mut file = File::open(file_path).map_err(to_error)?;
+  let mut generator = Sha256::new();
+  io::copy(&mut file, &mut generator).map_err(to_error)?;
+  Ok(to_hash(generator))
+}
+
+fn to_hash(generator: Sha256) -> Hash {
+  let raw_hash = generator.finalize();
+  Hash(BASE32_NOPAD.encode(&raw_hash[..20]).to_lowercase())
+}
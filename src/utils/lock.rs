@@ -0,0 +1,167 @@
+use std::{
+  fs::{self, File, OpenOptions, TryLockError},
+  io::Write,
+  path::{Path, PathBuf},
+  process, thread,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+  errors::{to_error, Error},
+  utils::fs::make_dir_if_not_exists,
+};
+
+const POLLING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock of a file shared among processes and threads, which is released when dropped.
+/// The lock file records PID of the process holding it to tell others who is waited for.
+#[derive(Debug)]
+pub struct FileLock {
+  file: File,
+  pub path: PathBuf,
+}
+
+impl FileLock {
+  /// Acquire an exclusive lock of `path`, waiting for the other holder to release it until `timeout` elapses.
+  pub fn acquire(path: impl AsRef<Path>, timeout: Duration) -> Result<Self> {
+    Self::acquire_with(path.as_ref(), timeout, false)
+  }
+
+  /// Acquire a lock of `path` shared with other shared holders, waiting for an exclusive holder to release it until `timeout` elapses.
+  pub fn acquire_shared(path: impl AsRef<Path>, timeout: Duration) -> Result<Self> {
+    Self::acquire_with(path.as_ref(), timeout, true)
+  }
+
+  fn acquire_with(path: &Path, timeout: Duration, shared: bool) -> Result<Self> {
+    let path = path.to_path_buf();
+    if let Some(parent) = path.parent() {
+      make_dir_if_not_exists(parent)?;
+    }
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)
+      .map_err(|error| Error::NotAccessible(path.clone()).log_debug(error))?;
+    let started_at = Instant::now();
+    let mut waiting = false;
+    loop {
+      let locked = match shared {
+        true => file.try_lock_shared(),
+        false => file.try_lock(),
+      };
+      match locked {
+        Ok(()) => break,
+        Err(TryLockError::WouldBlock) => {
+          if started_at.elapsed() >= timeout {
+            return Err(Error::Locked(path.clone(), Self::read_pid(&path)).into());
+          }
+          if !waiting {
+            waiting = true;
+            // printed regardless of the log level, since waiting silently looks like a hang
+            eprintln!(
+              "Waiting for {} to be released by PID {}",
+              path.to_string_lossy(),
+              Self::read_pid(&path)
+                .map(|pid| pid.to_string())
+                .unwrap_or(String::from("unknown")),
+            );
+          }
+          thread::sleep(POLLING_INTERVAL);
+        }
+        Err(TryLockError::Error(error)) => return Err(to_error(error)),
+      }
+    }
+    let mut lock = Self { file, path };
+    lock.write_pid()?;
+    Ok(lock)
+  }
+
+  fn write_pid(&mut self) -> Result<()> {
+    self.file.set_len(0).map_err(to_error)?;
+    write!(self.file, "{}", process::id()).map_err(to_error)
+  }
+
+  /// The file may not be readable while locked on some platforms.
+  fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse::<u32>().ok()
+  }
+}
+
+impl Drop for FileLock {
+  fn drop(&mut self) {
+    // clear PID before releasing, and keep the file because removing it races with other processes opening it
+    let _ = self.file.set_len(0);
+    if let Err(error) = self.file.unlock() {
+      Error::NotAccessible(self.path.clone())
+        .log_debug(error)
+        .log_warn(Some("Failed to release the lock"));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+
+  use super::*;
+
+  #[test]
+  fn test_acquire() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path().join("locks/foo.lock");
+
+    let lock = FileLock::acquire(&path, Duration::ZERO).unwrap();
+    assert_eq!(
+      fs::read_to_string(&path).unwrap(),
+      process::id().to_string()
+    );
+
+    let error = FileLock::acquire(&path, Duration::from_millis(200)).unwrap_err();
+    let error = error.downcast::<Error>().unwrap();
+    #[cfg(not(windows))]
+    assert_eq!(error, Error::Locked(path.clone(), Some(process::id())));
+    #[cfg(windows)]
+    assert!(matches!(error, Error::Locked(..)));
+
+    drop(lock);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    assert!(FileLock::acquire(&path, Duration::ZERO).is_ok());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_acquire_shared() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path().join("foo.lock");
+
+    let shared = FileLock::acquire_shared(&path, Duration::ZERO).unwrap();
+    assert!(FileLock::acquire_shared(&path, Duration::ZERO).is_ok());
+    assert!(FileLock::acquire(&path, Duration::from_millis(200)).is_err());
+    drop(shared);
+
+    let exclusive = FileLock::acquire(&path, Duration::ZERO).unwrap();
+    assert!(FileLock::acquire_shared(&path, Duration::from_millis(200)).is_err());
+    drop(exclusive);
+    assert!(FileLock::acquire_shared(&path, Duration::ZERO).is_ok());
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_acquire_after_release() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path().join("foo.lock");
+
+    let lock = FileLock::acquire(&path, Duration::ZERO).unwrap();
+    let handle = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(200));
+      drop(lock);
+    });
+    assert!(FileLock::acquire(&path, Duration::from_secs(10)).is_ok());
+    handle.join().unwrap();
+    tmp_dir.close().unwrap();
+  }
+}
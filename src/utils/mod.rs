@@ -1,8 +1,10 @@
 pub mod fs;
 pub mod glob;
 pub mod hash;
+pub mod lock;
 pub mod map;
 pub mod option;
 pub mod path;
+pub mod process;
 pub mod result;
 pub mod tests;
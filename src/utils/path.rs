@@ -1,9 +1,10 @@
 use std::{
-  env::{current_dir, set_current_dir},
+  env::current_dir,
   fmt::Display,
-  io,
   path::{Component, Path, PathBuf},
 };
+#[cfg(test)]
+use std::{env::set_current_dir, io};
 
 use anyhow::Result;
 use itertools::Itertools;
@@ -13,7 +14,7 @@ use path_clean::PathClean;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::errors::Error;
+use crate::{errors::Error, utils::hash::Hashable};
 
 pub fn to_absolute_path(path: impl AsRef<Path>) -> Result<PathBuf> {
   let path = path.as_ref();
@@ -40,34 +41,84 @@ impl Display for DirKey {
   }
 }
 
+/// The length of the hash in a `DirKey`, which is long enough not to collide among projects on a machine.
+const DIR_KEY_HASH_LEN: usize = 10;
+
+/// The readable part of a `DirKey` is truncated to keep lock files within the limit of file names.
+const DIR_KEY_READABLE_LEN: usize = 64;
+
+/// Identify a project directory by `<readable path>-<hash of the absolute path>`.
+/// The readable part is the path relative to the home directory if under it, whose components are joined by `_`.
 pub fn to_dir_key(path: impl AsRef<Path>) -> DirKey {
-  type ToDirComponent = Box<dyn FnMut(&Component<'_>) -> Option<String>>;
-  let mut to_dir_component: ToDirComponent = {
-    let home_dir = dirs::home_dir();
-    match home_dir {
-      Some(home_dir) => {
-        let to_os_ascii_lowercase = |c: Component<'_>| c.as_os_str().to_ascii_lowercase();
-        let home_components = home_dir
-          .components()
-          .map(to_os_ascii_lowercase)
-          .collect_vec();
-        Box::new(move |c: &Component<'_>| match c {
-          Component::Normal(p) => {
-            if home_components.contains(&to_os_ascii_lowercase(*c)) {
-              None
-            } else {
-              Some(p.to_string_lossy().to_string())
-            }
-          }
-          _ => None,
-        })
-      }
-      None => Box::new(|c: &Component<'_>| match c {
-        Component::Normal(p) => Some(p.to_string_lossy().to_string()),
-        _ => None,
-      }),
-    }
+  let path = {
+    let p = path.as_ref();
+    to_absolute_path(p).unwrap_or(p.to_path_buf())
   };
+  let relative_path = dirs::home_dir()
+    .and_then(|home_dir| strip_prefix_ignore_ascii_case(&path, &home_dir))
+    .unwrap_or(path.clone());
+  let readable = relative_path
+    .components()
+    .filter_map(|c| match c {
+      Component::Normal(p) => Some(p.to_string_lossy().to_string()),
+      _ => None,
+    })
+    .join("_");
+  // keep the tail, which tells the project better than the head
+  let readable = readable
+    .chars()
+    .skip(
+      readable
+        .chars()
+        .count()
+        .saturating_sub(DIR_KEY_READABLE_LEN),
+    )
+    .collect::<String>();
+  let hash = PathHash(&path)
+    .generate_hash()
+    .map(|h| h.0.chars().take(DIR_KEY_HASH_LEN).collect::<String>())
+    .unwrap_or_default();
+  if readable.is_empty() {
+    DirKey(hash)
+  } else {
+    DirKey(format!("{}-{}", readable, hash))
+  }
+}
+
+struct PathHash<'a>(&'a Path);
+
+impl Hashable for PathHash<'_> {
+  fn to_hash_target(&self) -> Result<impl AsRef<[u8]>> {
+    Ok(self.0.to_string_lossy().to_string())
+  }
+}
+
+/// Strip `prefix` from `path` comparing components case-insensitively as home directories on macOS and Windows.
+fn strip_prefix_ignore_ascii_case(path: &Path, prefix: &Path) -> Option<PathBuf> {
+  let mut components = path.components();
+  for prefix_component in prefix.components() {
+    let component = components.next()?;
+    if !component
+      .as_os_str()
+      .eq_ignore_ascii_case(prefix_component.as_os_str())
+    {
+      return None;
+    }
+  }
+  Some(components.as_path().to_path_buf())
+}
+
+/// The `DirKey` before metadata version 3, which joins path components by `_` dropping any component of the home directory.
+/// Different directories may share the key, so it is only used to migrate metadata.
+pub fn to_legacy_dir_key(path: impl AsRef<Path>) -> DirKey {
+  let home_components = dirs::home_dir()
+    .map(|home_dir| {
+      home_dir
+        .components()
+        .map(|c| c.as_os_str().to_ascii_lowercase())
+        .collect_vec()
+    })
+    .unwrap_or_default();
   let path = {
     let p = path.as_ref();
     to_absolute_path(p).unwrap_or(p.to_path_buf())
@@ -75,7 +126,12 @@ pub fn to_dir_key(path: impl AsRef<Path>) -> DirKey {
   DirKey(
     path
       .components()
-      .filter_map(|c| to_dir_component(&c))
+      .filter_map(|c| match c {
+        Component::Normal(p) if !home_components.contains(&p.to_ascii_lowercase()) => {
+          Some(p.to_string_lossy().to_string())
+        }
+        _ => None,
+      })
       .join("_"),
   )
 }
@@ -118,6 +174,7 @@ pub fn remove_windows_path_prefix(path: impl AsRef<Path>) -> PathBuf {
 }
 
 /// Run a function `f` in the base directory `base_dir`, and go back to the original cwd.
+#[cfg(test)]
 pub fn run_in_base_dir<T, F>(base_dir: impl AsRef<Path>, f: F, fallback: Option<T>) -> F::Output
 where
   T: Default,
@@ -233,14 +290,14 @@ mod tests {
       run_in_base_dir(
         base_dir,
         || {
-          let result = to_absolute_path(&case.input).unwrap();
-          assert!(result.starts_with(&base_dir.canonicalize().unwrap()));
+          let result = to_absolute_path(case.input).unwrap();
+          assert!(result.starts_with(base_dir.canonicalize().unwrap()));
           assert!(result.ends_with(&case.expected));
         },
         None,
       )
     } else {
-      assert_eq!(to_absolute_path(&case.input).unwrap(), case.expected);
+      assert_eq!(to_absolute_path(case.input).unwrap(), case.expected);
     }
   }
 
@@ -270,10 +327,12 @@ mod tests {
 
   fn test_to_dir_key_each(case: &ToDirKeyTestCase) {
     let dir_key = to_dir_key(&case.input).to_string();
+    let (readable, hash) = dir_key.rsplit_once('-').unwrap_or(("", &dir_key));
+    assert_eq!(hash.len(), DIR_KEY_HASH_LEN);
     if case.input.is_relative() {
-      assert!(dir_key.ends_with(case.expected));
+      assert!(readable.ends_with(case.expected));
     } else {
-      assert_eq!(dir_key, case.expected);
+      assert_eq!(readable, case.expected);
     }
   }
 
@@ -300,8 +359,29 @@ mod tests {
       input: PathBuf::from("./a/b/c/../c/d"),
       expected: "a_b_c_d"
     },
+    "outside_home" => &ToDirKeyTestCase {
+      input: PathBuf::from("/work/a_b/c"),
+      expected: "work_a_b_c"
+    },
   );
 
+  #[test]
+  fn test_to_dir_key_collision() {
+    let (a, b) = (PathBuf::from("/work/a_b/c"), PathBuf::from("/work/a/b_c"));
+    assert_eq!(to_legacy_dir_key(&a), to_legacy_dir_key(&b));
+    assert_ne!(to_dir_key(&a), to_dir_key(&b));
+    assert_eq!(to_dir_key(&a), to_dir_key("/work/a_b/./c"));
+
+    // a component named as one of the home directory is kept out of the home directory
+    let home_dir = dirs::home_dir().unwrap();
+    let name = home_dir.file_name().unwrap().to_string_lossy();
+    let path = PathBuf::from("/srv").join(name.as_ref()).join("app");
+    assert!(to_dir_key(&path)
+      .0
+      .starts_with(&format!("srv_{}_app-", name)));
+    assert!(to_dir_key(home_dir.join("app")).0.starts_with("app-"));
+  }
+
   struct CleanPathSeparatorTestCase {
     input: &'static str,
     expected: &'static str,
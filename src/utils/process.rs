@@ -0,0 +1,67 @@
+use std::{
+  cell::Cell,
+  io,
+  process::{Command, Output},
+};
+
+thread_local! {
+  static IS_SPAWNING_ALLOWED: Cell<bool> = const { Cell::new(true) };
+  static SPAWNED: Cell<usize> = const { Cell::new(0) };
+  static REFUSED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Run `command` to collect its output, which is refused inside `without_spawning`.
+pub fn output(command: &mut Command) -> io::Result<Output> {
+  if !is_spawning_allowed() {
+    REFUSED.set(REFUSED.get() + 1);
+    return Err(io::Error::new(
+      io::ErrorKind::PermissionDenied,
+      format!(
+        "{} is not run without spawning processes",
+        command.get_program().to_string_lossy()
+      ),
+    ));
+  }
+  SPAWNED.set(SPAWNED.get() + 1);
+  command.output()
+}
+
+pub fn is_spawning_allowed() -> bool {
+  IS_SPAWNING_ALLOWED.get()
+}
+
+/// Call `f` refusing to spawn processes by `output` in the current thread, such as planning which must have no side effects.
+/// Return whether any process was refused, which makes the result of `f` depend on defaults instead of the environment.
+pub fn without_spawning<T>(f: impl FnOnce() -> T) -> (T, bool) {
+  let was_allowed = IS_SPAWNING_ALLOWED.replace(false);
+  let refused = REFUSED.get();
+  let result = f();
+  IS_SPAWNING_ALLOWED.set(was_allowed);
+  (result, REFUSED.get() != refused)
+}
+
+/// The number of processes spawned by `output` in the current thread.
+#[cfg(test)]
+pub fn count_spawned() -> usize {
+  SPAWNED.get()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_without_spawning() {
+    let spawned = count_spawned();
+    let (result, refused) = without_spawning(|| output(&mut Command::new("node")).is_err());
+    assert!(result);
+    assert!(refused);
+    assert_eq!(count_spawned(), spawned);
+    assert!(is_spawning_allowed());
+
+    let (_, refused) = without_spawning(|| ());
+    assert!(!refused);
+    let _ = output(Command::new("node").arg("--version"));
+    assert_eq!(count_spawned(), spawned + 1);
+  }
+}
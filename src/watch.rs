@@ -0,0 +1,329 @@
+use std::{
+  collections::BTreeSet,
+  fs,
+  ops::ControlFlow,
+  path::{Path, PathBuf},
+  sync::mpsc::{self, Receiver, RecvTimeoutError},
+  time::Duration,
+};
+
+use anyhow::Result;
+use notify::{
+  event::{AccessKind, AccessMode},
+  Event, EventKind, RecursiveMode, Watcher,
+};
+
+use crate::{
+  cache::CacheOptions,
+  core::{self, Outcome},
+  errors::to_error,
+  project::{
+    find_project_root, to_lockfile_paths, to_package_json_path, to_pnpm_workspace_paths,
+    to_workspace_read_dirs, Lockfile, ProjectRoot,
+  },
+};
+
+/// Configuration files of package managers, which change how dependencies are installed.
+const CONFIG_FILES: [&str; 4] = [".npmrc", ".yarnrc", ".yarnrc.yml", "bunfig.toml"];
+
+const NODE_MODULES_DIR: &str = "node_modules";
+
+/// Resolve the files whose changes may change node_modules of the project at `base_dir`.
+/// Lockfiles, configuration files and package.json of directories which may be workspaces are included even if missing,
+/// so that creating them is noticed.
+pub fn resolve_watched_files(base_dir: impl AsRef<Path>) -> BTreeSet<PathBuf> {
+  let base_dir = base_dir.as_ref();
+  let mut files = BTreeSet::from([to_package_json_path(base_dir)]);
+  files.extend(to_lockfile_paths(base_dir));
+  files.extend(to_pnpm_workspace_paths(base_dir));
+  files.extend(CONFIG_FILES.map(|name| base_dir.join(name)));
+  // an invalid project has no workspaces until package.json is fixed
+  let kind = Lockfile::new(base_dir).map(|l| l.kind).ok();
+  if let Ok(project_root) = ProjectRoot::new(base_dir, kind) {
+    files.extend(
+      project_root
+        .workspace_dirs()
+        .into_iter()
+        .map(to_package_json_path),
+    );
+  }
+  // directories matched with the patterns of workspaces become workspaces once package.json is added
+  files.extend(
+    to_workspace_read_dirs(base_dir)
+      .iter()
+      .filter_map(|dir| fs::read_dir(dir).ok())
+      .flatten()
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| entry.file_name() != NODE_MODULES_DIR && entry.path().is_dir())
+      .map(|entry| to_package_json_path(entry.path())),
+  );
+  files
+}
+
+/// Sync node_modules of the project at `base_dir` now and whenever the files from `resolve_watched_files` change,
+/// passing each result to `on_synced` until the watcher fails.
+/// Changes within `debounce` are synced at once, and the files are resolved again after each sync as workspaces may change.
+pub fn watch(
+  base_dir: impl AsRef<Path>,
+  cache_dir: Option<impl AsRef<Path>>,
+  lock_timeout: Duration,
+  options: CacheOptions,
+  debounce: Duration,
+  mut on_synced: impl FnMut(Result<Outcome>),
+) -> Result<()> {
+  let base_dir = find_project_root(base_dir)?;
+  watch_with(
+    &base_dir,
+    debounce,
+    || core::run(&base_dir, cache_dir.as_ref(), lock_timeout, options.clone()),
+    |result| {
+      on_synced(result);
+      ControlFlow::Continue(())
+    },
+  )
+}
+
+/// Run `sync` now and whenever the files of the project at `base_dir` change, until `on_synced` breaks or the watcher fails.
+fn watch_with(
+  base_dir: &Path,
+  debounce: Duration,
+  mut sync: impl FnMut() -> Result<Outcome>,
+  mut on_synced: impl FnMut(Result<Outcome>) -> ControlFlow<()>,
+) -> Result<()> {
+  let (sender, receiver) = mpsc::channel();
+  let mut watcher = notify::recommended_watcher(sender).map_err(to_error)?;
+  let mut watched_dirs = BTreeSet::<PathBuf>::new();
+  loop {
+    // watch directories rather than files, since editors and package managers replace files by renaming
+    let files = resolve_watched_files(base_dir);
+    // new workspaces appear in the directories which the patterns of workspaces are expanded from
+    let read_dirs = to_workspace_read_dirs(base_dir)
+      .into_iter()
+      .collect::<BTreeSet<_>>();
+    let dirs = files
+      .iter()
+      .filter_map(|file| file.parent().map(Path::to_path_buf))
+      .chain(read_dirs.iter().cloned())
+      .filter(|dir| dir.is_dir())
+      .collect::<BTreeSet<_>>();
+    for dir in watched_dirs.difference(&dirs) {
+      let _ = watcher.unwatch(dir);
+    }
+    for dir in dirs.difference(&watched_dirs) {
+      watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(to_error)?;
+    }
+    watched_dirs = dirs;
+
+    let result = sync();
+    // the sync itself writes node_modules and the lockfile, which must not trigger another sync
+    settle(&receiver, debounce)?;
+    if on_synced(result).is_break() {
+      return Ok(());
+    }
+    wait_for_changes(&receiver, &files, &read_dirs, debounce)?;
+  }
+}
+
+/// Block until any of `files` changes or an entry is added to or removed from `read_dirs`, and no more events come within `debounce`.
+fn wait_for_changes(
+  receiver: &Receiver<notify::Result<Event>>,
+  files: &BTreeSet<PathBuf>,
+  read_dirs: &BTreeSet<PathBuf>,
+  debounce: Duration,
+) -> Result<()> {
+  loop {
+    match receiver.recv().map_err(to_error)? {
+      Ok(event) if is_change_of(&event, files, read_dirs) => break,
+      Ok(_) => {}
+      Err(error) => log::warn!("Failed to watch files: {:?}", error),
+    }
+  }
+  // package managers and git write files one after another
+  settle(receiver, debounce)
+}
+
+/// Discard events until no more events come within `debounce`.
+fn settle(receiver: &Receiver<notify::Result<Event>>, debounce: Duration) -> Result<()> {
+  loop {
+    match receiver.recv_timeout(debounce) {
+      Ok(_) => {}
+      Err(RecvTimeoutError::Timeout) => return Ok(()),
+      Err(error) => return Err(to_error(error)),
+    }
+  }
+}
+
+/// Whether `event` writes, creates, removes or renames any of `files` or an entry of `read_dirs`, ignoring reads by package managers.
+fn is_change_of(event: &Event, files: &BTreeSet<PathBuf>, read_dirs: &BTreeSet<PathBuf>) -> bool {
+  let is_change = match event.kind {
+    EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+    EventKind::Access(_) => false,
+    _ => true,
+  };
+  is_change
+    && event.paths.iter().any(|path| {
+      files.contains(path)
+        || path
+          .parent()
+          .is_some_and(|parent| read_dirs.contains(parent))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::Cell, thread};
+
+  use notify::event::{CreateKind, ModifyKind};
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::errors::Error;
+
+  #[test]
+  fn test_resolve_watched_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let base_dir = tmp_dir.path();
+    fs::create_dir_all(base_dir.join("packages/a")).unwrap();
+    fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"root","workspaces":["packages/*"]}"#,
+    )
+    .unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    fs::write(base_dir.join("packages/a/package.json"), r#"{"name":"a"}"#).unwrap();
+
+    let files = resolve_watched_files(base_dir);
+    for file in [
+      "package.json",
+      "package-lock.json",
+      "yarn.lock",
+      "pnpm-workspace.yaml",
+      ".npmrc",
+      "packages/a/package.json",
+    ] {
+      assert!(files.contains(&base_dir.join(file)), "{}", file);
+    }
+    assert!(!files.contains(&base_dir.join("packages/b/package.json")));
+
+    fs::create_dir_all(base_dir.join("packages/b")).unwrap();
+    fs::write(base_dir.join("packages/b/package.json"), r#"{"name":"b"}"#).unwrap();
+    let files = resolve_watched_files(base_dir);
+    assert!(files.contains(&base_dir.join("packages/b/package.json")));
+
+    // a directory becomes a workspace once package.json is added
+    fs::create_dir_all(base_dir.join("packages/c")).unwrap();
+    let files = resolve_watched_files(base_dir);
+    assert!(files.contains(&base_dir.join("packages/c/package.json")));
+    tmp_dir.close().unwrap();
+  }
+
+  #[test]
+  fn test_is_change_of() {
+    let files = BTreeSet::from([PathBuf::from("/a/package.json")]);
+    let read_dirs = BTreeSet::from([PathBuf::from("/a/packages")]);
+    let event = |kind: EventKind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+    assert!(is_change_of(
+      &event(EventKind::Modify(ModifyKind::Any), "/a/package.json"),
+      &files,
+      &read_dirs
+    ));
+    assert!(is_change_of(
+      &event(
+        EventKind::Access(AccessKind::Close(AccessMode::Write)),
+        "/a/package.json"
+      ),
+      &files,
+      &read_dirs
+    ));
+    assert!(!is_change_of(
+      &event(
+        EventKind::Access(AccessKind::Close(AccessMode::Read)),
+        "/a/package.json"
+      ),
+      &files,
+      &read_dirs
+    ));
+    assert!(!is_change_of(
+      &event(EventKind::Create(CreateKind::File), "/a/node_modules"),
+      &files,
+      &read_dirs
+    ));
+    assert!(is_change_of(
+      &event(EventKind::Create(CreateKind::Folder), "/a/packages/c"),
+      &files,
+      &read_dirs
+    ));
+  }
+
+  #[test]
+  fn test_wait_for_changes() {
+    let files = BTreeSet::from([PathBuf::from("/a/package.json")]);
+    let read_dirs = BTreeSet::new();
+    let (sender, receiver) = mpsc::channel();
+    let modify =
+      |path: &str| Ok(Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.into()));
+    let handle = thread::spawn(move || {
+      sender.send(modify("/a/node_modules")).unwrap();
+      sender.send(modify("/a/package.json")).unwrap();
+      sender.send(modify("/a/package.json")).unwrap();
+      // keep the channel open until the burst settles
+      thread::sleep(Duration::from_millis(100));
+      sender
+    });
+    wait_for_changes(&receiver, &files, &read_dirs, Duration::from_millis(20)).unwrap();
+    // the burst is consumed at once
+    assert!(receiver.try_recv().is_err());
+    drop(handle.join().unwrap());
+    assert!(wait_for_changes(&receiver, &files, &read_dirs, Duration::from_millis(20)).is_err());
+  }
+
+  #[test]
+  fn test_watch_with() {
+    let tmp_dir = TempDir::new().unwrap();
+    let base_dir = tmp_dir.path().to_path_buf();
+    fs::create_dir_all(base_dir.join("packages/a")).unwrap();
+    fs::write(
+      base_dir.join("package.json"),
+      r#"{"name":"root","workspaces":["packages/*"]}"#,
+    )
+    .unwrap();
+    fs::write(base_dir.join("package-lock.json"), "{}").unwrap();
+    fs::write(base_dir.join("packages/a/package.json"), r#"{"name":"a"}"#).unwrap();
+
+    let (synced_sender, synced) = mpsc::channel::<usize>();
+    let handle = {
+      let base_dir = base_dir.clone();
+      thread::spawn(move || {
+        let count = Cell::new(0);
+        watch_with(
+          &base_dir,
+          Duration::from_millis(100),
+          || {
+            // installing rewrites the lockfile and node_modules
+            count.set(count.get() + 1);
+            fs::write(base_dir.join("package-lock.json"), count.get().to_string()).unwrap();
+            fs::create_dir_all(base_dir.join("node_modules")).unwrap();
+            Err(Error::Any(String::from("synced")).into())
+          },
+          |_| match synced_sender.send(count.get()) {
+            Ok(_) if count.get() < 2 => ControlFlow::Continue(()),
+            _ => ControlFlow::Break(()),
+          },
+        )
+      })
+    };
+    let receive = |synced: &Receiver<usize>| synced.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(receive(&synced), 1);
+    // the changes by the sync itself are ignored
+    assert!(synced.recv_timeout(Duration::from_millis(500)).is_err());
+
+    // a new workspace is noticed
+    fs::create_dir_all(base_dir.join("packages/c")).unwrap();
+    fs::write(base_dir.join("packages/c/package.json"), r#"{"name":"c"}"#).unwrap();
+    assert_eq!(receive(&synced), 2);
+    handle.join().unwrap().unwrap();
+    tmp_dir.close().unwrap();
+  }
+}